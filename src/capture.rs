@@ -0,0 +1,392 @@
+//! Packet capture on cables. A cable with capture enabled has a capture
+//! stage in its dpipe chain, which records the frames in both directions
+//! and is controlled through a socket in the cable directory.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+use std::time::Duration;
+use std::{fs, thread};
+
+use crate::executor::{self, Options};
+use crate::vde;
+
+mod pcap;
+mod stage;
+
+pub use stage::run as run_stage;
+
+/// Time given to the capture stage to answer a command
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Send a command to the capture stage of a cable, returning its answer
+pub fn control(socket: &str, command: &str) -> Result<String> {
+    let stream = UnixStream::connect(socket).context("Connecting to the capture stage")?;
+    stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONTROL_TIMEOUT))?;
+    writeln!(&stream, "{command}")?;
+
+    let mut answer = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut answer)
+        .context("Reading the answer of the capture stage")?;
+    let answer = answer.trim().to_owned();
+    if let Some(e) = answer.strip_prefix("error ") {
+        bail!("{e}");
+    }
+    Ok(answer)
+}
+
+/// State of the capture stage of a running cable, if it has one
+pub fn capture_status(opts: &Options, conn: &vde::Cable) -> Option<String> {
+    control(&conn.capture_socket_path(&opts.working_dir()), "status").ok()
+}
+
+/// Find the cable of a capture target: the name of a cable, or `switch:port`
+/// for the cable plugged in that port of a switch
+fn find_cable<'a>(t: &'a vde::Topology, target: &str) -> Result<&'a vde::Cable> {
+    let Some((switch, port)) = target.split_once(':') else {
+        return t
+            .get_cables()
+            .iter()
+            .find(|c| c.get_name() == target)
+            .ok_or_else(|| anyhow!("Cable {target} not found"));
+    };
+
+    if !t.get_switches().iter().any(|s| s.get_name() == switch) {
+        bail!("Switch {switch} not found");
+    }
+    let plugged =
+        |e: &vde::Endpoint| e.get_name() == switch && e.get_port().is_some_and(|p| p == port);
+    t.get_cables()
+        .iter()
+        .find(|c| plugged(c.get_a()) || plugged(c.get_b()))
+        .ok_or_else(|| anyhow!("No cable is plugged in port {port} of {switch}"))
+}
+
+/// Only the cables started with dpipe can have a capture stage
+fn check_engine(conn: &vde::Cable) -> Result<()> {
+    if conn.get_engine() == vde::Engine::Native {
+        bail!(
+            "Cable {} is native: capture is not supported",
+            conn.get_name()
+        );
+    }
+    Ok(())
+}
+
+/// Make sure a running cable has a capture stage. A cable without one is
+/// restarted with it, without recording to file and without changing the
+/// topology
+fn ensure_stage(opts: &Options, conn: &vde::Cable) -> Result<()> {
+    let name = conn.get_name();
+    if !executor::pid_path_is_alive(&conn.pid_path(&opts.working_dir()))? {
+        bail!("Cable {name} is not running");
+    }
+    if capture_status(opts, conn).is_some() {
+        return Ok(());
+    }
+    check_engine(conn)?;
+    if executor::is_link_down(opts, conn)? {
+        bail!("Cable {name} is down, use `imaginet link up` first");
+    }
+
+    log::info!("Restarting {name} with a capture stage");
+    let mut staged = conn.clone();
+    staged.set_capture(true);
+    executor::restart_cable(opts, &staged, false)
+}
+
+/// Open a pcapng stream of the frames crossing a cable with a capture stage
+fn open_stream(opts: &Options, conn: &vde::Cable) -> Result<BufReader<UnixStream>> {
+    let stream = UnixStream::connect(conn.capture_socket_path(&opts.working_dir()))
+        .context("Connecting to the capture stage")?;
+    writeln!(&stream, "stream pcapng")?;
+
+    let mut reader = BufReader::new(stream);
+    let mut answer = String::new();
+    reader.read_line(&mut answer)?;
+    if answer.trim() != "ok" {
+        bail!("Streaming from {}: {}", conn.get_name(), answer.trim());
+    }
+    Ok(reader)
+}
+
+/// Stream the frames of a cable to the standard output, in pcapng format
+pub fn live(opts: &Options, target: &str) -> Result<()> {
+    if io::stdout().is_terminal() {
+        bail!("The capture is binary, pipe it into a program: imaginet capture {target} --live | wireshark -k -i -");
+    }
+
+    let t = executor::get_topology(opts).context("Getting topology")?;
+    let conn = find_cable(&t, target)?;
+    ensure_stage(opts, conn)?;
+    let mut reader = open_stream(opts, conn)?;
+
+    // Flush every chunk, so that frames are shown as soon as they cross
+    // the cable. The stream ends when the reader goes away
+    let mut out = io::stdout().lock();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let n = reader.read(&mut buf).context("Reading the capture")?;
+        if n == 0 {
+            return Ok(());
+        }
+        match out.write_all(&buf[..n]).and_then(|_| out.flush()) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(e).context("Writing the capture"),
+        }
+    }
+}
+
+/// Ask whether the running cables without a capture stage can be restarted
+/// with one. Restarting drops the frames in flight, so without a terminal
+/// to ask on they are skipped
+fn confirm_restart(cables: &[&vde::Cable]) -> Result<bool> {
+    let names: Vec<&str> = cables.iter().map(|c| c.get_name().as_str()).collect();
+    if !io::stdin().is_terminal() {
+        log::warn!(
+            "Skipping the cables without a capture stage, which would be restarted: {}",
+            names.join(", ")
+        );
+        return Ok(false);
+    }
+
+    println!(
+        "These cables have no capture stage and must be restarted, dropping the frames in flight: {}",
+        names.join(", ")
+    );
+    print!("Restart them? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .context("Reading the answer")?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Capture every running cable into a single pcapng file, with an interface
+/// per cable. The cables without a capture stage are restarted only if the
+/// user agrees. It runs until interrupted, or until all the cables stop
+pub fn all(opts: &Options, output: &str) -> Result<()> {
+    let t = executor::get_topology(opts).context("Getting topology")?;
+
+    let mut running = Vec::new();
+    for conn in t.get_cables() {
+        if executor::pid_path_is_alive(&conn.pid_path(&opts.working_dir()))? {
+            running.push(conn);
+        }
+    }
+    let unstaged: Vec<&vde::Cable> = running
+        .iter()
+        .copied()
+        .filter(|c| c.get_engine() != vde::Engine::Native && capture_status(opts, c).is_none())
+        .collect();
+    let restart = !unstaged.is_empty() && confirm_restart(&unstaged)?;
+
+    let mut interfaces = Vec::new();
+    let mut streams = Vec::new();
+    for conn in running {
+        let name = conn.get_name();
+        if !restart && unstaged.iter().any(|c| c.get_name() == name) {
+            continue;
+        }
+        if let Err(e) = ensure_stage(opts, conn) {
+            log::warn!("Skipping {name}: {e:#}");
+            continue;
+        }
+        match open_stream(opts, conn) {
+            Ok(stream) => streams.push(stream),
+            Err(e) => {
+                log::warn!("Skipping {name}: {e:#}");
+                continue;
+            }
+        }
+        interfaces.push(pcap::Interface {
+            name: name.to_owned(),
+            description: conn.description(),
+        });
+    }
+    if streams.is_empty() {
+        bail!("No running cable to capture");
+    }
+
+    let file = fs::File::create(output).context(format!("Creating {output}"))?;
+    let mut writer = pcap::Writer::new(file, pcap::Format::Pcapng, &interfaces)
+        .context(format!("Writing {output}"))?;
+
+    // Every cable is read by its own thread, the packets are written in the
+    // order they arrive
+    let (packets, received) = mpsc::channel();
+    for (i, stream) in streams.into_iter().enumerate() {
+        let packets = packets.clone();
+        thread::spawn(move || {
+            let mut reader = pcap::Reader::new(stream);
+            while let Ok(Some(p)) = reader.read_packet() {
+                if packets.send((i as u32, p)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(packets);
+
+    println!(
+        "Capturing {} cable(s) in {output}, press Ctrl-C to stop",
+        interfaces.len()
+    );
+    for (interface, p) in received {
+        writer
+            .write_packet(interface, p.time, p.direction, &p.data)
+            .context(format!("Writing {output}"))?;
+    }
+    Ok(())
+}
+
+/// Open Wireshark on the frames of a cable, in a new terminal
+pub fn wireshark(opts: &Options, target: &str) -> Result<()> {
+    let t = executor::get_topology(opts).context("Getting topology")?;
+    let conn = find_cable(&t, target)?;
+    // Done here, so that errors are not lost in the terminal
+    ensure_stage(opts, conn)?;
+
+    let exe = opts.executables(&t);
+    let args = vec![
+        "-c".to_owned(),
+        r#"w="$1"; shift; "$@" | "$w" -k -i -"#.to_owned(),
+        "sh".to_owned(),
+        exe.get_wireshark(),
+        exe.get_imaginet(),
+        "--data-dir".to_owned(),
+        opts.data_dir.clone(),
+        "--base-dir".to_owned(),
+        opts.runtime_dir.clone(),
+        "--topology".to_owned(),
+        opts.topology.clone(),
+        "capture".to_owned(),
+        target.to_owned(),
+        "--live".to_owned(),
+    ];
+    executor::exec_terminal(&opts.terminal, &opts.terminal_args, "sh", &args)
+}
+
+/// Start recording the frames of a cable. If the cable has no capture stage,
+/// capture is enabled in the topology and the cable is restarted
+pub fn start(opts: &Options, cable: String, output: Option<String>) -> Result<()> {
+    let mut t = executor::get_topology(opts).context("Getting topology")?;
+    let base = opts.working_dir();
+    let Some(conn) = t.get_cable_mut(&cable) else {
+        bail!("Cable {cable} not found");
+    };
+    check_engine(conn)?;
+
+    let alive = executor::pid_path_is_alive(&conn.pid_path(&base))?;
+    let captures = opts.captures_dir();
+    let output = match output {
+        Some(o) => {
+            if !alive {
+                bail!(
+                    "Cable {cable} is not running: the capture starts with it, in {}",
+                    conn.capture_path(&captures)
+                );
+            }
+            std::path::absolute(&o)
+                .context(format!("Resolving {o}"))?
+                .to_str()
+                .context("Converting output path to string")?
+                .to_owned()
+        }
+        None => {
+            fs::create_dir_all(&captures).context("Creating captures directory")?;
+            conn.capture_path(&captures)
+        }
+    };
+
+    if !conn.get_capture() {
+        conn.set_capture(true);
+        executor::write_topology(opts.clone(), &t).context("Writing topology")?;
+    }
+
+    if !alive {
+        println!("Capture enabled: it starts with {cable}");
+        return Ok(());
+    }
+
+    let conn = t
+        .get_cables()
+        .iter()
+        .find(|c| c.get_name() == &cable)
+        .unwrap();
+    ensure_stage(opts, conn)?;
+
+    control(&conn.capture_socket_path(&base), &format!("start {output}"))
+        .context(format!("Starting capture on {cable}"))?;
+    println!("Capturing {cable} in {output}");
+    Ok(())
+}
+
+/// Stop recording the frames of a cable. The capture stage keeps forwarding
+/// the frames until the cable is restarted
+pub fn stop(opts: &Options, cable: String) -> Result<()> {
+    let mut t = executor::get_topology(opts).context("Getting topology")?;
+    let base = opts.working_dir();
+    let Some(conn) = t.get_cable_mut(&cable) else {
+        bail!("Cable {cable} not found");
+    };
+
+    let enabled = conn.get_capture();
+    if enabled {
+        conn.set_capture(false);
+        executor::write_topology(opts.clone(), &t).context("Writing topology")?;
+    }
+
+    let conn = t
+        .get_cables()
+        .iter()
+        .find(|c| c.get_name() == &cable)
+        .unwrap();
+    match capture_status(opts, conn) {
+        Some(status) => {
+            control(&conn.capture_socket_path(&base), "stop")
+                .context(format!("Stopping capture on {cable}"))?;
+            if let Some(path) = status.strip_prefix("recording ") {
+                println!("Capture of {cable} saved in {path}");
+            }
+        }
+        None if !enabled => log::warn!("Cable {cable} is not capturing"),
+        None => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_cable_by_switch_port() {
+        let mut t = vde::Topology::new();
+        t.add_switch(vde::Switch::new("sw1".to_owned())).unwrap();
+        t.add_cable(vde::Cable::new(
+            "conn1".to_owned(),
+            "ns1".to_owned(),
+            None,
+            vde::VdeConnProtocols::PTP,
+            false,
+            "sw1".to_owned(),
+            Some("2".to_owned()),
+            vde::VdeConnProtocols::VDE,
+            false,
+            None,
+        ))
+        .unwrap();
+
+        assert_eq!(find_cable(&t, "conn1").unwrap().get_name(), "conn1");
+        assert_eq!(find_cable(&t, "sw1:2").unwrap().get_name(), "conn1");
+        assert!(find_cable(&t, "sw1:3").is_err());
+        assert!(find_cable(&t, "sw2:2").is_err());
+    }
+}
@@ -0,0 +1,310 @@
+//! Minimal writers for the pcap and pcapng file formats, with ethernet frames
+//! and microsecond timestamps, and a reader for the pcapng streams written
+//! by the capture stages.
+
+use std::io::{self, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::vde::Direction;
+
+const LINKTYPE_ETHERNET: u16 = 1;
+const SNAPLEN: u32 = 65535;
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const OPT_END: u16 = 0;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_DESCRIPTION: u16 = 3;
+const OPT_EPB_FLAGS: u16 = 2;
+const EPB_FLAG_INBOUND: u32 = 1;
+const EPB_FLAG_OUTBOUND: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Pcap,
+    Pcapng,
+}
+
+impl Format {
+    /// Format of a file, from its extension. Pcap is the default
+    pub fn from_path(path: &str) -> Format {
+        if path.ends_with(".pcapng") {
+            Format::Pcapng
+        } else {
+            Format::Pcap
+        }
+    }
+}
+
+/// A capture interface, which is a cable. Only pcapng keeps these
+pub struct Interface {
+    pub name: String,
+    pub description: String,
+}
+
+pub struct Writer<W: Write> {
+    out: W,
+    format: Format,
+}
+
+/// Append a pcapng option, padded to 32 bits
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend(code.to_le_bytes());
+    buf.extend((value.len() as u16).to_le_bytes());
+    buf.extend(value);
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+/// Wrap a body in a pcapng block
+fn block(kind: u32, mut body: Vec<u8>) -> Vec<u8> {
+    body.resize(body.len().next_multiple_of(4), 0);
+    let len = (body.len() + 12) as u32;
+    let mut buf = Vec::with_capacity(len as usize);
+    buf.extend(kind.to_le_bytes());
+    buf.extend(len.to_le_bytes());
+    buf.extend(body);
+    buf.extend(len.to_le_bytes());
+    buf
+}
+
+impl<W: Write> Writer<W> {
+    /// Create a writer, writing the headers. Pcap supports a single interface
+    pub fn new(mut out: W, format: Format, interfaces: &[Interface]) -> io::Result<Writer<W>> {
+        match format {
+            Format::Pcap => {
+                let mut buf = Vec::with_capacity(24);
+                buf.extend(PCAP_MAGIC.to_le_bytes());
+                buf.extend(2u16.to_le_bytes());
+                buf.extend(4u16.to_le_bytes());
+                buf.extend(0i32.to_le_bytes());
+                buf.extend(0u32.to_le_bytes());
+                buf.extend(SNAPLEN.to_le_bytes());
+                buf.extend(u32::from(LINKTYPE_ETHERNET).to_le_bytes());
+                out.write_all(&buf)?;
+            }
+            Format::Pcapng => {
+                let mut shb = Vec::new();
+                shb.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+                shb.extend(1u16.to_le_bytes());
+                shb.extend(0u16.to_le_bytes());
+                // Unknown section length
+                shb.extend((-1i64).to_le_bytes());
+                out.write_all(&block(BLOCK_SECTION_HEADER, shb))?;
+
+                for i in interfaces {
+                    let mut idb = Vec::new();
+                    idb.extend(LINKTYPE_ETHERNET.to_le_bytes());
+                    idb.extend(0u16.to_le_bytes());
+                    idb.extend(SNAPLEN.to_le_bytes());
+                    push_option(&mut idb, OPT_IF_NAME, i.name.as_bytes());
+                    push_option(&mut idb, OPT_IF_DESCRIPTION, i.description.as_bytes());
+                    push_option(&mut idb, OPT_END, &[]);
+                    out.write_all(&block(BLOCK_INTERFACE_DESCRIPTION, idb))?;
+                }
+            }
+        }
+        out.flush()?;
+        Ok(Writer { out, format })
+    }
+
+    /// Write a frame captured on an interface. The direction is the one of
+    /// the cable: from A to B is outbound, from B to A inbound
+    pub fn write_packet(
+        &mut self,
+        interface: u32,
+        time: SystemTime,
+        direction: Direction,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let micros = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let len = data.len() as u32;
+
+        let buf = match self.format {
+            Format::Pcap => {
+                let mut buf = Vec::with_capacity(data.len() + 16);
+                buf.extend(((micros / 1_000_000) as u32).to_le_bytes());
+                buf.extend(((micros % 1_000_000) as u32).to_le_bytes());
+                buf.extend(len.to_le_bytes());
+                buf.extend(len.to_le_bytes());
+                buf.extend(data);
+                buf
+            }
+            Format::Pcapng => {
+                let mut epb = Vec::with_capacity(data.len() + 32);
+                epb.extend(interface.to_le_bytes());
+                epb.extend(((micros >> 32) as u32).to_le_bytes());
+                epb.extend((micros as u32).to_le_bytes());
+                epb.extend(len.to_le_bytes());
+                epb.extend(len.to_le_bytes());
+                epb.extend(data);
+                epb.resize(epb.len().next_multiple_of(4), 0);
+                let flags = match direction {
+                    Direction::BToA => EPB_FLAG_INBOUND,
+                    _ => EPB_FLAG_OUTBOUND,
+                };
+                push_option(&mut epb, OPT_EPB_FLAGS, &flags.to_le_bytes());
+                push_option(&mut epb, OPT_END, &[]);
+                block(BLOCK_ENHANCED_PACKET, epb)
+            }
+        };
+        self.out.write_all(&buf)?;
+        self.out.flush()
+    }
+}
+
+/// A frame read from a capture
+pub struct Packet {
+    pub time: SystemTime,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+/// Reader of the pcapng streams written by `Writer`. Only the packets are
+/// returned, the other blocks are skipped
+pub struct Reader<R: Read> {
+    input: R,
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        buf.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+/// Parse the body of an enhanced packet block
+fn parse_packet(body: &[u8]) -> Option<Packet> {
+    let micros = (u64::from(u32_at(body, 4)?) << 32) | u64::from(u32_at(body, 8)?);
+    let len = u32_at(body, 12)? as usize;
+    let data = body.get(20..20 + len)?.to_vec();
+
+    let mut direction = Direction::AToB;
+    let mut offset = 20 + len.next_multiple_of(4);
+    while let (Some(code), Some(len)) = (u16_at(body, offset), u16_at(body, offset + 2)) {
+        if code == OPT_END {
+            break;
+        }
+        if code == OPT_EPB_FLAGS && u32_at(body, offset + 4)? & 3 == EPB_FLAG_INBOUND {
+            direction = Direction::BToA;
+        }
+        offset += 4 + (len as usize).next_multiple_of(4);
+    }
+
+    Some(Packet {
+        time: UNIX_EPOCH + Duration::from_micros(micros),
+        direction,
+        data,
+    })
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(input: R) -> Reader<R> {
+        Reader { input }
+    }
+
+    /// Read the next packet. None is returned at the end of the stream
+    pub fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            let mut header = [0u8; 8];
+            match self.input.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            let kind = u32_at(&header, 0).unwrap();
+            let len = u32_at(&header, 4).unwrap() as usize;
+            if len < 12 || !len.is_multiple_of(4) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid pcapng block length {len}"),
+                ));
+            }
+
+            // Body and trailing length
+            let mut body = vec![0u8; len - 8];
+            self.input.read_exact(&mut body)?;
+            body.truncate(len - 12);
+
+            if kind == BLOCK_ENHANCED_PACKET {
+                return parse_packet(&body).map(Some).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid pcapng packet")
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcap_layout() {
+        let mut w = Writer::new(Vec::new(), Format::Pcap, &[]).unwrap();
+        w.write_packet(0, UNIX_EPOCH, Direction::AToB, &[1, 2, 3])
+            .unwrap();
+        assert_eq!(w.out.len(), 24 + 16 + 3);
+        assert_eq!(&w.out[..4], &PCAP_MAGIC.to_le_bytes());
+    }
+
+    #[test]
+    fn pcapng_blocks_are_aligned() {
+        let iface = Interface {
+            name: "conn1".to_owned(),
+            description: "ns1 <-> sw1".to_owned(),
+        };
+        let mut w = Writer::new(Vec::new(), Format::Pcapng, &[iface]).unwrap();
+        w.write_packet(0, SystemTime::now(), Direction::BToA, &[0; 61])
+            .unwrap();
+
+        // Walk the blocks using their lengths
+        let mut offset = 0;
+        let mut kinds = vec![];
+        while offset < w.out.len() {
+            let kind = u32::from_le_bytes(w.out[offset..offset + 4].try_into().unwrap());
+            let len = u32::from_le_bytes(w.out[offset + 4..offset + 8].try_into().unwrap());
+            assert_eq!(len % 4, 0);
+            kinds.push(kind);
+            offset += len as usize;
+        }
+        assert_eq!(offset, w.out.len());
+        assert_eq!(
+            kinds,
+            vec![
+                BLOCK_SECTION_HEADER,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_ENHANCED_PACKET
+            ]
+        );
+    }
+
+    #[test]
+    fn pcapng_round_trip() {
+        let mut w = Writer::new(Vec::new(), Format::Pcapng, &[]).unwrap();
+        let time = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        w.write_packet(0, time, Direction::AToB, &[1; 5]).unwrap();
+        w.write_packet(0, time, Direction::BToA, &[2; 64]).unwrap();
+
+        let mut r = Reader::new(w.out.as_slice());
+        let p = r.read_packet().unwrap().unwrap();
+        assert_eq!(
+            (p.time, p.direction, p.data),
+            (time, Direction::AToB, vec![1; 5])
+        );
+        let p = r.read_packet().unwrap().unwrap();
+        assert_eq!((p.direction, p.data), (Direction::BToA, vec![2; 64]));
+        assert!(r.read_packet().unwrap().is_none());
+    }
+}
@@ -0,0 +1,263 @@
+//! The capture stage of a cable. It runs inside the dpipe chain of the cable,
+//! next to the vde_plug of endpoint A, forwarding the frames between its two
+//! sides and recording them. It is controlled through a unix socket, with
+//! one command per connection:
+//! - `start <path>` records the frames in a new file, replacing the previous
+//! - `stop` stops recording
+//! - `status` answers `recording <path>` or `idle`
+//! - `stream <pcap|pcapng>` answers `ok` and keeps sending the frames on the
+//!   same connection, in the given format
+//! - `stats` answers the traffic counters of both directions, in json
+
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::fd::FromRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Instant, SystemTime};
+
+use super::pcap::{Format, Interface, Writer};
+use crate::stats::{Meter, Traffic};
+use crate::vde::Direction;
+
+/// File descriptors used by dpipe for the side of the next command
+const ALTERNATE_STDIN: i32 = 3;
+const ALTERNATE_STDOUT: i32 = 4;
+/// Frames waiting to be sent to a stream reader. Every reader is fed by its
+/// own thread: when the reader falls behind and its queue is full, the new
+/// frames are dropped for it, so that it never slows down the cable
+const STREAM_QUEUE: usize = 1024;
+
+/// A frame for the stream readers, with when and in which direction it was seen
+type Packet = (SystemTime, Direction, Vec<u8>);
+
+struct Recorder {
+    interface: Interface,
+    output: Option<(String, Writer<File>)>,
+    streams: Vec<SyncSender<Packet>>,
+    /// Counters from A to B and from B to A
+    meters: [Meter; 2],
+}
+
+impl Recorder {
+    fn interfaces(&self) -> [Interface; 1] {
+        [Interface {
+            name: self.interface.name.clone(),
+            description: self.interface.description.clone(),
+        }]
+    }
+
+    fn start(&mut self, path: &str) -> Result<()> {
+        let file = File::create(path).context(format!("Creating {path}"))?;
+        let writer = Writer::new(file, Format::from_path(path), &self.interfaces())
+            .context(format!("Writing {path}"))?;
+        self.output = Some((path.to_owned(), writer));
+        Ok(())
+    }
+
+    /// Send the frames to a new reader, from its own thread. The thread ends
+    /// when the reader goes away
+    fn stream(&mut self, stream: UnixStream, format: Format) {
+        let (packets, queue) = mpsc::sync_channel::<Packet>(STREAM_QUEUE);
+        let interfaces = self.interfaces();
+        thread::spawn(move || {
+            (&stream).write_all(b"ok\n")?;
+            let mut writer = Writer::new(stream, format, &interfaces)?;
+            for (time, direction, frame) in queue {
+                writer.write_packet(0, time, direction, &frame)?;
+            }
+            std::io::Result::Ok(())
+        });
+        self.streams.push(packets);
+    }
+
+    fn record(&mut self, time: SystemTime, direction: Direction, frame: &[u8]) {
+        let meter = match direction {
+            Direction::BToA => &mut self.meters[1],
+            _ => &mut self.meters[0],
+        };
+        meter.record(Instant::now(), frame.len());
+
+        if let Some((path, writer)) = &mut self.output {
+            if let Err(e) = writer.write_packet(0, time, direction, frame) {
+                log::error!("Writing {path}: {e}, capture stopped");
+                self.output = None;
+            }
+        }
+        self.streams
+            .retain(|s| match s.try_send((time, direction, frame.to_vec())) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+
+    fn stats(&mut self) -> Result<String> {
+        let now = Instant::now();
+        let traffic = Traffic {
+            a_to_b: self.meters[0].counters(now),
+            b_to_a: self.meters[1].counters(now),
+        };
+        Ok(serde_json::to_string(&traffic)?)
+    }
+
+    fn status(&self) -> String {
+        match &self.output {
+            Some((path, _)) => format!("recording {path}"),
+            None => "idle".to_owned(),
+        }
+    }
+}
+
+/// Forward the frames from input to output. Frames are exchanged in the vde
+/// stream format: a 2 bytes big endian length, followed by the frame
+fn forward(
+    mut input: File,
+    mut output: File,
+    direction: Direction,
+    recorder: &Mutex<Recorder>,
+) -> std::io::Result<()> {
+    let mut frame = vec![0u8; 2 + u16::MAX as usize];
+    loop {
+        input.read_exact(&mut frame[..2])?;
+        let len = u16::from_be_bytes([frame[0], frame[1]]) as usize;
+        input.read_exact(&mut frame[2..2 + len])?;
+
+        recorder
+            .lock()
+            .unwrap()
+            .record(SystemTime::now(), direction, &frame[2..2 + len]);
+        output.write_all(&frame[..2 + len])?;
+    }
+}
+
+fn handle(stream: UnixStream, recorder: &Mutex<Recorder>) -> Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let (command, arg) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+
+    let mut recorder = recorder.lock().unwrap();
+    let format = match arg {
+        "pcapng" => Format::Pcapng,
+        _ => Format::Pcap,
+    };
+    let reply = match command {
+        "stream" => {
+            recorder.stream(stream, format);
+            return Ok(());
+        }
+        "start" => match recorder.start(arg) {
+            Ok(()) => "ok".to_owned(),
+            Err(e) => format!("error {e:#}"),
+        },
+        "stop" => {
+            recorder.output = None;
+            "ok".to_owned()
+        }
+        "status" => recorder.status(),
+        "stats" => recorder.stats()?,
+        _ => format!("error unknown command {command}"),
+    };
+    (&stream).write_all(format!("{reply}\n").as_bytes())?;
+    Ok(())
+}
+
+/// Run the capture stage until one of the sides of the cable is closed
+pub fn run(name: &str, description: &str, socket: &str, output: Option<&str>) -> Result<()> {
+    let recorder = Arc::new(Mutex::new(Recorder {
+        interface: Interface {
+            name: name.to_owned(),
+            description: description.to_owned(),
+        },
+        output: None,
+        streams: Vec::new(),
+        meters: [Meter::new(false), Meter::new(false)],
+    }));
+    if let Some(path) = output {
+        recorder.lock().unwrap().start(path)?;
+    }
+
+    if fs::exists(socket)? {
+        fs::remove_file(socket).context("Removing old control socket")?;
+    }
+    let listener = UnixListener::bind(socket).context("Creating control socket")?;
+    let control = Arc::clone(&recorder);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = handle(stream, &control) {
+                log::warn!("Control connection: {e:#}");
+            }
+        }
+    });
+
+    // SAFETY: dpipe gives the standard file descriptors and the alternate
+    // ones to every command in the middle of the chain, and nothing else in
+    // this process uses them
+    let (stdin, stdout, alt_stdin, alt_stdout) = unsafe {
+        (
+            File::from_raw_fd(0),
+            File::from_raw_fd(1),
+            File::from_raw_fd(ALTERNATE_STDIN),
+            File::from_raw_fd(ALTERNATE_STDOUT),
+        )
+    };
+
+    let (done, finished) = mpsc::channel();
+    for (input, output, direction) in [
+        (stdin, alt_stdout, Direction::AToB),
+        (alt_stdin, stdout, Direction::BToA),
+    ] {
+        let recorder = Arc::clone(&recorder);
+        let done = done.clone();
+        thread::spawn(move || {
+            let _ = done.send(forward(input, output, direction, &recorder));
+        });
+    }
+
+    // The cable is gone as soon as one side closes
+    if let Ok(Err(e)) = finished.recv() {
+        if e.kind() != std::io::ErrorKind::UnexpectedEof {
+            return Err(e).context("Forwarding frames");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn stalled_reader_does_not_block() {
+        let mut recorder = Recorder {
+            interface: Interface {
+                name: "conn1".to_owned(),
+                description: String::new(),
+            },
+            output: None,
+            streams: Vec::new(),
+            meters: [Meter::new(false), Meter::new(false)],
+        };
+        // The reader never reads, far more than the socket buffer is sent
+        let (stream, reader) = UnixStream::pair().unwrap();
+        recorder.stream(stream, Format::Pcapng);
+
+        let start = Instant::now();
+        for _ in 0..20000 {
+            recorder.record(SystemTime::now(), Direction::AToB, &[0; 1500]);
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(recorder.streams.len(), 1);
+
+        // The reader goes away, so does its stream
+        drop(reader);
+        while recorder.streams.len() == 1 && start.elapsed() < Duration::from_secs(10) {
+            recorder.record(SystemTime::now(), Direction::BToA, &[0; 1500]);
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(recorder.streams.is_empty());
+    }
+}
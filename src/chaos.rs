@@ -0,0 +1,473 @@
+//! The chaos command runs a scenario of timed faults against the running
+//! topology: cables changing their impairments, going down and flapping.
+
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+use serde::Deserialize;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::{fmt, fs, thread};
+
+use crate::executor::{self, Options};
+use crate::vde;
+
+/// Time a flapping cable stays down, if not specified
+const DEFAULT_FLAP: Duration = Duration::from_secs(1);
+/// How often an interrupt is checked while waiting for the next event
+const INTERRUPT_POLL: Duration = Duration::from_millis(100);
+
+/// Set by SIGINT, so that the cables taken down can be brought up again
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Scenario {
+    /// Time after which the scenario ends. Without it, a scenario with
+    /// periodic events runs until interrupted
+    duration: Option<String>,
+    events: Vec<Event>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    Impair,
+    Clear,
+    Down,
+    Up,
+    Flap,
+}
+
+#[derive(Debug, Deserialize)]
+struct Event {
+    cable: String,
+    /// Time of the event, from the start of the scenario
+    at: Option<String>,
+    /// Period of a repeated event. It starts at `at` if present, otherwise
+    /// after the first period
+    every: Option<String>,
+    action: Action,
+    /// How long the cable stays down, for down and flap
+    #[serde(rename = "for")]
+    down_for: Option<String>,
+    direction: Option<vde::Direction>,
+    #[serde(flatten)]
+    params: vde::FlatParams,
+}
+
+/// What is done to a cable by an event
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Impair(vde::Direction, vde::LinkParams),
+    Clear(vde::Direction),
+    Down,
+    Up,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::Impair(d, p) => {
+                let (_, commands) = vde::Impairments::default().apply(*d, p);
+                write!(f, "impair {}", commands.join(", "))
+            }
+            Step::Clear(vde::Direction::Both) => write!(f, "clear"),
+            Step::Clear(vde::Direction::AToB) => write!(f, "clear a2b"),
+            Step::Clear(vde::Direction::BToA) => write!(f, "clear b2a"),
+            Step::Down => write!(f, "down"),
+            Step::Up => write!(f, "up"),
+        }
+    }
+}
+
+/// An event checked against the topology
+struct Fault {
+    cable: String,
+    first: Duration,
+    every: Option<Duration>,
+    step: Step,
+    /// Time after which a cable taken down is brought up again
+    restore_after: Option<Duration>,
+}
+
+/// A step waiting for its time. Ordered by time, then by insertion
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Pending {
+    time: Duration,
+    seq: usize,
+    fault: usize,
+    restore: bool,
+}
+
+/// Steps of a scenario in chronological order
+struct Timeline {
+    faults: Vec<Fault>,
+    queue: BinaryHeap<Reverse<Pending>>,
+    seq: usize,
+    end: Option<Duration>,
+}
+
+/// Parse a duration, i.e. 500ms, 10s, 2m, 1h. Without a unit, seconds are used
+fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let (number, factor) = if let Some(n) = s.strip_suffix("ms") {
+        (n, 0.001)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1.0)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 60.0)
+    } else if let Some(n) = s.strip_suffix('h') {
+        (n, 3600.0)
+    } else {
+        (s, 1.0)
+    };
+    match number.trim().parse::<f64>() {
+        Ok(n) if n >= 0.0 && n.is_finite() => Ok(Duration::from_secs_f64(n * factor)),
+        _ => bail!("Invalid duration {s}: use a number followed by ms, s, m or h"),
+    }
+}
+
+fn parse_optional_duration(s: &Option<String>) -> Result<Option<Duration>> {
+    s.as_deref().map(parse_duration).transpose()
+}
+
+impl Event {
+    fn to_fault(&self, t: &vde::Topology) -> Result<Fault> {
+        let Some(cable) = t.get_cables().iter().find(|c| c.get_name() == &self.cable) else {
+            bail!("Cable {} not found", self.cable);
+        };
+        self.params.check_unknown()?;
+        let params = &self.params.params;
+
+        let at = parse_optional_duration(&self.at)?;
+        let every = parse_optional_duration(&self.every)?;
+        let down_for = parse_optional_duration(&self.down_for)?;
+        if every == Some(Duration::ZERO) {
+            bail!("The period of an event can't be 0");
+        }
+        let Some(first) = at.or(every) else {
+            bail!("An event needs at least one of at and every");
+        };
+
+        let impairs = matches!(self.action, Action::Impair | Action::Clear);
+        if impairs && !cable.can_impair() {
+            bail!("Cable {} has no wirefilter", self.cable);
+        }
+        if !impairs && self.direction.is_some() {
+            bail!("direction is only valid with impair and clear");
+        }
+        if (self.action == Action::Impair) == params.is_empty() {
+            bail!("Impairments are needed by impair and only valid with it");
+        }
+        if down_for.is_some() && !matches!(self.action, Action::Down | Action::Flap) {
+            bail!("for is only valid with down and flap");
+        }
+
+        let direction = self.direction.unwrap_or(vde::Direction::Both);
+        let (step, restore_after) = match self.action {
+            Action::Impair => {
+                params.checks()?;
+                (Step::Impair(direction, params.clone()), None)
+            }
+            Action::Clear => (Step::Clear(direction), None),
+            Action::Down => (Step::Down, down_for),
+            Action::Up => (Step::Up, None),
+            Action::Flap => (Step::Down, Some(down_for.unwrap_or(DEFAULT_FLAP))),
+        };
+
+        Ok(Fault {
+            cable: self.cable.clone(),
+            first,
+            every,
+            step,
+            restore_after,
+        })
+    }
+}
+
+impl Timeline {
+    fn new(scenario: &Scenario, t: &vde::Topology) -> Result<Timeline> {
+        let mut faults = Vec::new();
+        for (i, e) in scenario.events.iter().enumerate() {
+            faults.push(e.to_fault(t).context(format!("Checking event {}", i + 1))?);
+        }
+
+        let end = parse_optional_duration(&scenario.duration).context("Checking duration")?;
+        if end.is_none() && faults.iter().any(|f| f.every.is_some()) {
+            log::warn!(
+                "The scenario has periodic events and no duration: it runs until interrupted"
+            );
+        }
+
+        let mut timeline = Timeline {
+            faults,
+            queue: BinaryHeap::new(),
+            seq: 0,
+            end,
+        };
+        for i in 0..timeline.faults.len() {
+            timeline.push(timeline.faults[i].first, i, false);
+        }
+        Ok(timeline)
+    }
+
+    fn push(&mut self, time: Duration, fault: usize, restore: bool) {
+        self.queue.push(Reverse(Pending {
+            time,
+            seq: self.seq,
+            fault,
+            restore,
+        }));
+        self.seq += 1;
+    }
+
+    /// Cables waiting to be brought up again, removing them from the queue
+    fn take_restores(&mut self) -> Vec<String> {
+        let mut cables: Vec<String> = self
+            .queue
+            .drain()
+            .filter(|Reverse(p)| p.restore)
+            .map(|Reverse(p)| self.faults[p.fault].cable.clone())
+            .collect();
+        cables.sort();
+        cables.dedup();
+        cables
+    }
+}
+
+impl Iterator for Timeline {
+    type Item = (Duration, String, Step);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Cables taken down are always brought up again, even after the end
+        let p = loop {
+            let Reverse(p) = self.queue.pop()?;
+            if p.restore || self.end.is_none_or(|end| p.time <= end) {
+                break p;
+            }
+        };
+
+        let fault = &self.faults[p.fault];
+        if p.restore {
+            return Some((p.time, fault.cable.clone(), Step::Up));
+        }
+
+        let item = (p.time, fault.cable.clone(), fault.step.clone());
+        let (every, restore_after) = (fault.every, fault.restore_after);
+        if let Some(after) = restore_after {
+            self.push(p.time + after, p.fault, true);
+        }
+        if let Some(every) = every {
+            self.push(p.time + every, p.fault, false);
+        }
+        Some(item)
+    }
+}
+
+fn execute(opts: &Options, cable: &str, step: &Step) -> Result<()> {
+    let cable = cable.to_owned();
+    match step {
+        Step::Impair(d, p) => executor::topology_impair(opts, cable, *d, Some(p.clone())),
+        Step::Clear(d) => executor::topology_impair(opts, cable, *d, None),
+        Step::Down => executor::topology_link(opts, cable, false),
+        Step::Up => executor::topology_link(opts, cable, true),
+    }
+}
+
+/// Run the scenario in `file` against the running topology
+pub fn run(opts: &Options, file: &str) -> Result<()> {
+    let content = fs::read_to_string(file).context(format!("Reading {file}"))?;
+    let scenario: Scenario = serde_yaml::from_str(&content).context("Parsing scenario")?;
+    let t = executor::get_topology(opts).context("Getting topology")?;
+    let timeline = Timeline::new(&scenario, &t)?;
+
+    // SAFETY: the handler only stores into an atomic, which is async signal safe
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        )
+    };
+    INTERRUPTED.store(false, Ordering::SeqCst);
+
+    println!("{} {file}", "Running scenario".bold());
+    let mut timeline = timeline;
+    let start = Instant::now();
+    let mut failures = 0;
+    while let Some((time, cable, step)) = timeline.next() {
+        while let Some(wait) = time.checked_sub(start.elapsed()) {
+            if INTERRUPTED.load(Ordering::SeqCst) {
+                break;
+            }
+            thread::sleep(wait.min(INTERRUPT_POLL));
+        }
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            // The pending step was not run: a cable it brings up is still down
+            let mut cables = timeline.take_restores();
+            if step == Step::Up {
+                cables.push(cable);
+            }
+            return interrupted(opts, cables);
+        }
+
+        let stamp = format!("[{:>8.1}s]", time.as_secs_f64());
+        match execute(opts, &cable, &step) {
+            Ok(()) => println!("{stamp} {} {step}", cable.bold()),
+            Err(e) => {
+                failures += 1;
+                println!("{stamp} {} {step} {}: {e:#}", cable.bold(), "failed".red());
+            }
+        }
+    }
+
+    // SAFETY: restoring the default action has no memory safety requirements
+    unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+
+    if failures > 0 {
+        bail!("{failures} event(s) failed");
+    }
+    Ok(())
+}
+
+/// Bring up the cables taken down by the scenario, then stop
+fn interrupted(opts: &Options, mut cables: Vec<String>) -> Result<()> {
+    // SAFETY: restoring the default action has no memory safety requirements
+    unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+
+    cables.sort();
+    cables.dedup();
+    println!(
+        "{}, restoring the cables taken down",
+        "Interrupted".yellow()
+    );
+    for cable in &cables {
+        match execute(opts, cable, &Step::Up) {
+            Ok(()) => println!("{} {}", cable.bold(), Step::Up),
+            Err(e) => println!(
+                "{} {} {}: {e:#}, run `imaginet link up {cable}`",
+                cable.bold(),
+                Step::Up,
+                "failed".red()
+            ),
+        }
+    }
+    bail!("Scenario interrupted")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topology() -> vde::Topology {
+        let mut t = vde::Topology::new();
+        for (name, wirefilter) in [("conn1", true), ("conn2", false)] {
+            t.add_cable(vde::Cable::new(
+                name.to_owned(),
+                "a".to_owned(),
+                None,
+                vde::VdeConnProtocols::VDE,
+                false,
+                "b".to_owned(),
+                None,
+                vde::VdeConnProtocols::VDE,
+                false,
+                Some(wirefilter),
+            ))
+            .unwrap();
+        }
+        t
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("10").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert!(parse_duration("soon").is_err());
+    }
+
+    #[test]
+    fn timeline_order() {
+        let scenario: Scenario = serde_yaml::from_str(
+            r#"
+duration: 25s
+events:
+  - at: 10s
+    cable: conn1
+    action: impair
+    loss: 30
+  - every: 10s
+    cable: conn2
+    action: flap
+    for: 6s
+"#,
+        )
+        .unwrap();
+        let steps: Vec<_> = Timeline::new(&scenario, &topology())
+            .unwrap()
+            .map(|(time, cable, step)| (time.as_secs(), cable, step.to_string()))
+            .collect();
+
+        let expected = [
+            (10, "conn1", "impair loss 30"),
+            (10, "conn2", "down"),
+            (16, "conn2", "up"),
+            (20, "conn2", "down"),
+            (26, "conn2", "up"),
+        ];
+        assert_eq!(steps.len(), expected.len());
+        for (s, e) in steps.iter().zip(expected) {
+            assert_eq!((s.0, s.1.as_str(), s.2.as_str()), e);
+        }
+    }
+
+    #[test]
+    fn interrupt_restores() {
+        let scenario: Scenario = serde_yaml::from_str(
+            "events: [{at: 1s, cable: conn2, action: down, for: 5s}, {at: 2s, cable: conn1, action: down}]",
+        )
+        .unwrap();
+        let mut timeline = Timeline::new(&scenario, &topology()).unwrap();
+        assert_eq!(timeline.next().unwrap().2, Step::Down);
+
+        assert_eq!(timeline.take_restores(), vec!["conn2"]);
+        assert!(timeline.next().is_none());
+    }
+
+    #[test]
+    fn invalid_events() {
+        let check = |yaml: &str| {
+            let scenario: Scenario = serde_yaml::from_str(yaml).unwrap();
+            Timeline::new(&scenario, &topology()).is_err()
+        };
+        // Impairments on a cable without wirefilter
+        assert!(check(
+            "events: [{at: 1s, cable: conn2, action: impair, loss: 5}]"
+        ));
+        // No time
+        assert!(check("events: [{cable: conn1, action: down}]"));
+        // Unknown cable
+        assert!(check("events: [{at: 1s, cable: conn9, action: up}]"));
+        // Misspelled parameter
+        assert!(check(
+            "events: [{at: 1s, cable: conn1, action: impair, lost: 5}]"
+        ));
+        // Percentages as in `imaginet impair`
+        assert!(serde_yaml::from_str::<Scenario>(
+            "events: [{at: 1s, cable: conn1, action: impair, loss: 120%}]"
+        )
+        .is_err());
+        assert!(!check(
+            "events: [{at: 1s, cable: conn1, action: impair, loss: 5%, dup: 1}]"
+        ));
+        assert!(!check(
+            "events: [{at: 1s, cable: conn1, action: down, for: 5s}]"
+        ));
+    }
+}
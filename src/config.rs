@@ -4,7 +4,11 @@ use std::collections::{HashMap, HashSet};
 use std::net;
 use std::path::PathBuf;
 
+use crate::vde::{Engine, FlatParams, LinkParams, NetnodeKind};
+
 const DEFAULT_SWITCH_PORTS: u32 = 32;
+/// Highest vlan id accepted by vde_switch
+const MAX_VLAN_ID: u16 = 4094;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct Endpoint {
@@ -15,6 +19,9 @@ pub struct Endpoint {
     // in the VDE topology file, but it creates a PTP connection that can
     // be manually used to connect external devices.
     pub open: Option<bool>,
+    /// vdeplug plugins nested around the endpoint, the first is the outermost
+    #[serde(default)]
+    pub plugins: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -23,6 +30,22 @@ pub struct Switch {
     pub config: Option<String>,
     pub ports: Option<u32>,
     pub hub: Option<bool>,
+    /// Arguments appended to vde_switch
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+    #[serde(default)]
+    pub vlans: Vec<Vlan>,
+    #[serde(default)]
+    pub engine: Engine,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Vlan {
+    pub id: u16,
+    #[serde(default)]
+    pub untagged: Vec<u32>,
+    #[serde(default)]
+    pub tagged: Vec<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,6 +54,9 @@ pub struct Namespace {
     pub interfaces: Vec<NSInterface>,
     #[serde(default)]
     pub commands: Vec<String>,
+    /// Arguments passed to vdens
+    #[serde(default)]
+    pub extra_args: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -47,12 +73,49 @@ pub struct Cable {
     pub endpoint_b: Endpoint,
     pub wirefilter: Option<bool>,
     pub config: Option<String>,
+    /// Arguments appended to each vde_plug of the cable
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+    /// Arguments appended to wirefilter
+    #[serde(default)]
+    pub wirefilter_extra_args: Vec<String>,
+    /// Impairments applied by wirefilter. They imply wirefilter
+    pub impairments: Option<Impairments>,
+    /// Record the frames crossing the cable from when it starts
+    #[serde(default)]
+    pub capture: bool,
+    /// Native cables are run by imaginet in place of dpipe and wirefilter
+    #[serde(default)]
+    pub engine: Engine,
+}
+
+/// The impairments of a cable. The parameters at the top level apply to
+/// both directions, a_to_b and b_to_a override them for a single direction
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Impairments {
+    #[serde(flatten)]
+    pub both: FlatParams,
+    pub a_to_b: Option<LinkParams>,
+    pub b_to_a: Option<LinkParams>,
+}
+
+impl Impairments {
+    /// Parameters for the direction from A to B and from B to A
+    pub fn directions(&self) -> (LinkParams, LinkParams) {
+        let a_to_b = self.a_to_b.clone().unwrap_or_default();
+        let b_to_a = self.b_to_a.clone().unwrap_or_default();
+        let both = &self.both.params;
+        (both.merge(&a_to_b), both.merge(&b_to_a))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Slirp {
     pub name: String,
     // I can't find a manual that lists configuration options
+    /// Arguments appended to vde_plug
+    #[serde(default)]
+    pub extra_args: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -60,6 +123,31 @@ pub struct VXVDE {
     pub name: String,
     pub addr: Option<String>,
     pub port: Option<u16>,
+    /// Arguments appended to vde_plug
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Netnode {
+    pub name: String,
+    pub kind: NetnodeKind,
+    /// Arguments appended to vde_plug
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Tap {
+    pub name: String,
+    /// Tap interface of the host
+    pub interface: String,
+    /// Offer to create the interface when it is missing
+    #[serde(default)]
+    pub create: bool,
+    /// Arguments appended to vde_plug
+    #[serde(default)]
+    pub extra_args: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -69,12 +157,15 @@ pub struct Config {
     pub cable: Option<Vec<Cable>>,
     pub slirp: Option<Vec<Slirp>>,
     pub vxvde: Option<Vec<VXVDE>>,
+    pub netnode: Option<Vec<Netnode>>,
+    pub tap: Option<Vec<Tap>>,
+    /// Executables for this topology only
+    pub executables: Option<crate::vde::Executables>,
 }
 
 impl Config {
     pub fn from_string(file: &str, relative_path: PathBuf) -> Result<Config> {
-        let mut c =
-            serde_yaml::from_str::<Self>(&file).context("Deserialize config file failed")?;
+        let mut c = serde_yaml::from_str::<Self>(file).context("Deserialize config file failed")?;
 
         c.convert_paths(relative_path)
             .context("Could not convert all paths to be relative to the main config file")?;
@@ -91,6 +182,7 @@ impl Config {
         // For now the only paths present in the config file are
         // - Switches config files
         // - Cables config files
+        // - Executables given as a path
 
         if let Some(ref mut sws) = &mut self.switch {
             for sw in sws {
@@ -110,6 +202,13 @@ impl Config {
             }
         }
 
+        // The topology is used from any directory, so the paths of the
+        // executables are made absolute
+        if let Some(exe) = &mut self.executables {
+            exe.resolve_paths(&relative_path)
+                .context("Converting executable paths")?;
+        }
+
         Ok(())
     }
 
@@ -184,6 +283,33 @@ impl Config {
             }
         }
 
+        log::trace!("Checking netnode's name uniqueness");
+        if let Some(nns) = &self.netnode {
+            for nn in nns {
+                log::trace!("Netnode {}", &nn.name);
+                if !set.insert(&nn.name) {
+                    anyhow::bail!("Netnode name {} is not unique", nn.name);
+                }
+            }
+        }
+
+        log::trace!("Checking tap's name uniqueness");
+        if let Some(taps) = &self.tap {
+            let mut interfaces = HashSet::new();
+            for tap in taps {
+                log::trace!("Tap {}", &tap.name);
+                if !set.insert(&tap.name) {
+                    anyhow::bail!("Tap name {} is not unique", tap.name);
+                }
+
+                crate::vde::Tap::check_interface(&tap.interface)
+                    .context(format!("Checks failed for tap {}", tap.name))?;
+                if !interfaces.insert(&tap.interface) {
+                    anyhow::bail!("Interface {} is used by more than one tap", tap.interface);
+                }
+            }
+        }
+
         drop(set);
 
         // Endpoints must exist and ports must be valid
@@ -193,6 +319,8 @@ impl Config {
         let mut namespaces = HashSet::new();
         let mut slirps = HashSet::new();
         let mut vxvdes = HashSet::new();
+        let mut netnodes = HashSet::new();
+        let mut taps = HashSet::new();
 
         if let Some(sw) = &self.switch {
             for s in sw {
@@ -211,6 +339,7 @@ impl Config {
                         name: s.name.clone(),
                         port: Some(ports.to_string()),
                         open: Some(false),
+                        plugins: Vec::new(),
                     },
                 );
             }
@@ -227,6 +356,7 @@ impl Config {
                             name: i.name.clone(),
                             port: Some(1.to_string()),
                             open: Some(false),
+                            plugins: Vec::new(),
                         },
                     );
                 }
@@ -243,6 +373,7 @@ impl Config {
                         name: s.name.clone(),
                         port: Some(1.to_string()),
                         open: Some(false),
+                        plugins: Vec::new(),
                     },
                 );
             }
@@ -258,6 +389,40 @@ impl Config {
                         name: vx.name.clone(),
                         port: Some(1.to_string()),
                         open: Some(false),
+                        plugins: Vec::new(),
+                    },
+                );
+            }
+        }
+
+        if let Some(nns) = &self.netnode {
+            for nn in nns {
+                netnodes.insert(&nn.name);
+
+                // Netnodes have no limit on the number of plugs
+                endpoint_map.insert(
+                    &nn.name,
+                    Endpoint {
+                        name: nn.name.clone(),
+                        port: Some(1.to_string()),
+                        open: Some(false),
+                        plugins: Vec::new(),
+                    },
+                );
+            }
+        }
+
+        if let Some(tps) = &self.tap {
+            for tap in tps {
+                taps.insert(&tap.name);
+
+                endpoint_map.insert(
+                    &tap.name,
+                    Endpoint {
+                        name: tap.name.clone(),
+                        port: Some(1.to_string()),
+                        open: Some(false),
+                        plugins: Vec::new(),
                     },
                 );
             }
@@ -267,6 +432,13 @@ impl Config {
         // This simply checks if the endpoint exists and if the port is valid.
         // based on the map we created before.
         let endpoint_check = |name: String, port: Option<&String>| -> Result<()> {
+            if crate::vde::is_uri(&name) {
+                if port.is_some() {
+                    bail!("The vdeplug URI {name} has no ports");
+                }
+                return crate::vde::check_uri(&name);
+            }
+
             let end = endpoint_map
                 .get(&name)
                 .ok_or_else(|| anyhow::anyhow!("Endpoint {name} does not exist"))?;
@@ -281,7 +453,7 @@ impl Config {
             //
             // If name is a namespace interface we need to check if the
             // interface exists on the namespace
-            if switches.get(&name).is_some() {
+            if switches.contains(&name) {
                 if port.is_none() {
                     return Ok(());
                 }
@@ -304,9 +476,11 @@ impl Config {
                         "Port {int_port} is out of range for endpoint {name} (max {int_endport} ports){s}"
                     );
                 }
-            } else if namespaces.get(&name).is_some()
-                || slirps.get(&name).is_some()
-                || vxvdes.get(&name).is_some()
+            } else if namespaces.contains(&name)
+                || slirps.contains(&name)
+                || vxvdes.contains(&name)
+                || netnodes.contains(&name)
+                || taps.contains(&name)
             {
                 // The only check is that the port exists here.
                 // Nothing needs to ben done as the previous code already
@@ -339,12 +513,12 @@ impl Config {
                         .context(format!("Checks failed for cable {} endpoint B", c.name))?;
                 }
 
-                for edpt in vec![&c.endpoint_a, &c.endpoint_b] {
+                for edpt in [&c.endpoint_a, &c.endpoint_b] {
                     // If endpoint is a switch we add it to the used map. If the
                     // port is specified we also add it to the set. For namespaces
                     // only the set is used.
                     let name = &edpt.name;
-                    if switches.get(name).is_some() {
+                    if switches.contains(name) {
                         *multi_used_map.entry(name).or_default() += 1;
                         if let Some(port) = edpt.port.as_ref() {
                             if !used_set.insert(format!("{name}-{port}")) {
@@ -353,7 +527,7 @@ impl Config {
                                 )
                             }
                         }
-                    } else if namespaces.get(name).is_some() {
+                    } else if namespaces.contains(name) {
                         if let Some(port) = edpt.port.as_ref() {
                             if !used_set.insert(format!("{name}-{port}")) {
                                 anyhow::bail!(
@@ -361,10 +535,10 @@ impl Config {
                                 )
                             }
                         }
-                    } else if slirps.get(name).is_some() {
-                        if !used_set.insert(name.clone()) {
-                            anyhow::bail!("Slirp {name} is used more than once.");
-                        }
+                    } else if slirps.contains(name) && !used_set.insert(name.clone()) {
+                        anyhow::bail!("Slirp {name} is used more than once.");
+                    } else if taps.contains(name) && !used_set.insert(name.clone()) {
+                        anyhow::bail!("Tap {name} is used more than once.");
                     }
                 }
             }
@@ -376,7 +550,7 @@ impl Config {
         // If endpoint is a namespace we must use an interface only once
         for (name, ports) in endpoint_map {
             let used = multi_used_map.get(&name).unwrap_or(&0);
-            if switches.get(&name).is_some() {
+            if switches.contains(&name) {
                 let total_ports = ports.port.unwrap();
                 let total_ports = total_ports.parse::<u32>().context(format!(
                     "Can't parse total ports into an integers: {total_ports}"
@@ -404,6 +578,57 @@ impl Switch {
             let _ = std::fs::read_to_string(c).context(format!("Reading config file {}", c))?;
         }
 
+        if self.engine == Engine::Native && !self.extra_args.is_empty() {
+            bail!(
+                "Switch {} is native, it can't have vde_switch arguments",
+                self.name
+            );
+        }
+
+        self.vlan_checks().context("Invalid vlans")
+    }
+
+    fn vlan_checks(&self) -> Result<()> {
+        if self.vlans.is_empty() {
+            return Ok(());
+        }
+
+        if self.hub.unwrap_or(false) {
+            bail!("A hub can't have vlans");
+        }
+
+        let ports = self.ports.unwrap_or(DEFAULT_SWITCH_PORTS);
+        let mut ids = HashSet::new();
+        let mut untagged = HashSet::new();
+
+        for v in &self.vlans {
+            // Vlan 0 is the default one of vde_switch, it always exists
+            if v.id == 0 || v.id > MAX_VLAN_ID {
+                bail!("Vlan id {} is out of range (1-{MAX_VLAN_ID})", v.id);
+            }
+            if !ids.insert(v.id) {
+                bail!("Vlan {} is defined more than once", v.id);
+            }
+
+            for p in v.untagged.iter().chain(&v.tagged) {
+                if *p >= ports {
+                    bail!(
+                        "Port {p} of vlan {} is out of range (max {ports} ports, zero-indexed)",
+                        v.id
+                    );
+                }
+            }
+
+            for p in &v.untagged {
+                if !untagged.insert(*p) {
+                    bail!("Port {p} is untagged in more than one vlan");
+                }
+                if v.tagged.contains(p) {
+                    bail!("Port {p} is both tagged and untagged in vlan {}", v.id);
+                }
+            }
+        }
+
         Ok(())
     }
 }
@@ -517,15 +742,73 @@ impl NSInterface {
 }
 
 impl Cable {
+    /// Impairments of vde cables can only be applied by wirefilter, so they
+    /// imply it. Native cables apply them without wirefilter
+    pub fn has_wirefilter(&self) -> bool {
+        self.engine == Engine::Vde && self.wirefilter.unwrap_or(self.impairments.is_some())
+    }
+
+    fn can_impair(&self) -> bool {
+        self.has_wirefilter() || self.engine == Engine::Native
+    }
+
     fn checks(&self) -> Result<()> {
+        for e in [&self.endpoint_a, &self.endpoint_b] {
+            if crate::vde::is_uri(&e.name) && e.open == Some(true) {
+                bail!("The vdeplug URI {} can't be an open endpoint", e.name);
+            }
+            for p in &e.plugins {
+                crate::vde::check_plugin(p).context(format!("Invalid plugin of {}", e.name))?;
+            }
+        }
+
+        if self.engine == Engine::Native {
+            if [&self.endpoint_a, &self.endpoint_b]
+                .iter()
+                .any(|e| crate::vde::is_uri(&e.name))
+            {
+                bail!("Native cables can only be plugged in devices of the topology");
+            }
+            if [&self.endpoint_a, &self.endpoint_b]
+                .iter()
+                .any(|e| !e.plugins.is_empty())
+            {
+                bail!("Plugins are not supported by native cables");
+            }
+            if self.wirefilter == Some(true) {
+                bail!("Native cables apply the impairments themselves, without wirefilter");
+            }
+            if self.capture {
+                bail!("Capture is not supported by native cables");
+            }
+            if !self.extra_args.is_empty() {
+                bail!("Extra arguments are not supported by native cables, which run no VDE tool");
+            }
+        }
+
+        if let Some(imp) = &self.impairments {
+            if !self.can_impair() {
+                bail!("Cable has impairments but wirefilter is disabled");
+            }
+
+            imp.both.check_unknown()?;
+            let (a_to_b, b_to_a) = imp.directions();
+            a_to_b.checks().context("Invalid impairments from A to B")?;
+            b_to_a.checks().context("Invalid impairments from B to A")?;
+        }
+
         if let Some(c) = &self.config {
-            if !self.wirefilter.unwrap_or(false) {
+            if !self.can_impair() {
                 anyhow::bail!("Cable has a config file but it's not a wirefilter cable",);
             }
 
             let _ = std::fs::read_to_string(c).context(format!("Reading config file {}", c))?;
         }
 
+        if !self.wirefilter_extra_args.is_empty() && !self.has_wirefilter() {
+            anyhow::bail!("Cable has wirefilter arguments but it's not a wirefilter cable");
+        }
+
         Ok(())
     }
 }
@@ -565,4 +848,160 @@ switch:
         assert_eq!(sw.ports, None);
         assert_eq!(sw.hub, Some(true));
     }
+
+    #[test]
+    fn vlan_checks() {
+        let file = r#"
+switch:
+    - name: "sw1"
+      ports: 8
+      vlans:
+        - id: 10
+          untagged: [1]
+          tagged: [7]
+        - id: 20
+          untagged: [2]
+          tagged: [7]
+"#;
+        let c = Config::from_string(file, PathBuf::new()).unwrap();
+        assert_eq!(c.switch.unwrap()[0].vlans.len(), 2);
+
+        let out_of_range = file.replace("[7]", "[8]");
+        assert!(Config::from_string(&out_of_range, PathBuf::new()).is_err());
+
+        let untagged_twice = file.replace("untagged: [2]", "untagged: [1]");
+        assert!(Config::from_string(&untagged_twice, PathBuf::new()).is_err());
+    }
+
+    #[test]
+    fn cable_impairments() {
+        let file = r#"
+switch:
+    - name: "sw1"
+    - name: "sw2"
+cable:
+    - name: "c1"
+      endpoint_a:
+        name: "sw1"
+      endpoint_b:
+        name: "sw2"
+      impairments:
+        delay: 10
+        jitter: 2
+        b_to_a:
+          loss: 5
+"#;
+        let c = Config::from_string(file, PathBuf::new()).unwrap();
+        let cable = &c.cable.unwrap()[0];
+        assert!(cable.has_wirefilter());
+
+        let (a_to_b, b_to_a) = cable.impairments.as_ref().unwrap().directions();
+        assert_eq!(a_to_b.loss, None);
+        assert_eq!(b_to_a.loss, Some(5.0));
+        assert_eq!(b_to_a.delay, Some(10.0));
+
+        let bad = file.replace("loss: 5", "loss: 500");
+        assert!(Config::from_string(&bad, PathBuf::new()).is_err());
+        let typo = file.replace("jitter: 2", "jiter: 2");
+        assert!(Config::from_string(&typo, PathBuf::new()).is_err());
+        let typo = file.replace("loss: 5", "lost: 5");
+        assert!(Config::from_string(&typo, PathBuf::new()).is_err());
+
+        let native = file.replace(
+            "      impairments:",
+            "      engine: native\n      impairments:",
+        );
+        let c = Config::from_string(&native, PathBuf::new()).unwrap();
+        let cable = &c.cable.unwrap()[0];
+        assert!(!cable.has_wirefilter());
+        assert!(cable.can_impair());
+
+        let wirefilter = native.replace("engine: native", "engine: native\n      wirefilter: true");
+        assert!(Config::from_string(&wirefilter, PathBuf::new()).is_err());
+        let extra = native.replace(
+            "engine: native",
+            "engine: native\n      extra_args: [\"-v\"]",
+        );
+        assert!(Config::from_string(&extra, PathBuf::new()).is_err());
+    }
+
+    #[test]
+    fn netnode_endpoints() {
+        let file = r#"
+netnode:
+    - name: "hub1"
+      kind: hub
+cable:
+    - name: "c1"
+      endpoint_a:
+        name: "hub1"
+      endpoint_b:
+        name: "hub1"
+"#;
+        let c = Config::from_string(file, PathBuf::new()).unwrap();
+        assert_eq!(c.netnode.unwrap()[0].kind, NetnodeKind::Hub);
+
+        let bad = file.replace("kind: hub", "kind: router");
+        assert!(Config::from_string(&bad, PathBuf::new()).is_err());
+        let duplicate = file.replace("cable:", "switch:\n    - name: \"hub1\"\ncable:");
+        assert!(Config::from_string(&duplicate, PathBuf::new()).is_err());
+    }
+
+    #[test]
+    fn tap_endpoints() {
+        let file = r#"
+switch:
+    - name: "sw1"
+tap:
+    - name: "uplink"
+      interface: "tap0"
+cable:
+    - name: "c1"
+      endpoint_a:
+        name: "sw1"
+      endpoint_b:
+        name: "uplink"
+"#;
+        let c = Config::from_string(file, PathBuf::new()).unwrap();
+        assert!(!c.tap.unwrap()[0].create);
+
+        let twice = format!("{file}    - name: \"c2\"\n      endpoint_a:\n        name: \"sw1\"\n      endpoint_b:\n        name: \"uplink\"\n");
+        assert!(Config::from_string(&twice, PathBuf::new()).is_err());
+        let bad = file.replace("tap0", "tap 0");
+        assert!(Config::from_string(&bad, PathBuf::new()).is_err());
+    }
+
+    #[test]
+    fn uri_endpoints() {
+        let file = r#"
+switch:
+    - name: "sw1"
+cable:
+    - name: "c1"
+      endpoint_a:
+        name: "sw1"
+      endpoint_b:
+        name: "udp://10.0.0.2:5000"
+"#;
+        Config::from_string(file, PathBuf::new()).unwrap();
+
+        let scheme = file.replace("udp://", "http://");
+        assert!(Config::from_string(&scheme, PathBuf::new()).is_err());
+        let port = file.replace(":5000\"", ":5000\"\n        port: \"1\"");
+        assert!(Config::from_string(&port, PathBuf::new()).is_err());
+        let native = file.replace(
+            "      endpoint_a:",
+            "      engine: native\n      endpoint_a:",
+        );
+        assert!(Config::from_string(&native, PathBuf::new()).is_err());
+
+        let plugins = file.replace(
+            "        name: \"sw1\"\n",
+            "        name: \"sw1\"\n        plugins: [\"vlan://20\", \"agno://\"]\n",
+        );
+        let c = Config::from_string(&plugins, PathBuf::new()).unwrap();
+        assert_eq!(c.cable.unwrap()[0].endpoint_a.plugins.len(), 2);
+        let bad = plugins.replace("agno://", "tap://tap0");
+        assert!(Config::from_string(&bad, PathBuf::new()).is_err());
+    }
 }
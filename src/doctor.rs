@@ -0,0 +1,495 @@
+//! The doctor command checks that everything ImagiNet needs is installed
+//! and suggests how to fix what is missing.
+
+use anyhow::{bail, Result};
+use colored::Colorize;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::{env, fs, process, thread};
+
+use crate::executor::{get_topology, Options};
+use crate::tap;
+use crate::vde::{self, VdeConnProtocols};
+
+/// Minimum nsenter version with the options used to enter the namespaces
+const NSENTER_MIN_VERSION: (u32, u32) = (2, 40);
+/// Name of the vdeplug4 plugin needed by the slirps
+const SLIRP_PLUGIN: &str = "libvdeplug_slirp.so";
+/// Plugin of vdeplug4 implementing the hub, switch, multi and bundling netnodes
+const NETNODE_PLUGIN: &str = "libvdeplug_netnode.so";
+/// Time given to an executable to print its help or version
+const COMMAND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+enum Outcome {
+    Ok(String),
+    /// Something that only breaks a part of ImagiNet
+    Warning(String, String),
+    /// Something that breaks ImagiNet
+    Error(String, String),
+}
+
+struct Check {
+    name: String,
+    outcome: Outcome,
+}
+
+impl Check {
+    fn print(&self) {
+        match &self.outcome {
+            Outcome::Ok(msg) => println!("- {} {} {msg}", self.name.bold(), "ok".green()),
+            Outcome::Warning(msg, fix) => {
+                println!("- {} {} {msg}", self.name.bold(), "warning".yellow());
+                println!("\tfix: {fix}");
+            }
+            Outcome::Error(msg, fix) => {
+                println!("- {} {} {msg}", self.name.bold(), "error".red());
+                println!("\tfix: {fix}");
+            }
+        }
+    }
+}
+
+/// Executables used by the devices, with what they are used for. They are
+/// taken from the devices themselves, so that a new command is never missed
+fn required_executables(exe: &vde::Executables) -> Vec<(String, Vec<String>)> {
+    let sw = vde::Switch::new("doctor".to_owned());
+    let ns = vde::Namespace::new("doctor".to_owned());
+    let cable = |wirefilter| {
+        vde::Cable::new(
+            "doctor".to_owned(),
+            "a".to_owned(),
+            None,
+            VdeConnProtocols::VDE,
+            false,
+            "b".to_owned(),
+            None,
+            VdeConnProtocols::VDE,
+            false,
+            Some(wirefilter),
+        )
+    };
+    let plain = cable(false);
+    let wf = cable(true);
+    let slirp = vde::Slirp::new("doctor".to_owned());
+    let vx = vde::VXVDE::new("doctor".to_owned());
+    let nn = vde::Netnode::new("doctor".to_owned(), vde::NetnodeKind::Hub);
+    let tap = vde::Tap::new("doctor".to_owned(), "tap0".to_owned());
+
+    let mut uses = vec![
+        (sw.exec_command(exe), "start switches"),
+        (sw.attach_command(exe), "attach to switches"),
+        (sw.exec_command_command(exe), "execute commands in switches"),
+        (ns.exec_command(exe), "start namespaces"),
+        (ns.attach_command(exe), "attach to namespaces"),
+        (
+            ns.exec_command_command(exe),
+            "execute commands in namespaces",
+        ),
+        (plain.exec_command(exe), "start cables"),
+        (wf.exec_command(exe), "start cables with wirefilter"),
+        // Started by dpipe for the cables with wirefilter
+        (exe.get_wirefilter(), "start cables with wirefilter"),
+        (exe.get_vde_plug(), "start cables with wirefilter"),
+        (slirp.exec_command(exe), "start slirps"),
+        (vx.exec_command(exe), "start vxvdes"),
+        (nn.exec_command(exe), "start netnodes"),
+        (tap.exec_command(exe), "start taps"),
+    ];
+    if let Ok(cmd) = wf.attach_command(exe) {
+        uses.push((cmd, "attach to cables with wirefilter"));
+    }
+    if let Ok(cmd) = wf.exec_command_command(exe) {
+        uses.push((cmd, "execute commands in cables with wirefilter"));
+    }
+
+    let mut executables: Vec<(String, Vec<String>)> = Vec::new();
+    for (cmd, usage) in uses {
+        match executables.iter_mut().find(|(c, _)| *c == cmd) {
+            Some((_, usages)) => {
+                if !usages.iter().any(|u| u == usage) {
+                    usages.push(usage.to_owned())
+                }
+            }
+            None => executables.push((cmd, vec![usage.to_owned()])),
+        }
+    }
+    executables
+}
+
+/// How to install an executable, given its name or its configured path
+fn install_hint(executable: &str) -> String {
+    let name = Path::new(executable)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let hint = match name.as_str() {
+        "vde_switch" | "vdeterm" | "vdecmd" | "wirefilter" => {
+            "install vde-2 (https://github.com/virtualsquare/vde-2)".to_owned()
+        }
+        "vde_plug" | "dpipe" => {
+            "install vdeplug4 (https://github.com/rd235/vdeplug4) after vde-2".to_owned()
+        }
+        "vdens" => "install vdens (https://github.com/rd235/vdens)".to_owned(),
+        "nsenter" => "install util-linux".to_owned(),
+        "ip" => "install iproute2".to_owned(),
+        _ => format!("install {executable} or add it to the PATH"),
+    };
+    if executable.contains('/') {
+        format!("{hint}, or fix its path in the executables section of the configuration")
+    } else {
+        hint
+    }
+}
+
+/// Find an executable in the PATH
+fn find_executable(name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        return is_executable(Path::new(name)).then(|| PathBuf::from(name));
+    }
+
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|p| is_executable(p))
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Run a command and return its output, both stdout and stderr. Some VDE
+/// tools do not know about --help or --version and could start running,
+/// so the command is killed if it does not terminate in time. The pipes are
+/// drained while waiting, so that a long output can't block the command
+fn command_output(cmd: &Path, args: &[&str]) -> Option<String> {
+    let mut child = process::Command::new(cmd)
+        .args(args)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .ok()?;
+
+    fn drain(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = pipe.read_to_end(&mut buf);
+            buf
+        })
+    }
+    let stdout = drain(child.stdout.take()?);
+    let stderr = drain(child.stderr.take()?);
+
+    let deadline = std::time::Instant::now() + COMMAND_TIMEOUT;
+    while child.try_wait().ok()?.is_none() {
+        if std::time::Instant::now() > deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            log::debug!("{} {args:?} did not terminate", cmd.display());
+            return None;
+        }
+        thread::sleep(std::time::Duration::from_millis(20));
+    }
+
+    let stdout = stdout.join().ok()?;
+    let stderr = stderr.join().ok()?;
+    Some(format!(
+        "{}{}",
+        String::from_utf8_lossy(&stdout),
+        String::from_utf8_lossy(&stderr)
+    ))
+}
+
+/// The vde_plug of vdeplug4 connects Virtual Network Locators, while the one
+/// of vde-2 only connects to a switch
+fn is_vdeplug4_usage(usage: &str) -> bool {
+    usage.contains("VNL")
+}
+
+/// Parse the output of `nsenter --version`, i.e. "nsenter from util-linux 2.40.1"
+fn parse_nsenter_version(output: &str) -> Option<(u32, u32)> {
+    let version = output.split_whitespace().last()?;
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts
+        .next()
+        .map(|m| {
+            m.chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect::<String>()
+        })
+        .and_then(|m| m.parse().ok())
+        .unwrap_or(0);
+    Some((major, minor))
+}
+
+fn check_vde_plug(path: &Path) -> Check {
+    let name = "vde_plug version".to_owned();
+    let outcome = match command_output(path, &["--help"]) {
+        Some(usage) if is_vdeplug4_usage(&usage) => {
+            Outcome::Ok(format!("{} is from vdeplug4", path.display()))
+        }
+        Some(_) => Outcome::Error(
+            format!("{} is the one from vde-2", path.display()),
+            "install vdeplug4 (https://github.com/rd235/vdeplug4) again, after vde-2, \
+             so that its vde_plug replaces the one of vde-2"
+                .to_owned(),
+        ),
+        None => Outcome::Warning(
+            format!("could not run {} --help", path.display()),
+            "run `vde_plug --help` and check that the usage mentions VNL".to_owned(),
+        ),
+    };
+    Check { name, outcome }
+}
+
+fn check_nsenter(path: &Path) -> Check {
+    let name = "nsenter version".to_owned();
+    let (min_major, min_minor) = NSENTER_MIN_VERSION;
+    let fix = format!("install util-linux {min_major}.{min_minor} or newer");
+    let outcome = match command_output(path, &["--version"])
+        .as_deref()
+        .and_then(parse_nsenter_version)
+    {
+        Some(v) if v >= NSENTER_MIN_VERSION => Outcome::Ok(format!("{}.{}", v.0, v.1)),
+        Some(v) => Outcome::Error(
+            format!(
+                "{}.{} is too old, exec in namespaces needs at least {min_major}.{min_minor}",
+                v.0, v.1
+            ),
+            fix,
+        ),
+        None => Outcome::Warning("could not detect the version".to_owned(), fix),
+    };
+    Check { name, outcome }
+}
+
+/// Directories where vdeplug4 could look for its plugins
+fn plugin_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(paths) = env::var_os("LD_LIBRARY_PATH") {
+        dirs.extend(env::split_paths(&paths).map(|p| p.join("vdeplug")));
+    }
+    for prefix in [
+        "/usr/local/lib",
+        "/usr/local/lib64",
+        "/usr/lib",
+        "/usr/lib64",
+        "/lib",
+    ] {
+        dirs.push(Path::new(prefix).join("vdeplug"));
+        // Multiarch layouts, i.e. /usr/lib/x86_64-linux-gnu/vdeplug
+        if let Ok(entries) = fs::read_dir(prefix) {
+            dirs.extend(
+                entries
+                    .flatten()
+                    .map(|e| e.path().join("vdeplug"))
+                    .filter(|p| p.is_dir()),
+            );
+        }
+    }
+    dirs
+}
+
+/// First plugin directory holding the given plugin
+fn find_plugin(file: &str) -> Option<PathBuf> {
+    plugin_dirs()
+        .into_iter()
+        .map(|d| d.join(file))
+        .find(|p| p.exists())
+}
+
+fn check_slirp_plugin() -> Check {
+    let name = "slirp plugin".to_owned();
+    let outcome = match find_plugin(SLIRP_PLUGIN) {
+        Some(p) => Outcome::Ok(format!("{}", p.display())),
+        None => Outcome::Warning(
+            format!("{SLIRP_PLUGIN} not found, slirps will not start"),
+            "install libvdeslirp (https://github.com/virtualsquare/libvdeslirp) \
+             and the vdeplug_slirp plugin (https://github.com/virtualsquare/vdeplug_slirp)"
+                .to_owned(),
+        ),
+    };
+    Check { name, outcome }
+}
+
+fn check_netnode_plugin() -> Check {
+    let name = "netnode plugin".to_owned();
+    let outcome = match find_plugin(NETNODE_PLUGIN) {
+        Some(p) => Outcome::Ok(format!("{}", p.display())),
+        None => Outcome::Warning(
+            format!("{NETNODE_PLUGIN} not found, netnodes will not start"),
+            "install vdeplug4 (https://github.com/rd235/vdeplug4), \
+             which provides the hub, switch, multi and bundling plugins"
+                .to_owned(),
+        ),
+    };
+    Check { name, outcome }
+}
+
+fn check_tun() -> Check {
+    let name = "tun device".to_owned();
+    let outcome = if !Path::new(tap::TUN_DEVICE).exists() {
+        Outcome::Warning(
+            format!("{} not found, taps will not start", tap::TUN_DEVICE),
+            "load the tun module with `modprobe tun`".to_owned(),
+        )
+    } else if !tap::tun_usable() {
+        Outcome::Warning(
+            format!(
+                "{} is not readable and writable, taps will not start",
+                tap::TUN_DEVICE
+            ),
+            format!("allow your user to read and write {}", tap::TUN_DEVICE),
+        )
+    } else {
+        Outcome::Ok(tap::TUN_DEVICE.to_owned())
+    };
+    Check { name, outcome }
+}
+
+/// The interfaces of the taps must be usable without privileges
+fn check_tap(exe: &vde::Executables, t: &vde::Tap) -> Check {
+    let name = format!("tap {}", t.get_name());
+    let interface = t.get_interface();
+    let access = tap::check(interface);
+    let outcome = match access.problem(interface) {
+        None => Outcome::Ok(format!("{interface} is usable")),
+        // Created at start, after asking
+        Some(problem) if access == tap::Access::Missing && t.get_create() => Outcome::Ok(format!(
+            "{problem}, it will be created after asking for consent"
+        )),
+        Some(problem) => Outcome::Error(problem, tap::fix(exe, t, &access)),
+    };
+    Check { name, outcome }
+}
+
+fn check_terminal(opts: &Options) -> Check {
+    let name = format!("terminal {}", opts.terminal);
+    let outcome = match find_executable(&opts.terminal) {
+        Some(p) => Outcome::Ok(format!("{}", p.display())),
+        None => Outcome::Warning(
+            "not found, namespaces and attach will not open a new terminal".to_owned(),
+            "set the terminal with --terminal or in the configuration file, \
+             see the Terminal configuration section of the README"
+                .to_owned(),
+        ),
+    };
+    Check { name, outcome }
+}
+
+/// Check all the prerequisites and print a report. It fails if something
+/// needed by ImagiNet is missing
+pub fn run(opts: &Options) -> Result<()> {
+    let mut checks = Vec::new();
+
+    // Check the executables of the current topology if there is one
+    let topology = get_topology(opts).ok();
+    let exe = match &topology {
+        Some(t) => opts.executables(t),
+        None => opts.executables.clone(),
+    };
+    let taps = topology
+        .as_ref()
+        .map(|t| t.get_taps().as_slice())
+        .unwrap_or(&[]);
+
+    let mut executables = required_executables(&exe);
+    // The privileged helper is only needed to create interfaces
+    if taps.iter().any(|t| t.get_create()) {
+        if tap::needs_helper() {
+            executables.push((exe.get_sudo(), vec!["create tap interfaces".to_owned()]));
+        }
+        executables.push((exe.get_ip(), vec!["create tap interfaces".to_owned()]));
+    }
+    for (cmd, usages) in &executables {
+        let outcome = match find_executable(cmd) {
+            Some(p) => Outcome::Ok(format!("{}", p.display())),
+            None => Outcome::Error(
+                format!("not found, needed to {}", usages.join(", ")),
+                install_hint(cmd),
+            ),
+        };
+        checks.push(Check {
+            name: cmd.clone(),
+            outcome,
+        });
+    }
+
+    if let Some(p) = find_executable(&exe.get_vde_plug()) {
+        checks.push(check_vde_plug(&p));
+    }
+    if let Some(p) = find_executable(&exe.get_nsenter()) {
+        checks.push(check_nsenter(&p));
+    }
+    checks.push(check_slirp_plugin());
+    checks.push(check_netnode_plugin());
+    checks.push(check_tun());
+    for t in taps {
+        checks.push(check_tap(&exe, t));
+    }
+    checks.push(check_terminal(opts));
+
+    println!("{}", "Prerequisites".bold());
+    for c in &checks {
+        c.print();
+    }
+
+    let errors = checks
+        .iter()
+        .filter(|c| matches!(c.outcome, Outcome::Error(..)))
+        .count();
+    if errors > 0 {
+        bail!("{errors} problem(s) found");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nsenter_version() {
+        assert_eq!(
+            parse_nsenter_version("nsenter from util-linux 2.40.1\n"),
+            Some((2, 40))
+        );
+        assert_eq!(
+            parse_nsenter_version("nsenter from util-linux 2.39-rc1"),
+            Some((2, 39))
+        );
+        assert_eq!(parse_nsenter_version(""), None);
+    }
+
+    #[test]
+    fn long_output_does_not_block() {
+        let output = command_output(
+            Path::new("/bin/sh"),
+            &["-c", "head -c 200000 /dev/zero | tr '\\0' a"],
+        )
+        .unwrap();
+        assert_eq!(output.len(), 200000);
+    }
+
+    #[test]
+    fn executables_come_from_devices() {
+        let executables = required_executables(&vde::Executables::default());
+        for cmd in [
+            "vde_switch",
+            "vdens",
+            "nsenter",
+            "dpipe",
+            "wirefilter",
+            "vde_plug",
+        ] {
+            assert!(executables.iter().any(|(c, _)| c == cmd), "{cmd} missing");
+        }
+        let vde_plug = executables.iter().filter(|(c, _)| c == "vde_plug").count();
+        assert_eq!(vde_plug, 1);
+    }
+}
@@ -6,17 +6,264 @@ use std::{fs, process, thread};
 
 use crate::vde;
 
+mod liveness;
+
 const ERR_DEAD_DEVICE: &str = "Device not active";
+const TOPOLOGY_FILE_NAME: &str = "topology";
+const TOPOLOGIES_DIR_NAME: &str = "topologies";
+const CURRENT_FILE_NAME: &str = "current";
+const HISTORY_DIR_NAME: &str = "history";
+/// Directory in the data directory with the recordings of the cables, kept
+/// apart from the topologies so that deleting one does not lose them
+const CAPTURES_DIR_NAME: &str = "captures";
+pub const DEFAULT_STOP_TIMEOUT: u64 = 5;
+/// Number of previous topologies kept in the history directory
+const HISTORY_SIZE: usize = 20;
+pub const DEFAULT_TOPOLOGY_NAME: &str = "default";
+/// File in the device directory with the error output of its start command
+const STDERR_FILE_NAME: &str = "stderr";
+/// Time given to a device to daemonize and create its files
+const START_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+const START_POLL: std::time::Duration = std::time::Duration::from_millis(20);
+
+const HINT_SWITCH: &str =
+    "Hint: see \"Switch not starting\" in the Troubleshooting section of the README";
+const HINT_CABLE: &str =
+    "Hint: see \"Cable not starting\" in the Troubleshooting section of the README";
+const HINT_WIREFILTER: &str = "Hint: see \"Cable not starting\" and \"Wirefilter\" in the Troubleshooting section of the README";
+const HINT_PLUG: &str =
+    "Hint: see \"Slirp and VXVDE not starting\" in the Troubleshooting section of the README";
 
 #[derive(Clone, Debug)]
 pub struct Options {
     pub terminal: String,
     pub terminal_args: Vec<String>,
-    pub working_dir: String,
+    /// Persistent directory, holding the topology files and their history
+    pub data_dir: String,
+    /// Volatile directory, holding sockets, pid files and everything needed
+    /// by the running devices
+    pub runtime_dir: String,
+    /// Name of the topology the commands are applied to. Every topology has
+    /// its own directory both in the data and the runtime directories
+    pub topology: String,
+    /// Time given to the devices to stop gently before killing them
+    pub stop_timeout: std::time::Duration,
+    /// Executables from the global configuration
+    pub executables: vde::Executables,
+}
+
+impl Options {
+    /// Executables to use for the devices of a topology. The ones set in the
+    /// topology take precedence over the global ones
+    pub fn executables(&self, t: &vde::Topology) -> vde::Executables {
+        self.executables.merge(t.get_executables())
+    }
+
+    /// Runtime directory of the selected topology. All the device
+    /// directories are created here
+    pub fn working_dir(&self) -> String {
+        std::path::PathBuf::from(&self.runtime_dir)
+            .join(&self.topology)
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    /// Persistent directory with the recordings of the selected topology.
+    /// Nothing in it is removed by imaginet
+    pub fn captures_dir(&self) -> String {
+        std::path::PathBuf::from(&self.data_dir)
+            .join(CAPTURES_DIR_NAME)
+            .join(&self.topology)
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    /// The same options, but selecting another topology
+    pub fn with_topology(&self, name: &str) -> Options {
+        Options {
+            topology: name.to_owned(),
+            ..self.clone()
+        }
+    }
+
+    fn topology_data_dir(&self) -> std::path::PathBuf {
+        std::path::PathBuf::from(&self.data_dir)
+            .join(TOPOLOGIES_DIR_NAME)
+            .join(&self.topology)
+    }
+
+    fn topology_path(&self) -> String {
+        self.topology_data_dir()
+            .join(TOPOLOGY_FILE_NAME)
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    fn history_path(&self) -> std::path::PathBuf {
+        self.topology_data_dir().join(HISTORY_DIR_NAME)
+    }
+}
+
+/// Check that a topology name can be safely used as a directory name
+pub fn check_topology_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        bail!("Invalid topology name '{name}'");
+    }
+    Ok(())
+}
+
+/// Name of the topology selected with `topologies switch`. If none was
+/// selected the default topology is used
+pub fn current_topology(data_dir: &str) -> Result<String> {
+    let path = std::path::PathBuf::from(data_dir).join(CURRENT_FILE_NAME);
+    match fs::read_to_string(&path) {
+        Ok(name) => Ok(name.trim().to_owned()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(DEFAULT_TOPOLOGY_NAME.to_owned()),
+        Err(e) => Err(e).context(format!("Reading current topology from {path:?}")),
+    }
+}
+
+/// Select the topology used when no --topology option is given
+pub fn switch_topology(opts: &Options, name: &str) -> Result<()> {
+    check_topology_name(name)?;
+    if !topology_exists(&opts.with_topology(name)) {
+        log::warn!("Topology {name} does not exist yet. Create it with the create command");
+    }
+
+    fs::create_dir_all(&opts.data_dir).context("Creating data directory")?;
+    let path = std::path::PathBuf::from(&opts.data_dir).join(CURRENT_FILE_NAME);
+    fs::write(&path, name).context(format!("Writing current topology on {path:?}"))
+}
+
+/// Names of all the topologies saved in the data directory, sorted
+pub fn list_topology_names(opts: &Options) -> Result<Vec<String>> {
+    let dir = std::path::PathBuf::from(&opts.data_dir).join(TOPOLOGIES_DIR_NAME);
+    if !fs::exists(&dir)? {
+        return Ok(vec![]);
+    }
+
+    let mut names = Vec::new();
+    for e in fs::read_dir(&dir).context("Reading topologies directory")? {
+        let e = e?;
+        let name = e.file_name().to_string_lossy().to_string();
+        if topology_exists(&opts.with_topology(&name)) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Print all the topologies, marking the current one, with the number of
+/// active devices
+pub fn topologies_list(opts: &Options) -> Result<()> {
+    let current = current_topology(&opts.data_dir)?;
+
+    for name in list_topology_names(opts)? {
+        let o = opts.with_topology(&name);
+        let marker = if name == current { "*" } else { " " };
+        match topology_active_count(&o) {
+            Ok((active, total)) => {
+                println!("{marker} {} ({active}/{total} devices active)", name.bold())
+            }
+            Err(e) => println!("{marker} {} ({})", name.bold(), format!("{e}").red()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Stop and delete a topology, together with its history
+pub fn topology_delete(opts: &Options, force: bool) -> Result<()> {
+    let res = topology_stop(opts, None);
+    if !force {
+        res?;
+    } else if let Err(e) = res {
+        log::warn!("Error stopping topology: {e}. But continuing anyway. Some devices could not be stopped");
+    }
+
+    clear_topology(opts)?;
+    let dir = opts.topology_data_dir();
+    if fs::exists(&dir)? {
+        fs::remove_dir_all(&dir).context("Removing topology data directory")?;
+    }
+
+    // Fall back to the default topology instead of a deleted one
+    if current_topology(&opts.data_dir)? == opts.topology {
+        let path = std::path::PathBuf::from(&opts.data_dir).join(CURRENT_FILE_NAME);
+        if fs::exists(&path)? {
+            fs::remove_file(&path).context("Resetting current topology")?;
+        }
+    }
+    Ok(())
+}
+
+/// Number of active devices and total number of devices in a topology
+fn topology_active_count(opts: &Options) -> Result<(usize, usize)> {
+    let t = get_topology(opts).context("Gettin topology")?;
+    let names = t.get_device_names();
+    let mut active = 0;
+    for name in &names {
+        if let Some(p) = device_pid_path(&t, name, &opts.working_dir()) {
+            if pid_path_is_alive(&p)? {
+                active += 1;
+            }
+        }
+    }
+    Ok((active, names.len()))
+}
+
+/// Print a summary of all the topologies
+pub fn topologies_status(opts: &Options) -> Result<()> {
+    let current = current_topology(&opts.data_dir)?;
+
+    println!("{}", "Topologies status".bold());
+    for name in list_topology_names(opts)? {
+        let o = opts.with_topology(&name);
+        let t = match get_topology(&o) {
+            Ok(t) => t,
+            Err(e) => {
+                println!("- {} {}", name.bold(), format!("{e}").red());
+                continue;
+            }
+        };
+        let (active, total) = match topology_active_count(&o) {
+            Ok(count) => count,
+            Err(e) => {
+                println!("- {} {}", name.bold(), format!("{e:#}").red());
+                continue;
+            }
+        };
+        let status = if active == 0 {
+            "inactive".red()
+        } else if active == total {
+            "active".green()
+        } else {
+            "partially active".yellow()
+        };
+        let current = if name == current { " (current)" } else { "" };
+
+        println!("- {}{current} {status}", name.bold());
+        println!(
+            "\t{active}/{total} devices active: {} namespaces, {} switches, {} cables, {} slirps, {} vxvdes, {} netnodes, {} taps",
+            t.get_namespaces().len(),
+            t.get_switches().len(),
+            t.get_cables().len(),
+            t.get_slirps().len(),
+            t.get_vxvdes().len(),
+            t.get_netnodes().len(),
+            t.get_taps().len()
+        );
+    }
+
+    Ok(())
 }
 
 pub fn get_topology(opts: &Options) -> Result<crate::vde::Topology> {
-    let path = format!("{}/topology", opts.working_dir);
+    let path = opts.topology_path();
     let t = match fs::read_to_string(&path) {
         Ok(t) => t,
         Err(e) => {
@@ -35,7 +282,7 @@ pub fn get_topology(opts: &Options) -> Result<crate::vde::Topology> {
 }
 
 pub fn topology_exists(opts: &Options) -> bool {
-    let path = format!("{}/topology", opts.working_dir);
+    let path = opts.topology_path();
     fs::metadata(&path).is_ok()
 }
 
@@ -44,10 +291,14 @@ pub fn topology_start(opts: Options, devices: Option<Vec<String>>, inline: bool)
     log::debug!("Starting the topology");
     log::debug!("opts: {:?} devices: {:?} inline {inline}", opts, devices);
     let t = get_topology(&opts).context("Gettin topology")?;
+    let exe = opts.executables(&t);
+
+    // The runtime directory is volatile, it may not exist yet
+    fs::create_dir_all(opts.working_dir()).context("Creating runtime directory")?;
 
     let devices = devices.unwrap_or(vec![]);
 
-    if inline && (devices.len() > 1 || devices.len() == 0) {
+    if inline && (devices.len() > 1 || devices.is_empty()) {
         bail!("If the flag inline is specified you must pass only one device");
     }
 
@@ -58,25 +309,55 @@ pub fn topology_start(opts: Options, devices: Option<Vec<String>>, inline: bool)
         }
     }
 
+    // Taps may ask to create their interface, which is better done before
+    // anything is started
+    for tap in t.get_taps() {
+        if !devices.is_empty() && !devices.contains(tap.get_name()) {
+            continue;
+        }
+        if !pid_path_is_alive(&tap.pid_path(&opts.working_dir()))? {
+            crate::tap::prepare(&exe, tap)?;
+        }
+    }
+
     log::trace!("Starting switches");
     for sw in t.get_switches() {
-        if !devices.is_empty() && !devices.contains(&sw.get_name()) {
+        if !devices.is_empty() && !devices.contains(sw.get_name()) {
             log::trace!("Skipping switch {}", sw.get_name());
             continue;
         }
 
-        if pid_path_is_alive(&sw.pid_path(&opts.working_dir))? {
+        if pid_path_is_alive(&sw.pid_path(&opts.working_dir()))? {
             log::warn!("Switch {} is already started, skipping", sw.get_name());
             continue;
         }
 
-        start_switch(&opts, sw)?;
+        start_switch(&opts, &exe, sw)?;
+    }
+
+    // Netnodes are plugged like switches, so they start before the cables
+    log::trace!("Starting netnodes");
+    for nn in t.get_netnodes() {
+        if !devices.is_empty() && !devices.contains(nn.get_name()) {
+            log::trace!("Skipping netnode {}", nn.get_name());
+            continue;
+        }
+
+        if pid_path_is_alive(&nn.pid_path(&opts.working_dir()))? {
+            log::warn!("Netnode {} is already started, skipping", nn.get_name());
+            continue;
+        }
+
+        init_dir(nn.base_path(&opts.working_dir()))
+            .context(format!("Initializing base dir for {}", nn.get_name()))?;
+
+        start_netnode(&opts, &exe, nn)?;
     }
 
     // For namespaces we need a starter script in order to save
     // some information, such as the pid
     let script = crate::vde::Namespace::get_starter_script();
-    let script_path = std::path::PathBuf::from(&opts.working_dir).join("ns_starter.sh");
+    let script_path = std::path::PathBuf::from(opts.working_dir()).join("ns_starter.sh");
     let mut file = fs::File::create(&script_path).context("Creating starter script")?;
     file.write(script)
         .context("Writing starter script into file")?;
@@ -88,17 +369,17 @@ pub fn topology_start(opts: Options, devices: Option<Vec<String>>, inline: bool)
     let script_path = script_path.to_str().unwrap().to_owned();
     log::trace!("Starting namespaces");
     for ns in t.get_namespaces() {
-        if !devices.is_empty() && !devices.contains(&ns.get_name()) {
+        if !devices.is_empty() && !devices.contains(ns.get_name()) {
             log::trace!("Skipping namespace {}", ns.get_name());
             continue;
         }
 
-        if pid_path_is_alive(&ns.pid_path(&opts.working_dir))? {
+        if pid_path_is_alive(&ns.pid_path(&opts.working_dir()))? {
             log::warn!("Namespace {} is already started, skipping", ns.get_name());
             continue;
         }
 
-        start_namespace(&opts, ns, &script_path, inline)?;
+        start_namespace(&opts, &exe, ns, &script_path, inline)?;
     }
 
     log::trace!("Starting cables");
@@ -108,7 +389,7 @@ pub fn topology_start(opts: Options, devices: Option<Vec<String>>, inline: bool)
             continue;
         }
 
-        if pid_path_is_alive(&conn.pid_path(&opts.working_dir))? {
+        if pid_path_is_alive(&conn.pid_path(&opts.working_dir()))? {
             log::warn!(
                 "Connection {} is already started, skipping",
                 conn.get_name()
@@ -116,11 +397,19 @@ pub fn topology_start(opts: Options, devices: Option<Vec<String>>, inline: bool)
             continue;
         }
 
-        init_dir(conn.base_path(&opts.working_dir))
+        if is_link_down(&opts, conn)? {
+            log::warn!(
+                "Connection {} is down, skipping. Use `imaginet link up` to start it",
+                conn.get_name()
+            );
+            continue;
+        }
+
+        init_dir(conn.base_path(&opts.working_dir()))
             .context(format!("Initializing base dir for {}", conn.name))?;
 
         if conn.get_a().get_open() || conn.get_b().get_open() {
-            let tmp = std::path::PathBuf::from(&opts.working_dir)
+            let tmp = std::path::PathBuf::from(opts.working_dir())
                 .join(vde::OPEN_DIR_NAME)
                 .to_str()
                 .context("Converting open connections dir to string")?
@@ -129,47 +418,65 @@ pub fn topology_start(opts: Options, devices: Option<Vec<String>>, inline: bool)
             init_dir(tmp).context("Creating dir for open connections")?
         }
 
-        if !conn.wirefilter && inline {
+        if !conn.has_wirefilter() && inline {
             bail!("Can't start cable inline if wirefilter is not specified")
         }
 
-        start_cable(&opts, conn)?;
+        start_cable(&opts, &exe, conn, true)?;
     }
 
     log::trace!("Starting slirps");
     for sl in t.get_slirps() {
-        if !devices.is_empty() && !devices.contains(&sl.get_name()) {
+        if !devices.is_empty() && !devices.contains(sl.get_name()) {
             log::trace!("Skipping slirp {}", sl.get_name());
             continue;
         }
 
-        if pid_path_is_alive(&sl.pid_path(&opts.working_dir))? {
+        if pid_path_is_alive(&sl.pid_path(&opts.working_dir()))? {
             log::warn!("Slirp {} is already started, skipping", sl.get_name());
             continue;
         }
 
-        init_dir(sl.base_path(&opts.working_dir))
+        init_dir(sl.base_path(&opts.working_dir()))
             .context(format!("Initializing base dir for {}", sl.get_name()))?;
 
-        start_slirp(&opts, sl)?;
+        start_slirp(&opts, &exe, sl)?;
     }
 
     log::trace!("Starting VXVDES");
     for vx in t.get_vxvdes() {
-        if !devices.is_empty() && !devices.contains(&vx.get_name()) {
+        if !devices.is_empty() && !devices.contains(vx.get_name()) {
             log::trace!("Skipping VXVDE {}", vx.get_name());
             continue;
         }
 
-        if pid_path_is_alive(&vx.pid_path(&opts.working_dir))? {
+        if pid_path_is_alive(&vx.pid_path(&opts.working_dir()))? {
             log::warn!("VXVDE {} is already started, skipping", vx.get_name());
             continue;
         }
 
-        init_dir(vx.base_path(&opts.working_dir))
+        init_dir(vx.base_path(&opts.working_dir()))
             .context(format!("Initializing base dir for {}", vx.get_name()))?;
 
-        start_vxvde(&opts, vx)?;
+        start_vxvde(&opts, &exe, vx)?;
+    }
+
+    log::trace!("Starting taps");
+    for tap in t.get_taps() {
+        if !devices.is_empty() && !devices.contains(tap.get_name()) {
+            log::trace!("Skipping tap {}", tap.get_name());
+            continue;
+        }
+
+        if pid_path_is_alive(&tap.pid_path(&opts.working_dir()))? {
+            log::warn!("Tap {} is already started, skipping", tap.get_name());
+            continue;
+        }
+
+        init_dir(tap.base_path(&opts.working_dir()))
+            .context(format!("Initializing base dir for {}", tap.get_name()))?;
+
+        start_tap(&opts, &exe, tap)?;
     }
 
     if inline {
@@ -180,54 +487,62 @@ pub fn topology_start(opts: Options, devices: Option<Vec<String>>, inline: bool)
     Ok(())
 }
 
-fn start_switch(opts: &Options, sw: &crate::vde::Switch) -> Result<()> {
+fn start_switch(opts: &Options, exe: &vde::Executables, sw: &crate::vde::Switch) -> Result<()> {
     let sw_name = sw.get_name();
     log::trace!("Starting switch {}", sw_name);
-    init_dir(sw.base_path(&opts.working_dir))
+    init_dir(sw.base_path(&opts.working_dir()))
         .context(format!("Initializing base dir for {}", sw_name))?;
 
     if sw.needs_config() {
         log::debug!("Switch needs configuration");
-        let config = sw.get_config();
+        let config = sw.rc_lines();
         log::trace!("config: {config:?}");
-        let path = sw.config_path(&opts.working_dir);
+        let path = sw.config_path(&opts.working_dir());
         log::debug!("Writing configuration to {path}");
         fs::write(&path, config.join("\n"))
             .context(format!("Writing config file for {}", sw_name))?;
     }
 
-    let cmd = sw.exec_command();
+    let cmd = sw.exec_command(exe);
     log::debug!("Command: {}", cmd);
-    let args = sw.exec_args(&opts.working_dir);
+    let args = sw.exec_args(&opts.working_dir());
     log::debug!("Args: {:?}", args);
 
-    exec(&cmd, &args).context(format!("Starting switch {}", sw_name))
+    let base = opts.working_dir();
+    let startup = Startup {
+        base_path: sw.base_path(&base),
+        pid_path: sw.pid_path(&base),
+        files: vec![sw.sock_path(&base), sw.mgmt_path(&base)],
+        hint: HINT_SWITCH,
+    };
+    exec_daemon(&cmd, &args, &startup).context(format!("Starting switch {}", sw_name))
 }
 
 fn start_namespace(
     opts: &Options,
+    exe: &vde::Executables,
     ns: &crate::vde::Namespace,
     script_path: &str,
     inline: bool,
 ) -> Result<()> {
     let ns_name = ns.get_name();
     log::trace!("Starting namespace {}", ns_name);
-    init_dir(ns.base_path(&opts.working_dir))
+    init_dir(ns.base_path(&opts.working_dir()))
         .context(format!("Initializing base dir for {}", ns_name))?;
 
     if ns.needs_config() {
         log::debug!("Switch needs configuration");
         let config = ns.get_config();
         log::trace!("config: {config:?}");
-        let path = ns.config_path(&opts.working_dir);
+        let path = ns.config_path(&opts.working_dir());
         log::debug!("Writing configuration to {path}");
         fs::write(&path, config.join("\n"))
             .context(format!("Writing config file for {}", ns_name))?;
     }
 
-    let cmd = ns.exec_command();
+    let cmd = ns.exec_command(exe);
     log::debug!("Command: {}", cmd);
-    let args = ns.exec_args(&opts.working_dir, script_path);
+    let args = ns.exec_args(&opts.working_dir(), script_path);
     log::debug!("Args: {:?}", args);
 
     if inline {
@@ -242,59 +557,154 @@ fn start_namespace(
     .context(format!("Starting namespace {}", ns_name))
 }
 
-fn start_cable(opts: &Options, cable: &crate::vde::Cable) -> Result<()> {
+/// Restart a running cable as described by `cable`, which can differ from
+/// the cable in the topology. The topology is not changed. Without `record`,
+/// a capture stage only forwards the frames, without recording to file
+pub fn restart_cable(opts: &Options, cable: &vde::Cable, record: bool) -> Result<()> {
+    let t = get_topology(opts).context("Getting topology")?;
+    let exe = opts.executables(&t);
+
+    // The impairments changed with `imaginet impair` are kept in the cable
+    // directory, which is recreated: the cable starts with them instead of
+    // the ones of the topology
+    let base = opts.working_dir();
+    let mut cable = cable.clone();
+    let changed = fs::exists(cable.impairments_path(&base))?;
+    if changed {
+        cable.set_impairments(live_impairments(opts, &cable)?);
+    }
+
+    topology_stop(opts, Some(vec![cable.get_name().to_owned()]))?;
+    init_dir(cable.base_path(&base))
+        .context(format!("Initializing base dir for {}", cable.get_name()))?;
+    start_cable(opts, &exe, &cable, record)?;
+
+    if changed {
+        fs::write(
+            cable.impairments_path(&base),
+            serde_yaml::to_string(cable.get_impairments()).context("Serializing impairments")?,
+        )
+        .context("Writing impairments file")?;
+    }
+    Ok(())
+}
+
+fn start_cable(
+    opts: &Options,
+    exe: &vde::Executables,
+    cable: &crate::vde::Cable,
+    record: bool,
+) -> Result<()> {
     log::trace!("Starting cable {}", cable.get_name());
-    let cmd = cable.exec_command();
-    let args = cable.exec_args(&opts.working_dir);
+    let cmd = cable.exec_command(exe);
+    let output = (cable.get_capture() && record).then(|| cable.capture_path(&opts.captures_dir()));
+    if output.is_some() {
+        fs::create_dir_all(opts.captures_dir()).context("Creating captures directory")?;
+    }
+    let args = cable.exec_args(&opts.working_dir(), exe, output.as_deref());
 
     if cable.needs_config() {
         log::trace!("Configuring cable {}", cable.get_name());
-        let config = cable.get_config();
-        let path = cable.config_path(&opts.working_dir);
+        let config = cable.rc_lines();
+        let path = cable.config_path(&opts.working_dir());
         fs::write(&path, config.join("\n"))
             .context(format!("Writing config file for {}", cable.name))?;
     }
 
-    exec(&cmd, &args).context(format!("Starting cable {}", cable.name))
+    let base = opts.working_dir();
+    let startup = Startup {
+        base_path: cable.base_path(&base),
+        pid_path: cable.pid_path(&base),
+        files: cable
+            .mgmt_path(&base)
+            .into_iter()
+            .chain(
+                cable
+                    .get_capture()
+                    .then(|| cable.capture_socket_path(&base)),
+            )
+            .chain((cable.get_engine() == vde::Engine::Native).then(|| cable.control_path(&base)))
+            .collect(),
+        hint: if cable.has_wirefilter() {
+            HINT_WIREFILTER
+        } else {
+            HINT_CABLE
+        },
+    };
+    exec_daemon(&cmd, &args, &startup).context(format!("Starting cable {}", cable.name))
 }
 
-fn start_slirp(opts: &Options, slirp: &crate::vde::Slirp) -> Result<()> {
+fn start_slirp(opts: &Options, exe: &vde::Executables, slirp: &crate::vde::Slirp) -> Result<()> {
     log::trace!("Starting slirp {}", slirp.get_name());
-    let cmd = slirp.exec_command();
-    let args = slirp.exec_args(&opts.working_dir);
-
-    exec(&cmd, &args).context(format!("Starting slirp {}", slirp.get_name()))
+    let cmd = slirp.exec_command(exe);
+    let args = slirp.exec_args(&opts.working_dir());
+
+    let base = opts.working_dir();
+    let startup = Startup {
+        base_path: slirp.base_path(&base),
+        pid_path: slirp.pid_path(&base),
+        files: vec![],
+        hint: HINT_PLUG,
+    };
+    exec_daemon(&cmd, &args, &startup).context(format!("Starting slirp {}", slirp.get_name()))
 }
 
-fn start_vxvde(opts: &Options, vxvde: &crate::vde::VXVDE) -> Result<()> {
+fn start_vxvde(opts: &Options, exe: &vde::Executables, vxvde: &crate::vde::VXVDE) -> Result<()> {
     log::trace!("Starting VXVDE {}", vxvde.get_name());
-    let cmd = vxvde.exec_command();
-    let args = vxvde.exec_args(&opts.working_dir);
-
-    exec(&cmd, &args).context(format!("Starting VXVDE {}", vxvde.get_name()))
+    let cmd = vxvde.exec_command(exe);
+    let args = vxvde.exec_args(&opts.working_dir());
+
+    let base = opts.working_dir();
+    let startup = Startup {
+        base_path: vxvde.base_path(&base),
+        pid_path: vxvde.pid_path(&base),
+        files: vec![],
+        hint: HINT_PLUG,
+    };
+    exec_daemon(&cmd, &args, &startup).context(format!("Starting VXVDE {}", vxvde.get_name()))
 }
 
-fn init(opts: &Options) -> Result<()> {
-    if fs::exists(&opts.working_dir)? {
-        // Should check if a pid file is present
-        fs::remove_dir_all(&opts.working_dir)?;
-    }
-    fs::create_dir(&opts.working_dir)?;
+fn start_netnode(opts: &Options, exe: &vde::Executables, nn: &crate::vde::Netnode) -> Result<()> {
+    log::trace!("Starting netnode {}", nn.get_name());
+    let cmd = nn.exec_command(exe);
+    let args = nn.exec_args(&opts.working_dir());
+
+    let base = opts.working_dir();
+    let startup = Startup {
+        base_path: nn.base_path(&base),
+        pid_path: nn.pid_path(&base),
+        files: vec![nn.sock_path(&base)],
+        hint: HINT_PLUG,
+    };
+    exec_daemon(&cmd, &args, &startup).context(format!("Starting netnode {}", nn.get_name()))
+}
 
-    Ok(())
+fn start_tap(opts: &Options, exe: &vde::Executables, tap: &crate::vde::Tap) -> Result<()> {
+    log::trace!("Starting tap {}", tap.get_name());
+    let cmd = tap.exec_command(exe);
+    let args = tap.exec_args(&opts.working_dir());
+
+    let base = opts.working_dir();
+    let startup = Startup {
+        base_path: tap.base_path(&base),
+        pid_path: tap.pid_path(&base),
+        files: vec![],
+        hint: HINT_PLUG,
+    };
+    exec_daemon(&cmd, &args, &startup).context(format!("Starting tap {}", tap.get_name()))
 }
 
 fn init_dir(path: String) -> Result<()> {
     if fs::exists(&path)? {
         fs::remove_dir_all(&path)?;
     }
-    fs::create_dir(&path)?;
+    fs::create_dir_all(&path)?;
 
     Ok(())
 }
 
 /// Execute a command with args inside a terminal
-fn exec_terminal(
+pub fn exec_terminal(
     terminal: &str,
     terminal_args: &Vec<String>,
     cmd: &str,
@@ -313,14 +723,87 @@ fn exec_terminal(
     Ok(())
 }
 
-/// Execute a command with args
-fn exec(cmd: &str, args: &Vec<String>) -> Result<()> {
+/// What a device started in background must do to be considered started
+struct Startup {
+    /// Directory of the device, where the error output of the start is saved
+    base_path: String,
+    pid_path: String,
+    /// Files created by the device once ready, besides the pid file
+    files: Vec<String>,
+    /// Troubleshooting hint shown if the device does not start
+    hint: &'static str,
+}
+
+/// Execute a command that daemonizes itself and wait for the daemon to be
+/// ready. Fails with the error output of the command if it exits with an
+/// error, or if the daemon does not create its files in time
+fn exec_daemon(cmd: &str, args: &Vec<String>, startup: &Startup) -> Result<()> {
     log::debug!("Executing: {cmd} {args:?}");
-    process::Command::new(cmd)
+
+    // The error output goes to a file and not to a pipe: if the daemon
+    // does not close it, reading a pipe would block until the daemon exits
+    let stderr_path = std::path::PathBuf::from(&startup.base_path).join(STDERR_FILE_NAME);
+    let stderr = fs::File::create(&stderr_path).context("Creating error output file")?;
+
+    let mut child = process::Command::new(cmd)
         .args(args)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(stderr)
         .spawn()
-        .context(format!("Executing commad '{cmd}'\nargs: {args:#?}"))?;
-    Ok(())
+        .context(format!(
+            "Executing commad '{cmd}'\nargs: {args:#?}\n{}",
+            startup.hint
+        ))?;
+
+    let failure = |reason: String| {
+        let output = fs::read_to_string(&stderr_path).unwrap_or_default();
+        let output = output.trim();
+        if output.is_empty() {
+            anyhow!("{reason}\n{}", startup.hint)
+        } else {
+            anyhow!("{reason}:\n{output}\n{}", startup.hint)
+        }
+    };
+
+    // The command forks the daemon and exits
+    let deadline = std::time::Instant::now() + START_TIMEOUT;
+    loop {
+        if let Some(status) = child.try_wait().context(format!("Waiting for '{cmd}'"))? {
+            if !status.success() {
+                return Err(failure(format!("'{cmd}' exited with {status}")));
+            }
+            break;
+        }
+        if std::time::Instant::now() > deadline {
+            return Err(failure(format!(
+                "'{cmd}' did not daemonize within {}s",
+                START_TIMEOUT.as_secs()
+            )));
+        }
+        thread::sleep(START_POLL);
+    }
+
+    // Then the daemon writes its pid and creates its sockets
+    let mut missing = std::iter::once(&startup.pid_path)
+        .chain(&startup.files)
+        .filter(|f| !fs::exists(f).unwrap_or(false))
+        .collect::<Vec<_>>();
+    while !missing.is_empty() {
+        if std::time::Instant::now() > deadline {
+            let list = missing
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(failure(format!("'{cmd}' did not create {list}")));
+        }
+        thread::sleep(START_POLL);
+        missing.retain(|f| !fs::exists(f).unwrap_or(false));
+    }
+
+    liveness::record(&startup.pid_path)
+        .map_err(|e| failure(format!("'{cmd}' exited right after the start ({e})")))
 }
 
 /// This is a point of no return. Replace the current process with cmd. If it fails, it returns an error
@@ -333,24 +816,181 @@ fn exec_inline(cmd: &str, args: &Vec<String>) -> Result<()> {
     ))
 }
 
+/// Write the topology file. Only the topology file is replaced: the runtime
+/// state of the devices (pid, sockets, ...) is left untouched, so this is safe
+/// to call while the topology is running
 pub fn write_topology(opts: Options, t: &crate::vde::Topology) -> Result<()> {
-    init(&opts).context("Initializing executor")?;
-
     write_raw_topology(
-        opts.clone(),
+        opts,
         t.to_string().context("Converting topology to string")?,
     )
 }
 
+/// The topology is first written on a temporary file and then renamed, so
+/// a reader never sees a partially written topology
 pub fn write_raw_topology(opts: Options, t: String) -> Result<()> {
-    init(&opts).context("Initializing executor")?;
+    fs::create_dir_all(opts.topology_data_dir()).context("Creating data directory")?;
+
+    archive_topology(&opts).context("Saving previous topology in the history")?;
 
-    let path = &format!("{}/topology", &opts.working_dir);
-    fs::write(&path, t).context(format!("Writing topology on file {path}"))?;
+    let path = opts.topology_path();
+    let tmp_path = format!("{path}.tmp");
+    fs::write(&tmp_path, t).context(format!("Writing topology on file {tmp_path}"))?;
+    fs::rename(&tmp_path, &path).context(format!("Replacing topology file {path}"))?;
 
     Ok(())
 }
 
+/// Copy the current topology file, if any, in the history directory. Only
+/// the last HISTORY_SIZE topologies are kept
+fn archive_topology(opts: &Options) -> Result<()> {
+    let path = opts.topology_path();
+    if !fs::exists(&path)? {
+        return Ok(());
+    }
+
+    let history = opts.history_path();
+    fs::create_dir_all(&history).context("Creating history directory")?;
+
+    // The name is the time of the archival, so the history can be sorted
+    // by name
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .context("Getting current time")?;
+    let name = format!("{}.{:09}", now.as_secs(), now.subsec_nanos());
+    fs::copy(&path, history.join(name)).context("Copying topology file")?;
+
+    let mut entries = fs::read_dir(&history)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    while entries.len() > HISTORY_SIZE {
+        fs::remove_file(entries.remove(0)).context("Removing old topology from history")?;
+    }
+
+    Ok(())
+}
+
+/// Remove the runtime directory of a device, if present
+pub fn remove_device_dir(opts: &Options, name: &str) -> Result<()> {
+    let path = std::path::PathBuf::from(opts.working_dir()).join(name);
+    if fs::exists(&path)? {
+        fs::remove_dir_all(&path).context(format!("Removing directory of {name}"))?;
+    }
+    Ok(())
+}
+
+/// Reconcile the current topology with `new`. Only the devices that are
+/// different between the two topologies are stopped, started or rewired.
+/// Devices that were not running are not started, unless they are new and
+/// the topology is running.
+pub fn topology_apply(opts: Options, new: crate::vde::Topology, dry_run: bool) -> Result<()> {
+    let old = if topology_exists(&opts) {
+        get_topology(&opts).context("Gettin topology")?
+    } else {
+        crate::vde::Topology::new()
+    };
+
+    let diff = old.diff(&new);
+
+    println!("{}", "Plan".bold());
+    if diff.is_empty() {
+        println!("Topology is already up to date");
+        return Ok(());
+    }
+    for e in &diff.added {
+        println!("  {} {} {}", "+".green(), e.kind, e.name.bold());
+    }
+    for e in &diff.removed {
+        println!("  {} {} {}", "-".red(), e.kind, e.name.bold());
+    }
+    for e in &diff.changed {
+        println!("  {} {} {}", "~".yellow(), e.kind, e.name.bold());
+    }
+    for e in &diff.rewired {
+        println!("  {} {} {} (rewire)", "~".yellow(), e.kind, e.name.bold());
+    }
+
+    if dry_run {
+        return Ok(());
+    }
+
+    let mut running = false;
+    let mut to_restart = Vec::new();
+    for name in old.get_device_names() {
+        let alive = match device_pid_path(&old, &name, &opts.working_dir()) {
+            Some(p) => pid_path_is_alive(&p)?,
+            None => false,
+        };
+        running |= alive;
+
+        let restart = diff
+            .changed
+            .iter()
+            .chain(diff.rewired.iter())
+            .any(|e| e.name == name);
+        if alive && restart {
+            to_restart.push(name);
+        }
+    }
+
+    let to_stop: Vec<String> = diff
+        .removed
+        .iter()
+        .chain(diff.changed.iter())
+        .chain(diff.rewired.iter())
+        .map(|e| e.name.clone())
+        .collect();
+
+    log::debug!("Stopping devices {to_stop:?}");
+    topology_stop(&opts, Some(to_stop)).context("Stopping devices")?;
+
+    for e in &diff.removed {
+        remove_device_dir(&opts, &e.name)?;
+    }
+
+    write_topology(opts.clone(), &new).context("Writing topology")?;
+
+    let mut to_start = to_restart;
+    if running {
+        to_start.extend(diff.added.iter().map(|e| e.name.clone()));
+    }
+
+    if !to_start.is_empty() {
+        log::debug!("Starting devices {to_start:?}");
+        topology_start(opts, Some(to_start), false).context("Starting devices")?;
+    }
+
+    println!("Topology applied");
+    Ok(())
+}
+
+/// Find the pid file of a device given its name
+fn device_pid_path(t: &crate::vde::Topology, name: &str, base: &str) -> Option<String> {
+    if let Some(sw) = t.get_switches().iter().find(|x| x.get_name() == name) {
+        return Some(sw.pid_path(base));
+    }
+    if let Some(ns) = t.get_namespaces().iter().find(|x| x.get_name() == name) {
+        return Some(ns.pid_path(base));
+    }
+    if let Some(c) = t.get_cables().iter().find(|x| x.get_name() == name) {
+        return Some(c.pid_path(base));
+    }
+    if let Some(sl) = t.get_slirps().iter().find(|x| x.get_name() == name) {
+        return Some(sl.pid_path(base));
+    }
+    if let Some(vx) = t.get_vxvdes().iter().find(|x| x.get_name() == name) {
+        return Some(vx.pid_path(base));
+    }
+    if let Some(nn) = t.get_netnodes().iter().find(|x| x.get_name() == name) {
+        return Some(nn.pid_path(base));
+    }
+    if let Some(tap) = t.get_taps().iter().find(|x| x.get_name() == name) {
+        return Some(tap.pid_path(base));
+    }
+    None
+}
+
 /// If None is provided as devices, all devices are printed in the status
 pub fn topology_status(
     opts: Options,
@@ -365,6 +1005,8 @@ pub fn topology_status(
     let cbs = t.get_cables();
     let slirps = t.get_slirps();
     let vxvdes = t.get_vxvdes();
+    let netnodes = t.get_netnodes();
+    let taps = t.get_taps();
 
     println!("{}", "Topology status".bold());
 
@@ -375,6 +1017,8 @@ pub fn topology_status(
         println!("\tCables:\t\t{}", cbs.len());
         println!("\tSlirps:\t\t{}", slirps.len());
         println!("\tVXVDEs:\t\t{}", vxvdes.len());
+        println!("\tNetnodes:\t{}", netnodes.len());
+        println!("\tTaps:\t\t{}", taps.len());
 
         return Ok(());
     }
@@ -387,7 +1031,7 @@ pub fn topology_status(
             }
         }
 
-        let path = n.pid_path(&opts.working_dir);
+        let path = n.pid_path(&opts.working_dir());
         let status = if pid_path_is_alive(&path)? {
             "active".green()
         } else {
@@ -409,6 +1053,7 @@ pub fn topology_status(
                     println!("\t   gateway: {}", gt.bold(),);
                 }
             }
+            print_extra_args("extra args", n.get_extra_args());
         }
     }
 
@@ -421,7 +1066,7 @@ pub fn topology_status(
             }
         }
 
-        let path = s.pid_path(&opts.working_dir);
+        let path = s.pid_path(&opts.working_dir());
         let status = if pid_path_is_alive(&path)? {
             "active".green()
         } else {
@@ -435,6 +1080,27 @@ pub fn topology_status(
                 s.get_ports().to_string().bold(),
                 s.is_hub().to_string().bold()
             );
+            if !s.get_engine().is_default() {
+                println!("\tengine: {}", s.get_engine().to_string().bold());
+            }
+            if !s.get_vlans().is_empty() {
+                println!("\tvlans:");
+            }
+            for v in s.get_vlans() {
+                let ports = |p: &Vec<u32>| {
+                    p.iter()
+                        .map(|x| x.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                };
+                println!(
+                    "\t  {}: untagged [{}] tagged [{}]",
+                    v.get_id().to_string().bold(),
+                    ports(v.get_untagged()).bold(),
+                    ports(v.get_tagged()).bold()
+                );
+            }
+            print_extra_args("extra args", s.get_extra_args());
         }
 
         if verbose > 1 {
@@ -454,8 +1120,10 @@ pub fn topology_status(
             }
         }
 
-        let path = conn.pid_path(&opts.working_dir);
-        let status = if pid_path_is_alive(&path)? {
+        let path = conn.pid_path(&opts.working_dir());
+        let status = if is_link_down(&opts, conn)? {
+            "down (administrative)".yellow()
+        } else if pid_path_is_alive(&path)? {
             "active".green()
         } else {
             "inactive".red()
@@ -468,7 +1136,7 @@ pub fn topology_status(
             let open_a = endp_a.get_open();
             let open_b = endp_b.get_open();
 
-            let base_path = std::path::PathBuf::from(&opts.working_dir);
+            let base_path = std::path::PathBuf::from(opts.working_dir());
 
             let name_a = endp_a.get_name();
             let path_a = if open_a {
@@ -496,6 +1164,30 @@ pub fn topology_status(
                 option_to_string(endp_b.get_port()).bold(),
                 conn.has_wirefilter().to_string().bold()
             );
+            for (label, endp) in [("stack_a", endp_a), ("stack_b", endp_b)] {
+                if !endp.get_plugins().is_empty() {
+                    let uri = endp.uri(&opts.working_dir());
+                    println!("\t{label}: {}", uri.bold());
+                }
+            }
+            if !conn.get_engine().is_default() {
+                println!("\tengine: {}", conn.get_engine().to_string().bold());
+            }
+            let impairments = conn.get_impairments().rc_lines();
+            if !impairments.is_empty() {
+                println!("\timpairments: {}", impairments.join(", ").bold());
+            }
+            print_extra_args("extra args", conn.get_extra_args());
+            print_extra_args("wirefilter extra args", conn.get_wirefilter_extra_args());
+            if let Some(capture) = crate::capture::capture_status(&opts, conn) {
+                println!("\tcapture: {}", capture.bold());
+            } else if conn.get_capture() {
+                println!("\tcapture: {}", "enabled".bold());
+            }
+            if let Some(s) = crate::stats::collect(&opts, conn) {
+                println!("\ttraffic a2b: {}", s.traffic.a_to_b.to_string().bold());
+                println!("\ttraffic b2a: {}", s.traffic.b_to_a.to_string().bold());
+            }
         }
 
         if verbose > 1 {
@@ -503,6 +1195,15 @@ pub fn topology_status(
             for l in conn.get_config() {
                 println!("\t  {}", l.bold());
             }
+            if conn.can_impair() && pid_path_is_alive(&path)? {
+                let live = live_impairments(&opts, conn)?.rc_lines();
+                let live = if live.is_empty() {
+                    "none".to_owned()
+                } else {
+                    live.join(", ")
+                };
+                println!("\tlive impairments: {}", live.bold());
+            }
         }
     }
 
@@ -514,7 +1215,7 @@ pub fn topology_status(
             }
         }
 
-        let path = sl.pid_path(&opts.working_dir);
+        let path = sl.pid_path(&opts.working_dir());
         let status = if pid_path_is_alive(&path)? {
             "active".green()
         } else {
@@ -522,6 +1223,9 @@ pub fn topology_status(
         };
 
         println!("- {} {}", sl.get_name(), status);
+        if verbose > 0 {
+            print_extra_args("extra args", sl.get_extra_args());
+        }
     }
 
     println!("\n{}:", "VXDEs".bold());
@@ -532,7 +1236,7 @@ pub fn topology_status(
             }
         }
 
-        let path = vx.pid_path(&opts.working_dir);
+        let path = vx.pid_path(&opts.working_dir());
         let status = if pid_path_is_alive(&path)? {
             "active".green()
         } else {
@@ -547,114 +1251,236 @@ pub fn topology_status(
                 option_to_string(vx.get_port()).bold()
             );
         }
+        if verbose > 0 {
+            print_extra_args("extra args", vx.get_extra_args());
+        }
+    }
+
+    println!("\n{}:", "Netnodes".bold());
+    for nn in netnodes {
+        if let Some(devices) = &devices {
+            if !devices.contains(&nn.get_name().to_owned()) {
+                continue;
+            }
+        }
+
+        let path = nn.pid_path(&opts.working_dir());
+        let status = if pid_path_is_alive(&path)? {
+            "active".green()
+        } else {
+            "inactive".red()
+        };
+
+        println!("- {} {}", nn.get_name(), status);
+        if verbose > 0 {
+            println!("\tkind: {}", nn.get_kind().to_string().bold());
+            print_extra_args("extra args", nn.get_extra_args());
+        }
+    }
+
+    println!("\n{}:", "Taps".bold());
+    for tap in taps {
+        if let Some(devices) = &devices {
+            if !devices.contains(&tap.get_name().to_owned()) {
+                continue;
+            }
+        }
+
+        let path = tap.pid_path(&opts.working_dir());
+        let status = if pid_path_is_alive(&path)? {
+            "active".green()
+        } else {
+            "inactive".red()
+        };
+
+        println!("- {} {}", tap.get_name(), status);
+        if verbose > 0 {
+            let interface = tap.get_interface();
+            println!("\tinterface: {}", interface.bold());
+            println!("\tcreate: {}", tap.get_create().to_string().bold());
+            match crate::tap::check(interface).problem(interface) {
+                Some(problem) => println!("\thost: {}", problem.yellow()),
+                None => println!("\thost: {}", "ready".green()),
+            }
+            print_extra_args("extra args", tap.get_extra_args());
+        }
     }
 
     Ok(())
 }
 
-fn pid_path_is_alive(path: &str) -> Result<bool> {
-    if !fs::exists(&path)? {
-        return Ok(false);
+/// Print the extra arguments of a device in the status, if there are any
+fn print_extra_args(label: &str, args: &[String]) {
+    if !args.is_empty() {
+        println!("\t{label}: {}", args.join(" ").bold());
     }
-    let pid = fs::read_to_string(path)?;
-    let pid = pid.trim();
+}
 
-    return Ok(pid_is_alive(pid));
+pub fn pid_path_is_alive(path: &str) -> Result<bool> {
+    liveness::is_alive(path).context(format!("Checking if {path} is alive"))
 }
 
-fn pid_is_alive(pid: &str) -> bool {
-    // To check if a pid is alive we could use the kill syscall.
-    // Or we could use the ps command
-    process::Command::new("ps")
-        .arg("-p")
-        .arg(pid)
-        .output()
-        .unwrap()
-        .status
-        .success()
+/// A running device that has to be stopped
+struct StopTarget {
+    name: String,
+    pid_path: String,
+    pid: i32,
+    /// Command used to gently shut down the device through its management
+    /// socket. If None, SIGTERM is sent
+    shutdown: Option<(String, Vec<String>)>,
 }
 
-/// If None is provided as devices, all devices are stopped
+/// If None is provided as devices, all devices are stopped.
+/// Switches and wirefilter cables are shut down through their management
+/// socket, the other devices receive a SIGTERM. Devices still alive after
+/// the stop timeout are killed with SIGKILL. Fails if some device survives
 pub fn topology_stop(opts: &Options, devices: Option<Vec<String>>) -> Result<()> {
-    let t = get_topology(&opts).context("Gettin topology")?;
+    let t = get_topology(opts).context("Gettin topology")?;
+    let exe = opts.executables(&t);
+    let base = opts.working_dir();
 
-    for sw in t.get_switches() {
-        if let Some(devices) = &devices {
-            if !devices.contains(&sw.get_name().to_owned()) {
-                continue;
-            }
-        }
+    let selected = |name: &String| match &devices {
+        Some(devices) => devices.contains(name),
+        None => true,
+    };
 
-        let path = sw.pid_path(&opts.working_dir);
-        if pid_path_is_alive(&path)? {
-            let pid = fs::read_to_string(&path)?.trim().to_owned();
-            // We could send a shutdown signal to the switch :)
-            process::Command::new("kill").arg(pid).spawn()?;
+    let mut targets = Vec::new();
+    for name in t.get_device_names() {
+        if !selected(&name) {
+            continue;
         }
-    }
 
-    for ns in t.get_namespaces() {
-        if let Some(devices) = &devices {
-            if !devices.contains(&ns.get_name().to_owned()) {
-                continue;
-            }
+        let pid_path = match device_pid_path(&t, &name, &base) {
+            Some(p) => p,
+            None => continue,
+        };
+        if !pid_path_is_alive(&pid_path)? {
+            continue;
         }
+        let pid = fs::read_to_string(&pid_path)?
+            .trim()
+            .parse()
+            .context(format!("Internal error: can't parse pid for device {name}"))?;
+
+        let mut shutdown_cmd = vec!["shutdown".to_owned()];
+        let shutdown = if let Some(sw) = t.get_switches().iter().find(|x| x.get_name() == &name) {
+            Some((
+                sw.exec_command_command(&exe),
+                sw.exec_command_args(&base, &mut shutdown_cmd),
+            ))
+        } else if let Some(c) = t
+            .get_cables()
+            .iter()
+            .find(|x| x.get_name() == &name && x.has_wirefilter())
+        {
+            Some((
+                c.exec_command_command(&exe)?,
+                c.exec_command_args(&base, &mut shutdown_cmd)?,
+            ))
+        } else {
+            None
+        };
 
-        let path = ns.pid_path(&opts.working_dir);
-        if pid_path_is_alive(&path)? {
-            let pid = fs::read_to_string(&path)?.trim().to_owned();
-            process::Command::new("kill").arg(pid).spawn()?;
-        }
+        targets.push(StopTarget {
+            name,
+            pid_path,
+            pid,
+            shutdown,
+        });
     }
 
-    for conn in t.get_cables() {
-        if let Some(devices) = &devices {
-            if !devices.contains(&conn.name) {
-                continue;
+    for target in &targets {
+        log::debug!("Stopping {}", target.name);
+        if let Some((cmd, args)) = &target.shutdown {
+            match process::Command::new(cmd).args(args).output() {
+                Ok(out) if out.status.success() => continue,
+                Ok(out) => log::info!(
+                    "Shutdown of {} through management socket failed: {}. Sending SIGTERM",
+                    target.name,
+                    String::from_utf8_lossy(&out.stderr).trim()
+                ),
+                Err(e) => log::info!(
+                    "Could not execute {cmd} to shut down {}: {e}. Sending SIGTERM",
+                    target.name
+                ),
             }
         }
+        send_signal(target.pid, libc::SIGTERM);
+    }
+
+    let refs: Vec<&StopTarget> = targets.iter().collect();
+    let mut alive = wait_targets(&refs, opts.stop_timeout)?;
 
-        let path = conn.pid_path(&opts.working_dir);
-        if pid_path_is_alive(&path)? {
-            let pid = fs::read_to_string(&path)?.trim().to_owned();
-            process::Command::new("kill").arg(pid).spawn()?;
+    let mut killed = Vec::new();
+    if !alive.is_empty() {
+        for target in &alive {
+            log::info!(
+                "{} still alive after {:?}, sending SIGKILL",
+                target.name,
+                opts.stop_timeout
+            );
+            send_signal(target.pid, libc::SIGKILL);
+            killed.push(target.name.clone());
         }
+        alive = wait_targets(&alive, std::time::Duration::from_secs(1))?;
     }
 
-    for sl in t.get_slirps() {
-        if let Some(devices) = &devices {
-            if !devices.contains(&sl.get_name().to_owned()) {
-                continue;
-            }
+    for target in &targets {
+        if alive.iter().any(|x| x.name == target.name) {
+            println!("{} {}", target.name, "still alive".red());
+        } else if killed.contains(&target.name) {
+            println!("{} {}", target.name, "killed".yellow());
+        } else {
+            println!("{} {}", target.name, "stopped".green());
         }
+    }
 
-        let path = sl.pid_path(&opts.working_dir);
-        if pid_path_is_alive(&path)? {
-            let pid = fs::read_to_string(&path)?.trim().to_owned();
-            process::Command::new("kill").arg(pid).spawn()?;
-        }
+    if !alive.is_empty() {
+        let names: Vec<&str> = alive.iter().map(|x| x.name.as_str()).collect();
+        bail!("Some devices could not be stopped: {}", names.join(", "));
     }
 
-    for vx in t.get_vxvdes() {
-        if let Some(devices) = &devices {
-            if !devices.contains(&vx.get_name().to_owned()) {
-                continue;
+    Ok(())
+}
+
+/// Wait until all the targets are dead or the timeout expires. Returns the
+/// targets still alive
+fn wait_targets<'a>(
+    targets: &[&'a StopTarget],
+    timeout: std::time::Duration,
+) -> Result<Vec<&'a StopTarget>> {
+    let start = std::time::Instant::now();
+    let mut alive: Vec<&StopTarget> = targets.to_vec();
+    loop {
+        let mut still = Vec::new();
+        for t in alive {
+            if pid_path_is_alive(&t.pid_path)? {
+                still.push(t);
             }
         }
+        alive = still;
 
-        let path = vx.pid_path(&opts.working_dir);
-        if pid_path_is_alive(&path)? {
-            let pid = fs::read_to_string(&path)?.trim().to_owned();
-            process::Command::new("kill").arg(pid).spawn()?;
+        if alive.is_empty() || start.elapsed() >= timeout {
+            return Ok(alive);
         }
+        thread::sleep(std::time::Duration::from_millis(100));
     }
+}
 
-    Ok(())
+fn send_signal(pid: i32, signal: i32) {
+    // SAFETY: kill has no memory safety requirements
+    if unsafe { libc::kill(pid, signal) } != 0 {
+        log::debug!(
+            "Sending signal {signal} to {pid}: {}",
+            std::io::Error::last_os_error()
+        );
+    }
 }
 
 pub fn topology_attach(opts: Options, device: String, inline: bool) -> Result<()> {
     log::trace!("Attaching to device {}", device);
     let t = get_topology(&opts).context("Gettin topology")?;
+    let exe = opts.executables(&t);
 
     for sw in t.get_switches() {
         let sw_name = sw.get_name();
@@ -664,7 +1490,7 @@ pub fn topology_attach(opts: Options, device: String, inline: bool) -> Result<()
 
         log::trace!("Attaching to switch {}", sw_name);
 
-        let path = sw.pid_path(&opts.working_dir);
+        let path = sw.pid_path(&opts.working_dir());
         if !pid_path_is_alive(&path)? {
             return Err(anyhow!(ERR_DEAD_DEVICE));
         }
@@ -673,8 +1499,8 @@ pub fn topology_attach(opts: Options, device: String, inline: bool) -> Result<()
             "Internal error: can't parse pid for switch: {}",
             sw_name
         ))?;
-        let cmd = sw.attach_command();
-        let args = sw.attach_args(&opts.working_dir, pid);
+        let cmd = sw.attach_command(&exe);
+        let args = sw.attach_args(&opts.working_dir(), pid);
 
         if inline {
             exec_inline(&cmd, &args).context("Executing attach command")?;
@@ -692,7 +1518,7 @@ pub fn topology_attach(opts: Options, device: String, inline: bool) -> Result<()
 
         log::trace!("Attaching to namespace {}", ns.get_name());
 
-        let path = ns.pid_path(&opts.working_dir);
+        let path = ns.pid_path(&opts.working_dir());
         if !pid_path_is_alive(&path)? {
             return Err(anyhow!(ERR_DEAD_DEVICE));
         }
@@ -701,8 +1527,8 @@ pub fn topology_attach(opts: Options, device: String, inline: bool) -> Result<()
             "Internal error: can't parse pid for namespace: {}",
             ns.get_name()
         ))?;
-        let cmd = ns.attach_command();
-        let args = ns.attach_args(&opts.working_dir, pid);
+        let cmd = ns.attach_command(&exe);
+        let args = ns.attach_args(&opts.working_dir(), pid);
 
         if inline {
             exec_inline(&cmd, &args).context("Executing attach command")?;
@@ -720,13 +1546,13 @@ pub fn topology_attach(opts: Options, device: String, inline: bool) -> Result<()
 
         log::trace!("Attaching to cable {}", conn.name);
 
-        let path = conn.pid_path(&opts.working_dir);
+        let path = conn.pid_path(&opts.working_dir());
         if !pid_path_is_alive(&path)? {
             return Err(anyhow!(ERR_DEAD_DEVICE));
         }
 
-        let cmd = conn.attach_command()?;
-        let args = conn.attach_args(&opts.working_dir)?;
+        let cmd = conn.attach_command(&exe)?;
+        let args = conn.attach_args(&opts.working_dir())?;
 
         if inline {
             exec_inline(&cmd, &args).context("Executing attach command")?;
@@ -745,6 +1571,10 @@ pub fn topology_attach(opts: Options, device: String, inline: bool) -> Result<()
         return Err(anyhow!("Slirps do not support attach commands"));
     }
 
+    if t.get_taps().iter().any(|x| x.get_name() == &device) {
+        return Err(anyhow!("Taps do not support attach commands"));
+    }
+
     Err(anyhow!("Device not found"))
 }
 
@@ -752,6 +1582,7 @@ pub fn topology_attach(opts: Options, device: String, inline: bool) -> Result<()
 /// namespace is provided, it uses nsenter
 pub fn topology_exec(opts: Options, device: String, command: Vec<String>) -> Result<()> {
     let t = get_topology(&opts).context("Gettin topology")?;
+    let exe = opts.executables(&t);
 
     let mut command = command;
 
@@ -760,13 +1591,13 @@ pub fn topology_exec(opts: Options, device: String, command: Vec<String>) -> Res
             continue;
         }
 
-        let path = sw.pid_path(&opts.working_dir);
+        let path = sw.pid_path(&opts.working_dir());
         if !pid_path_is_alive(&path)? {
             return Err(anyhow!(ERR_DEAD_DEVICE));
         }
 
-        let cmd = sw.exec_command_command();
-        let args = sw.exec_command_args(&opts.working_dir, command.as_mut());
+        let cmd = sw.exec_command_command(&exe);
+        let args = sw.exec_command_args(&opts.working_dir(), command.as_mut());
 
         exec_inline(&cmd, &args).context("Executing command inside switch")?;
         return Ok(());
@@ -777,14 +1608,14 @@ pub fn topology_exec(opts: Options, device: String, command: Vec<String>) -> Res
             continue;
         }
 
-        let path = ns.pid_path(&opts.working_dir);
+        let path = ns.pid_path(&opts.working_dir());
         if !pid_path_is_alive(&path)? {
             return Err(anyhow!(ERR_DEAD_DEVICE));
         }
 
         let pid = fs::read_to_string(&path)?.trim().parse().unwrap();
-        let cmd = ns.exec_command_command();
-        let args = ns.exec_command_args(&opts.working_dir, pid, command.as_mut());
+        let cmd = ns.exec_command_command(&exe);
+        let args = ns.exec_command_args(&opts.working_dir(), pid, command.as_mut());
 
         exec_inline(&cmd, &args).context("Executing command inside namespace")?;
         return Ok(());
@@ -795,13 +1626,21 @@ pub fn topology_exec(opts: Options, device: String, command: Vec<String>) -> Res
             continue;
         }
 
-        let path = conn.pid_path(&opts.working_dir);
+        let path = conn.pid_path(&opts.working_dir());
         if !pid_path_is_alive(&path)? {
             return Err(anyhow!(ERR_DEAD_DEVICE));
         }
 
-        let cmd = conn.exec_command_command()?;
-        let args = conn.exec_command_args(&opts.working_dir, command.as_mut())?;
+        if conn.get_engine() == vde::Engine::Native {
+            let answer =
+                crate::native::control(&conn.control_path(&opts.working_dir()), &command.join(" "))
+                    .context("Executing command inside cable")?;
+            println!("{answer}");
+            return Ok(());
+        }
+
+        let cmd = conn.exec_command_command(&exe)?;
+        let args = conn.exec_command_args(&opts.working_dir(), command.as_mut())?;
 
         exec_inline(&cmd, &args).context("Executing command inside cable")?;
         return Ok(());
@@ -815,11 +1654,181 @@ pub fn topology_exec(opts: Options, device: String, command: Vec<String>) -> Res
         return Err(anyhow!("Slirps do not support exec commands"));
     }
 
+    if t.get_taps().iter().any(|x| x.get_name() == &device) {
+        return Err(anyhow!("Taps do not support exec commands"));
+    }
+
     Err(anyhow!("Device not found"))
 }
 
+/// Impairments currently applied by a running wirefilter cable: the ones
+/// changed at runtime, or the ones of the topology if never changed
+fn live_impairments(opts: &Options, conn: &vde::Cable) -> Result<vde::Impairments> {
+    let path = conn.impairments_path(&opts.working_dir());
+    if !fs::exists(&path)? {
+        return Ok(conn.get_impairments().clone());
+    }
+
+    let content = fs::read_to_string(&path).context("Reading impairments file")?;
+    serde_yaml::from_str(&content).context("Parsing impairments file")
+}
+
+/// Send a command to the wirefilter of a running cable, or to the relay of
+/// a native one, waiting for its result
+fn wirefilter_command(
+    opts: &Options,
+    exe: &vde::Executables,
+    conn: &vde::Cable,
+    command: &str,
+) -> Result<()> {
+    if conn.get_engine() == vde::Engine::Native {
+        crate::native::control(&conn.control_path(&opts.working_dir()), command)
+            .context(format!("Sending \"{command}\" to {}", conn.get_name()))?;
+        log::info!("{}: {command}", conn.get_name());
+        return Ok(());
+    }
+
+    let cmd = conn.exec_command_command(exe)?;
+    let mut words = command.split_whitespace().map(|w| w.to_owned()).collect();
+    let args = conn.exec_command_args(&opts.working_dir(), &mut words)?;
+
+    let output = process::Command::new(&cmd)
+        .args(&args)
+        .output()
+        .with_context(|| format!("Executing {cmd}"))?;
+    if !output.status.success() {
+        bail!(
+            "Sending \"{command}\" to {}: {}{}",
+            conn.get_name(),
+            String::from_utf8_lossy(&output.stdout).trim(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    log::info!("{}: {command}", conn.get_name());
+    Ok(())
+}
+
+/// Check if a cable has been disconnected with `link down`
+pub fn is_link_down(opts: &Options, conn: &vde::Cable) -> Result<bool> {
+    Ok(fs::exists(conn.down_path(&opts.working_dir()))?)
+}
+
+/// Disconnect or reconnect a cable, keeping it in the topology. Wirefilter
+/// and native cables keep running and drop all the packets, the other ones
+/// are stopped
+pub fn topology_link(opts: &Options, cable: String, up: bool) -> Result<()> {
+    let t = get_topology(opts).context("Getting topology")?;
+    let exe = opts.executables(&t);
+
+    let Some(conn) = t.get_cables().iter().find(|c| c.get_name() == &cable) else {
+        bail!("Cable {cable} not found");
+    };
+
+    let base = opts.working_dir();
+    let alive = pid_path_is_alive(&conn.pid_path(&base))?;
+    let down = is_link_down(opts, conn)?;
+
+    if up {
+        if !down {
+            log::warn!("Cable {cable} is not down, nothing to do");
+            return Ok(());
+        }
+        fs::remove_file(conn.down_path(&base)).context("Removing down file")?;
+
+        if alive && conn.can_impair() {
+            // Restore the loss in place before the cable went down
+            let live = live_impairments(opts, conn)?;
+            let loss = |p: &vde::LinkParams| vde::LinkParams {
+                loss: Some(p.loss.unwrap_or(0.0)),
+                ..Default::default()
+            };
+            let restore = vde::Impairments::new(loss(live.get_a_to_b()), loss(live.get_b_to_a()));
+            for c in restore.rc_lines() {
+                wirefilter_command(opts, &exe, conn, &c)?;
+            }
+        } else if !alive {
+            topology_start(opts.clone(), Some(vec![cable]), false)?;
+        }
+        return Ok(());
+    }
+
+    if down {
+        log::warn!("Cable {cable} is already down, nothing to do");
+        return Ok(());
+    }
+
+    if alive {
+        if conn.can_impair() {
+            wirefilter_command(opts, &exe, conn, "loss 100")?;
+        } else {
+            topology_stop(opts, Some(vec![cable]))?;
+        }
+    }
+
+    fs::create_dir_all(conn.base_path(&base)).context("Creating cable directory")?;
+    fs::write(conn.down_path(&base), "").context("Writing down file")?;
+    Ok(())
+}
+
+/// Change the impairments of a running wirefilter or native cable. With no
+/// parameters, the impairments of the given direction are removed
+pub fn topology_impair(
+    opts: &Options,
+    cable: String,
+    direction: vde::Direction,
+    params: Option<vde::LinkParams>,
+) -> Result<()> {
+    let t = get_topology(opts).context("Getting topology")?;
+    let exe = opts.executables(&t);
+
+    let Some(conn) = t.get_cables().iter().find(|c| c.get_name() == &cable) else {
+        bail!("Cable {cable} not found");
+    };
+    if !conn.can_impair() {
+        bail!("Cable {cable} has no wirefilter, impairments can't be applied");
+    }
+
+    let base = opts.working_dir();
+    if !pid_path_is_alive(&conn.pid_path(&base))? {
+        return Err(anyhow!(ERR_DEAD_DEVICE));
+    }
+    if is_link_down(opts, conn)? {
+        bail!("Cable {cable} is down, use `imaginet link up` first");
+    }
+
+    let current = live_impairments(opts, conn)?;
+    let (new, commands) = match params {
+        Some(p) => {
+            p.checks().context("Checking impairments")?;
+            current.apply(direction, &p)
+        }
+        None => current.clear(direction),
+    };
+
+    for c in &commands {
+        wirefilter_command(opts, &exe, conn, c)?;
+    }
+
+    fs::write(
+        conn.impairments_path(&base),
+        serde_yaml::to_string(&new).context("Serializing impairments")?,
+    )
+    .context("Writing impairments file")?;
+    Ok(())
+}
+
+/// Delete the topology file, which is kept in the history, and all the
+/// runtime files of the devices
 pub fn clear_topology(opts: &Options) -> Result<()> {
-    fs::remove_dir_all(&opts.working_dir).context("Removing working directory")
+    if topology_exists(opts) {
+        archive_topology(opts).context("Saving topology in the history")?;
+        fs::remove_file(opts.topology_path()).context("Removing topology file")?;
+    }
+
+    if fs::exists(opts.working_dir())? {
+        fs::remove_dir_all(opts.working_dir()).context("Removing runtime directory")?;
+    }
+    Ok(())
 }
 
 fn option_to_string<T: ToString>(opt: Option<T>) -> String {
@@ -828,3 +1837,166 @@ fn option_to_string<T: ToString>(opt: Option<T>) -> String {
         None => String::from(""),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_options(name: &str) -> Options {
+        let dir = std::env::temp_dir().join(format!("imaginet-test-{}-{name}", process::id()));
+        Options {
+            terminal: String::new(),
+            terminal_args: vec![],
+            data_dir: dir.join("data").to_str().unwrap().to_owned(),
+            runtime_dir: dir.join("run").to_str().unwrap().to_owned(),
+            topology: DEFAULT_TOPOLOGY_NAME.to_owned(),
+            stop_timeout: std::time::Duration::from_secs(1),
+            executables: vde::Executables::default(),
+        }
+    }
+
+    #[test]
+    fn write_topology_keeps_runtime_files() {
+        let opts = test_options("write");
+        let device_dir = std::path::PathBuf::from(opts.working_dir()).join("sw1");
+        fs::create_dir_all(&device_dir).unwrap();
+        fs::write(device_dir.join("pid"), "1").unwrap();
+
+        let mut t = vde::Topology::new();
+        t.add_switch(vde::Switch::new("sw1".to_owned())).unwrap();
+        write_topology(opts.clone(), &t).unwrap();
+
+        assert!(device_dir.join("pid").exists());
+        assert_eq!(get_topology(&opts).unwrap().get_switches().len(), 1);
+
+        fs::remove_dir_all(std::path::PathBuf::from(&opts.data_dir).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn write_topology_keeps_history() {
+        let opts = test_options("history");
+
+        let t = vde::Topology::new();
+        write_topology(opts.clone(), &t).unwrap();
+        assert!(!opts.history_path().exists());
+
+        for _ in 0..HISTORY_SIZE + 2 {
+            write_topology(opts.clone(), &t).unwrap();
+        }
+        assert_eq!(
+            fs::read_dir(opts.history_path()).unwrap().count(),
+            HISTORY_SIZE
+        );
+
+        fs::remove_dir_all(std::path::PathBuf::from(&opts.data_dir).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn start_creates_runtime_dir() {
+        let mut opts = test_options("fresh");
+        // Fail right after the directories are made, even if vde_switch is installed
+        opts.executables = serde_yaml::from_str("vde_switch: /nonexistent/vde_switch").unwrap();
+
+        let mut t = vde::Topology::new();
+        t.add_switch(vde::Switch::new("sw1".to_owned())).unwrap();
+        write_topology(opts.clone(), &t).unwrap();
+        assert!(!std::path::Path::new(&opts.runtime_dir).exists());
+
+        let err = topology_start(opts.clone(), None, false).unwrap_err();
+        assert!(!format!("{err:#}").contains("Initializing base dir"));
+        assert!(std::path::Path::new(&opts.working_dir())
+            .join("sw1")
+            .is_dir());
+
+        fs::remove_dir_all(std::path::PathBuf::from(&opts.data_dir).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn captures_survive_delete() {
+        let opts = test_options("captures");
+
+        let mut t = vde::Topology::new();
+        t.add_switch(vde::Switch::new("sw1".to_owned())).unwrap();
+        write_topology(opts.clone(), &t).unwrap();
+        fs::create_dir_all(opts.captures_dir()).unwrap();
+        let recording = std::path::PathBuf::from(opts.captures_dir()).join("conn1.pcap");
+        fs::write(&recording, "frames").unwrap();
+
+        topology_delete(&opts, false).unwrap();
+        assert!(!fs::exists(opts.topology_path()).unwrap());
+        assert!(recording.exists());
+
+        fs::remove_dir_all(std::path::PathBuf::from(&opts.data_dir).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn topologies_are_independent() {
+        let opts = test_options("multi");
+
+        let mut t = vde::Topology::new();
+        t.add_switch(vde::Switch::new("sw1".to_owned())).unwrap();
+        write_topology(opts.with_topology("lab1"), &t).unwrap();
+        write_topology(opts.with_topology("lab2"), &vde::Topology::new()).unwrap();
+
+        assert_eq!(list_topology_names(&opts).unwrap(), vec!["lab1", "lab2"]);
+        assert_eq!(
+            get_topology(&opts.with_topology("lab2"))
+                .unwrap()
+                .get_switches()
+                .len(),
+            0
+        );
+
+        assert_eq!(current_topology(&opts.data_dir).unwrap(), "default");
+        switch_topology(&opts, "lab2").unwrap();
+        assert_eq!(current_topology(&opts.data_dir).unwrap(), "lab2");
+
+        topology_delete(&opts.with_topology("lab1"), false).unwrap();
+        assert_eq!(list_topology_names(&opts).unwrap(), vec!["lab2"]);
+        assert_eq!(current_topology(&opts.data_dir).unwrap(), "lab2");
+
+        topology_delete(&opts.with_topology("lab2"), false).unwrap();
+        assert_eq!(current_topology(&opts.data_dir).unwrap(), "default");
+
+        fs::remove_dir_all(std::path::PathBuf::from(&opts.data_dir).parent().unwrap()).unwrap();
+    }
+
+    fn test_startup(name: &str) -> Startup {
+        let dir = std::env::temp_dir().join(format!("imaginet-test-{}-{name}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let pid_path = dir.join("pid").to_str().unwrap().to_owned();
+        let _ = fs::remove_file(&pid_path);
+        Startup {
+            base_path: dir.to_str().unwrap().to_owned(),
+            pid_path,
+            files: vec![],
+            hint: HINT_SWITCH,
+        }
+    }
+
+    #[test]
+    fn exec_daemon_reports_errors() {
+        let startup = test_startup("daemon-err");
+        let args = vec!["-c".to_owned(), "echo bad rcfile >&2; exit 1".to_owned()];
+        let err = exec_daemon("sh", &args, &startup).unwrap_err().to_string();
+        assert!(err.contains("bad rcfile"));
+        assert!(err.contains(HINT_SWITCH));
+
+        fs::remove_dir_all(startup.base_path).unwrap();
+    }
+
+    #[test]
+    fn exec_daemon_waits_for_pid_file() {
+        let startup = test_startup("daemon-ok");
+        let args = vec![
+            "-c".to_owned(),
+            format!("sleep 10 & echo $! > {}", startup.pid_path),
+        ];
+        exec_daemon("sh", &args, &startup).unwrap();
+        assert!(pid_path_is_alive(&startup.pid_path).unwrap());
+
+        let pid = liveness::read_pid(&startup.pid_path).unwrap().unwrap();
+        send_signal(pid, libc::SIGKILL);
+        fs::remove_dir_all(startup.base_path).unwrap();
+    }
+}
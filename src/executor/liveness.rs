@@ -0,0 +1,254 @@
+//! Liveness check of the devices.
+//!
+//! A pid file is not enough to know if a device is alive: the process could
+//! be dead and its pid reused by another process. When a device starts, the
+//! start time and the executable of its process are recorded in an identity
+//! file next to the pid file. A device is alive only if a process with that
+//! pid exists and has the same identity. Everything is read from /proc, so
+//! no process is spawned for the check.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Appended to the pid file path to get the identity file path
+const IDENT_SUFFIX: &str = ".ident";
+
+/// Processes started up to this amount of time after the pid file was
+/// written are still considered the owner of the pid file
+const MTIME_SLACK: Duration = Duration::from_secs(2);
+
+#[derive(Debug, PartialEq)]
+struct ProcessIdentity {
+    /// Start time of the process in clock ticks after boot
+    start_time: u64,
+    /// Path of the executable. It's None if it can't be read (i.e. the
+    /// process is owned by another user)
+    exe: Option<String>,
+}
+
+impl ProcessIdentity {
+    /// Read the identity of a process. None if the process does not exist
+    /// or is a zombie
+    fn of(pid: i32) -> Option<ProcessIdentity> {
+        let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+
+        // The second field is the executable name between parenthesis, that
+        // can contain spaces. All the other fields follow the last ')'
+        let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+
+        // Field 3 (state) is at index 0, so field 22 (starttime) is at 19
+        if *fields.first()? == "Z" {
+            return None;
+        }
+        let start_time = fields.get(19)?.parse().ok()?;
+
+        let exe = fs::read_link(format!("/proc/{pid}/exe"))
+            .ok()
+            .map(|p| p.to_string_lossy().to_string());
+
+        Some(ProcessIdentity { start_time, exe })
+    }
+
+    fn from_string(s: &str) -> Option<ProcessIdentity> {
+        let (start_time, exe) = s.trim_end_matches('\n').split_once(' ')?;
+        Some(ProcessIdentity {
+            start_time: start_time.parse().ok()?,
+            exe: if exe.is_empty() {
+                None
+            } else {
+                Some(exe.to_owned())
+            },
+        })
+    }
+
+    /// Two identities match if the start time is the same. The executable is
+    /// compared only if known in both
+    fn matches(&self, other: &ProcessIdentity) -> bool {
+        if self.start_time != other.start_time {
+            return false;
+        }
+        match (&self.exe, &other.exe) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        }
+    }
+
+    /// Absolute start time of the process
+    fn started_at(&self) -> Option<SystemTime> {
+        let stat = fs::read_to_string("/proc/stat").ok()?;
+        let btime: u64 = stat
+            .lines()
+            .find_map(|l| l.strip_prefix("btime "))?
+            .trim()
+            .parse()
+            .ok()?;
+
+        // SAFETY: sysconf has no memory safety requirements
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        if ticks <= 0 {
+            return None;
+        }
+
+        let after_boot = Duration::from_millis(self.start_time * 1000 / ticks as u64);
+        Some(UNIX_EPOCH + Duration::from_secs(btime) + after_boot)
+    }
+}
+
+impl std::fmt::Display for ProcessIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.start_time,
+            self.exe.as_deref().unwrap_or("")
+        )
+    }
+}
+
+fn ident_path(pid_path: &str) -> String {
+    format!("{pid_path}{IDENT_SUFFIX}")
+}
+
+/// Read the pid in a pid file. None if the file does not exist or does not
+/// contain a valid pid
+pub fn read_pid(pid_path: &str) -> Result<Option<i32>> {
+    let content = match fs::read_to_string(pid_path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(format!("Reading pid file {pid_path}")),
+    };
+
+    match content.trim().parse() {
+        Ok(pid) if pid > 0 => Ok(Some(pid)),
+        _ => {
+            log::debug!("Invalid pid file {pid_path}: {content:?}");
+            Ok(None)
+        }
+    }
+}
+
+/// Record the identity of the process in the pid file. It must be called as
+/// soon as the pid file is written by the device
+pub fn record(pid_path: &str) -> Result<()> {
+    let pid = match read_pid(pid_path)? {
+        Some(pid) => pid,
+        None => anyhow::bail!("No valid pid in {pid_path}"),
+    };
+    let ident = ProcessIdentity::of(pid).context(format!("Process {pid} is not alive"))?;
+
+    let path = ident_path(pid_path);
+    fs::write(&path, ident.to_string()).context(format!("Writing identity file {path}"))
+}
+
+/// Check if the process in the pid file is alive and is the one that wrote
+/// the pid file.
+/// If the identity was never recorded, the process is considered the right
+/// one only if it did not start after the pid file was written. In that
+/// case the identity is recorded for the next checks
+pub fn is_alive(pid_path: &str) -> Result<bool> {
+    let pid = match read_pid(pid_path)? {
+        Some(pid) => pid,
+        None => return Ok(false),
+    };
+
+    let ident = match ProcessIdentity::of(pid) {
+        Some(i) => i,
+        None => return Ok(false),
+    };
+
+    let path = ident_path(pid_path);
+    match fs::read_to_string(&path) {
+        Ok(recorded) => {
+            let alive = ProcessIdentity::from_string(&recorded)
+                .map(|r| r.matches(&ident))
+                .unwrap_or(false);
+            if !alive {
+                log::debug!("Pid {pid} in {pid_path} has been reused by another process");
+            }
+            Ok(alive)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let written = fs::metadata(pid_path)
+                .and_then(|m| m.modified())
+                .context(format!("Reading modification time of {pid_path}"))?;
+
+            if let Some(started) = ident.started_at() {
+                if started > written + MTIME_SLACK {
+                    log::debug!("Pid {pid} in {pid_path} started after the pid file was written");
+                    return Ok(false);
+                }
+            }
+
+            if let Err(e) = fs::write(&path, ident.to_string()) {
+                log::debug!("Could not record identity in {path}: {e}");
+            }
+            Ok(true)
+        }
+        Err(e) => Err(e).context(format!("Reading identity file {path}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid_file(name: &str, pid: u32) -> String {
+        let path =
+            std::env::temp_dir().join(format!("imaginet-liveness-{}-{name}", std::process::id()));
+        fs::write(&path, pid.to_string()).unwrap();
+        let path = path.to_str().unwrap().to_owned();
+        let _ = fs::remove_file(ident_path(&path));
+        path
+    }
+
+    fn cleanup(path: &str) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(ident_path(path));
+    }
+
+    #[test]
+    fn current_process_is_alive() {
+        let path = pid_file("self", std::process::id());
+        record(&path).unwrap();
+        assert!(is_alive(&path).unwrap());
+        cleanup(&path);
+    }
+
+    #[test]
+    fn reused_pid_is_not_alive() {
+        let path = pid_file("reused", std::process::id());
+        fs::write(ident_path(&path), "1 /nonexistent").unwrap();
+        assert!(!is_alive(&path).unwrap());
+        cleanup(&path);
+    }
+
+    #[test]
+    fn pid_file_older_than_process_is_not_alive() {
+        let path = pid_file("old", std::process::id());
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH)
+            .unwrap();
+        assert!(!is_alive(&path).unwrap());
+        cleanup(&path);
+    }
+
+    #[test]
+    fn missing_pid_file_is_not_alive() {
+        let path = pid_file("missing", 1);
+        cleanup(&path);
+        assert!(!is_alive(&path).unwrap());
+    }
+
+    #[test]
+    fn identity_roundtrip() {
+        let i = ProcessIdentity {
+            start_time: 42,
+            exe: Some("/usr/bin/vde switch".to_owned()),
+        };
+        assert_eq!(ProcessIdentity::from_string(&i.to_string()), Some(i));
+    }
+}
@@ -1,22 +1,39 @@
+// Device names such as VDE, PTP and VXVDE are part of the topology file format
+#![allow(clippy::upper_case_acronyms)]
+
 use anyhow::{Context, Result};
 use clap::Parser;
-use env_logger;
-use home;
-use log;
 use std::{fs, path::PathBuf, process, str::FromStr};
 use vde::VdeConnProtocols;
 
+mod capture;
+mod chaos;
 mod config;
+mod doctor;
 mod executor;
+mod native;
+mod stats;
+mod tap;
 mod vde;
 
 /// Create and manage VDE topologies
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None, arg_required_else_help=true)]
 struct Args {
-    #[arg(short, long, help = "Base directory for all imaginet files")]
+    #[arg(
+        short,
+        long,
+        help = "Base directory for runtime files of the devices (sockets, pids, ...)"
+    )]
     pub base_dir: Option<String>,
 
+    #[arg(
+        short,
+        long,
+        help = "Directory in which topologies are persistently saved"
+    )]
+    pub data_dir: Option<String>,
+
     #[arg(
         short,
         long,
@@ -27,6 +44,13 @@ struct Args {
     #[arg(short, long, help = "Path to global configuration file")]
     pub config: Option<String>,
 
+    #[arg(
+        short = 'T',
+        long,
+        help = "Name of the topology to use. Defaults to the one selected with `topologies switch`"
+    )]
+    pub topology: Option<String>,
+
     #[command(subcommand)]
     pub command: Option<Commands>,
 
@@ -39,6 +63,21 @@ enum Commands {
     #[command(subcommand, about = "Add a device to the current topology")]
     Add(AddSubcommands),
 
+    #[command(
+        about = "Apply a yaml configuration to the current topology, changing only what differs"
+    )]
+    Apply {
+        /// Path to configuration file
+        config: String,
+
+        #[arg(
+            short = 'n',
+            long,
+            help = "Only print the plan, without changing anything"
+        )]
+        dry_run: bool,
+    },
+
     #[command(about = "Attach to a device in the topology")]
     Attach {
         #[arg(short, long, help = "Attach inline: do not open a new terminal")]
@@ -64,6 +103,79 @@ enum Commands {
         summary: bool,
     },
 
+    #[command(
+        about = "Record the frames crossing cables",
+        args_conflicts_with_subcommands = true,
+        subcommand_required = false
+    )]
+    Capture(CaptureArgs),
+
+    #[command(name = "__capture", hide = true)]
+    CaptureStage {
+        #[arg(long)]
+        name: String,
+
+        #[arg(long)]
+        description: String,
+
+        #[arg(long)]
+        socket: String,
+
+        #[arg(long)]
+        output: Option<String>,
+    },
+
+    #[command(name = "__cable", hide = true)]
+    NativeCable {
+        #[arg(long)]
+        name: String,
+
+        #[arg(long)]
+        a: String,
+
+        #[arg(long)]
+        b: String,
+
+        #[arg(long)]
+        dir: String,
+
+        #[arg(long)]
+        control: String,
+
+        #[arg(long)]
+        pidfile: String,
+
+        #[arg(long)]
+        rcfile: Option<String>,
+    },
+
+    #[command(name = "__switch", hide = true)]
+    NativeSwitch {
+        #[arg(long)]
+        name: String,
+
+        #[arg(long)]
+        sock: String,
+
+        #[arg(long)]
+        mgmt: String,
+
+        #[arg(long)]
+        pidfile: String,
+
+        #[arg(long)]
+        numports: u32,
+
+        #[arg(long)]
+        hub: bool,
+
+        #[arg(long)]
+        rcfile: Option<String>,
+    },
+
+    #[command(subcommand, about = "Inject faults in the running topology")]
+    Chaos(ChaosSubcommands),
+
     #[command(about = "Stop and delete the current topology")]
     Clear {
         #[arg(
@@ -74,6 +186,9 @@ enum Commands {
         force: bool,
     },
 
+    #[command(about = "Check that all the prerequisites are installed")]
+    Doctor {},
+
     #[command(about = "Dump current raw configuration")]
     Dump {},
 
@@ -86,6 +201,54 @@ enum Commands {
         command: Vec<String>,
     },
 
+    #[command(about = "Change the impairments of a running cable")]
+    Impair {
+        /// Name of the cable
+        cable: String,
+
+        #[arg(long, value_parser = vde::parse_delay, help = "Delay, in milliseconds (100, 100ms) or seconds (1s)")]
+        delay: Option<f64>,
+
+        #[arg(long, value_parser = vde::parse_delay, requires = "delay", help = "Jitter of the delay")]
+        jitter: Option<f64>,
+
+        #[arg(long, value_parser = vde::parse_percentage, help = "Percentage of lost packets (5, 5%)")]
+        loss: Option<f64>,
+
+        #[arg(long, value_parser = vde::parse_percentage, help = "Percentage of duplicated packets")]
+        dup: Option<f64>,
+
+        #[arg(long, value_parser = vde::parse_rate, help = "Bandwidth, in bits (1Mbit) or bytes per second (125K)")]
+        bandwidth: Option<String>,
+
+        #[arg(long, value_parser = vde::parse_rate, help = "Interface speed, in bits (1Mbit) or bytes per second (125K)")]
+        speed: Option<String>,
+
+        #[arg(long, help = "Maximum size of the packets")]
+        mtu: Option<u32>,
+
+        #[arg(long, help = "Number of damaged bits per megabyte")]
+        noise: Option<u32>,
+
+        #[arg(long, help = "Size of the channel buffer, in bytes")]
+        chanbufsize: Option<u32>,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value = "both",
+            help = "Direction of the traffic to impair"
+        )]
+        direction: ImpairDirection,
+
+        #[arg(
+            long,
+            help = "Remove the impairments",
+            conflicts_with_all = ["delay", "jitter", "loss", "dup", "bandwidth", "speed", "mtu", "noise", "chanbufsize"]
+        )]
+        clear: bool,
+    },
+
     #[command(about = "Import a topology from a raw configuration file (generated with dump)")]
     Import {
         /// Path to the topology file
@@ -99,6 +262,9 @@ enum Commands {
         force: bool,
     },
 
+    #[command(subcommand, about = "Disconnect or reconnect a cable")]
+    Link(LinkSubcommands),
+
     #[command(about = "Remove a device from the topology")]
     Rm {
         /// Name of the device
@@ -120,6 +286,15 @@ pass only a single device "#
         devices: Option<Vec<String>>,
     },
 
+    #[command(about = "Traffic counters of the running cables")]
+    Stats {
+        /// Cables to show, all the running ones if not given
+        cables: Vec<String>,
+
+        #[arg(long, help = "Print the counters in json")]
+        json: bool,
+    },
+
     #[command(about = "Status of running topology")]
     Status {
         /// List of device names to get status
@@ -130,12 +305,201 @@ pass only a single device "#
 
         #[arg(short, long, help = "Print the quantity of each type of device")]
         count: bool,
+
+        #[arg(
+            short,
+            long,
+            help = "Print a summary of all the topologies",
+            conflicts_with_all = ["devices", "count"]
+        )]
+        all: bool,
     },
 
     #[command(about = "Stop devices in the current topology")]
     Stop {
         /// List of device names to stop
         devices: Option<Vec<String>>,
+
+        #[arg(
+            short,
+            long,
+            help = "Seconds to wait for devices to stop before killing them",
+            value_name = "SECONDS"
+        )]
+        timeout: Option<u64>,
+    },
+
+    #[command(subcommand, about = "Manage multiple topologies")]
+    Topologies(TopologiesSubcommands),
+}
+
+#[derive(clap::Args, Debug)]
+struct CaptureArgs {
+    #[command(subcommand)]
+    command: Option<CaptureSubcommands>,
+
+    /// Cable to capture, or switch:port for the cable plugged in that port
+    target: Option<String>,
+
+    #[arg(
+        long,
+        help = "Stream the frames to the standard output in pcapng format, i.e. into `wireshark -k -i -`",
+        requires = "target",
+        group = "mode"
+    )]
+    live: bool,
+
+    #[arg(
+        long,
+        help = "Open Wireshark on the frames in a new terminal",
+        requires = "target",
+        group = "mode"
+    )]
+    wireshark: bool,
+
+    #[arg(
+        long,
+        help = "Capture every running cable into a single pcapng file, until interrupted. Cables without a capture stage are restarted only after asking",
+        conflicts_with = "target",
+        requires = "output",
+        group = "mode"
+    )]
+    all: bool,
+
+    #[arg(
+        short,
+        long,
+        help = "File in which to record the frames of all the cables",
+        requires = "all",
+        value_name = "PATH"
+    )]
+    output: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+enum CaptureSubcommands {
+    #[command(about = "Start recording the frames of a cable, restarting it if needed")]
+    Start {
+        /// Name of the cable
+        cable: String,
+
+        #[arg(
+            short,
+            long,
+            help = "File in which to record the frames, pcapng if its extension is .pcapng. Defaults to <cable>.pcap in the captures directory of the topology, inside the data directory",
+            value_name = "PATH"
+        )]
+        output: Option<String>,
+    },
+
+    #[command(about = "Stop recording the frames of a cable")]
+    Stop {
+        /// Name of the cable
+        cable: String,
+    },
+}
+
+#[derive(Parser, Debug)]
+enum ChaosSubcommands {
+    #[command(about = "Run a scenario of timed faults")]
+    Run {
+        /// Path to the scenario file
+        file: String,
+    },
+}
+
+#[derive(Parser, Debug)]
+enum LinkSubcommands {
+    #[command(about = "Disconnect a cable, keeping it in the topology")]
+    Down {
+        /// Name of the cable
+        cable: String,
+    },
+
+    #[command(about = "Reconnect a cable disconnected with down")]
+    Up {
+        /// Name of the cable
+        cable: String,
+    },
+}
+
+/// Direction of the traffic on a cable, from endpoint A to endpoint B or
+/// the other way around
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ImpairDirection {
+    A2b,
+    B2a,
+    Both,
+}
+
+impl From<ImpairDirection> for vde::Direction {
+    fn from(d: ImpairDirection) -> Self {
+        match d {
+            ImpairDirection::A2b => vde::Direction::AToB,
+            ImpairDirection::B2a => vde::Direction::BToA,
+            ImpairDirection::Both => vde::Direction::Both,
+        }
+    }
+}
+
+/// Implementation of a device: the vde tools, or imaginet itself
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum DeviceEngine {
+    Vde,
+    Native,
+}
+
+impl From<DeviceEngine> for vde::Engine {
+    fn from(e: DeviceEngine) -> Self {
+        match e {
+            DeviceEngine::Vde => vde::Engine::Vde,
+            DeviceEngine::Native => vde::Engine::Native,
+        }
+    }
+}
+
+/// Kind of a netnode of libvdeplug_netnode
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum NetnodeKind {
+    Hub,
+    Switch,
+    Multi,
+    Bundling,
+}
+
+impl From<NetnodeKind> for vde::NetnodeKind {
+    fn from(k: NetnodeKind) -> Self {
+        match k {
+            NetnodeKind::Hub => vde::NetnodeKind::Hub,
+            NetnodeKind::Switch => vde::NetnodeKind::Switch,
+            NetnodeKind::Multi => vde::NetnodeKind::Multi,
+            NetnodeKind::Bundling => vde::NetnodeKind::Bundling,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+enum TopologiesSubcommands {
+    #[command(about = "List all the topologies. The current one is marked with *")]
+    List {},
+
+    #[command(about = "Select the topology used when --topology is not specified")]
+    Switch {
+        /// Name of the topology
+        name: String,
+    },
+
+    #[command(about = "Stop and delete a topology")]
+    Delete {
+        /// Name of the topology
+        name: String,
+
+        #[arg(
+            short,
+            long,
+            help = "Delete the topology even if some devices could not be stopped"
+        )]
+        force: bool,
     },
 }
 
@@ -150,6 +514,14 @@ enum AddSubcommands {
         /// and should have the following format: --iface <name> [ip] [gateway]
         #[clap(verbatim_doc_comment)]
         interfaces: Vec<String>,
+
+        #[arg(
+            long = "extra-arg",
+            help = "Argument passed to vdens. Can be used multiple times",
+            value_name = "ARG",
+            allow_hyphen_values = true
+        )]
+        extra_args: Vec<String>,
     },
 
     #[command(about = "Add a switch to the current topology")]
@@ -165,6 +537,23 @@ enum AddSubcommands {
 
         #[arg(short, long, help = "Load config from file", value_name = "PATH")]
         config: Option<String>,
+
+        #[arg(
+            long = "extra-arg",
+            help = "Argument appended to vde_switch. Can be used multiple times",
+            value_name = "ARG",
+            allow_hyphen_values = true
+        )]
+        extra_args: Vec<String>,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value = "vde",
+            help = "Run the switch with vde_switch, or with the native switch of imaginet",
+            conflicts_with = "extra_args"
+        )]
+        engine: DeviceEngine,
     },
 
     #[command(about = "Add a cable to the current topology")]
@@ -172,7 +561,7 @@ enum AddSubcommands {
         /// Name of the cable. Must be unique in all the topology
         name: String,
 
-        /// Name of the first endpoint
+        /// Name of the first endpoint, or a vdeplug URI such as udp://host:port
         a: String,
 
         #[arg(long, help = "Port number on endpoint A", value_name = "PORT")]
@@ -181,7 +570,14 @@ enum AddSubcommands {
         #[arg(long, help = "Open connection on endpoint A", value_name = "BOOL")]
         open_a: Option<bool>,
 
-        /// Name of the second endpoint
+        #[arg(
+            long = "plugin-a",
+            help = "vdeplug plugin nested around endpoint A, i.e. vlan://20. Can be used multiple times, the first is the outermost",
+            value_name = "URI"
+        )]
+        plugins_a: Vec<String>,
+
+        /// Name of the second endpoint, or a vdeplug URI such as udp://host:port
         b: String,
 
         #[arg(long, help = "Port number on endpoint A", value_name = "PORT")]
@@ -190,6 +586,13 @@ enum AddSubcommands {
         #[arg(long, help = "Open connection on endpoint B", value_name = "BOOL")]
         open_b: Option<bool>,
 
+        #[arg(
+            long = "plugin-b",
+            help = "vdeplug plugin nested around endpoint B, i.e. vlan://20. Can be used multiple times, the first is the outermost",
+            value_name = "URI"
+        )]
+        plugins_b: Vec<String>,
+
         #[arg(short, long, help = "Make the cable with wirefilter", group = "wr")]
         wirefilter: bool,
 
@@ -201,12 +604,52 @@ enum AddSubcommands {
             value_name = "PATH"
         )]
         config: Option<String>,
+
+        #[arg(
+            long = "extra-arg",
+            help = "Argument appended to each vde_plug of the cable. Can be used multiple times",
+            value_name = "ARG",
+            allow_hyphen_values = true
+        )]
+        extra_args: Vec<String>,
+
+        #[arg(
+            long = "wirefilter-extra-arg",
+            help = "Argument appended to wirefilter. Can be used multiple times",
+            value_name = "ARG",
+            allow_hyphen_values = true,
+            requires = "wirefilter"
+        )]
+        wirefilter_extra_args: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Record the frames crossing the cable from when it starts"
+        )]
+        capture: bool,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value = "vde",
+            help = "Run the cable with dpipe and wirefilter, or with the native relay of imaginet",
+            conflicts_with_all = ["wirefilter", "wirefilter_extra_args", "capture", "extra_args"]
+        )]
+        engine: DeviceEngine,
     },
 
     #[command(about = "Add a slirp connection to the current topology")]
     Slirp {
         /// Name of the slirp. Must be unique in all the topology
         name: String,
+
+        #[arg(
+            long = "extra-arg",
+            help = "Argument appended to vde_plug. Can be used multiple times",
+            value_name = "ARG",
+            allow_hyphen_values = true
+        )]
+        extra_args: Vec<String>,
     },
 
     #[command(about = "Add a VXVDE connection to the current topology")]
@@ -221,6 +664,54 @@ enum AddSubcommands {
         /// Port of the vxvde connection
         #[arg(short, long, help = "Port of the vxvde connection")]
         port: Option<u16>,
+
+        #[arg(
+            long = "extra-arg",
+            help = "Argument appended to vde_plug. Can be used multiple times",
+            value_name = "ARG",
+            allow_hyphen_values = true
+        )]
+        extra_args: Vec<String>,
+    },
+
+    #[command(about = "Add an unmanaged hub, switch, multi or bundling netnode")]
+    Netnode {
+        /// Name of the netnode. Must be unique in all the topology
+        name: String,
+
+        #[arg(short, long, value_enum, help = "Kind of the netnode")]
+        kind: NetnodeKind,
+
+        #[arg(
+            long = "extra-arg",
+            help = "Argument appended to vde_plug. Can be used multiple times",
+            value_name = "ARG",
+            allow_hyphen_values = true
+        )]
+        extra_args: Vec<String>,
+    },
+
+    #[command(about = "Add a tap bridging the topology to an interface of the host")]
+    Tap {
+        /// Name of the tap. Must be unique in all the topology
+        name: String,
+
+        #[arg(short, long, help = "Tap interface of the host, i.e. tap0")]
+        interface: String,
+
+        #[arg(
+            long,
+            help = "Offer to create the interface at start if it is missing. It needs root, and it is only done after asking"
+        )]
+        create: bool,
+
+        #[arg(
+            long = "extra-arg",
+            help = "Argument appended to vde_plug. Can be used multiple times",
+            value_name = "ARG",
+            allow_hyphen_values = true
+        )]
+        extra_args: Vec<String>,
     },
 }
 
@@ -235,19 +726,29 @@ struct Terminal {
 #[derive(serde::Deserialize)]
 struct Config {
     terminal: Option<Terminal>,
-    base_directory: Option<String>,
+    #[serde(alias = "base_directory")]
+    runtime_directory: Option<String>,
+    data_directory: Option<String>,
+    /// Seconds to wait for devices to stop before killing them
+    stop_timeout: Option<u64>,
+    /// Paths of the VDE tools, for the ones not installed in the PATH
+    #[serde(default)]
+    executables: vde::Executables,
 }
 
 impl Config {
     fn new() -> Self {
         Config {
             terminal: None,
-            base_directory: None,
+            runtime_directory: None,
+            data_directory: None,
+            stop_timeout: None,
+            executables: vde::Executables::default(),
         }
     }
 
     fn from_string(file: &str) -> Result<Config> {
-        let c = serde_yaml::from_str::<Self>(&file).context("Deserialize config file failed")?;
+        let c = serde_yaml::from_str::<Self>(file).context("Deserialize config file failed")?;
         Ok(c)
     }
 }
@@ -266,6 +767,50 @@ fn main() -> Result<()> {
         .format_timestamp(None)
         .init();
 
+    // The capture stage is started by dpipe inside a cable, and a native
+    // cable by start: they need neither the configuration nor a terminal
+    if let Some(Commands::CaptureStage {
+        name,
+        description,
+        socket,
+        output,
+    }) = &args.command
+    {
+        return capture::run_stage(name, description, socket, output.as_deref());
+    }
+    if let Some(Commands::NativeCable {
+        name,
+        a,
+        b,
+        dir,
+        control,
+        pidfile,
+        rcfile,
+    }) = &args.command
+    {
+        return native::run_cable(name, a, b, dir, control, pidfile, rcfile.as_deref());
+    }
+    if let Some(Commands::NativeSwitch {
+        name,
+        sock,
+        mgmt,
+        pidfile,
+        numports,
+        hub,
+        rcfile,
+    }) = &args.command
+    {
+        return native::run_switch(
+            name,
+            sock,
+            mgmt,
+            pidfile,
+            *numports,
+            *hub,
+            rcfile.as_deref(),
+        );
+    }
+
     let conf = if let Some(config) = args.config {
         parse_config_file(&config)
     } else {
@@ -279,6 +824,25 @@ fn main() -> Result<()> {
     }
     .context("Getting config")?;
 
+    // Persistent directory for the topology files. It defaults to
+    // $XDG_DATA_HOME/imaginet
+    let data_dir = if let Some(dir) = args.data_dir {
+        dir
+    } else if let Some(data_dir) = &conf.data_directory {
+        data_dir.clone()
+    } else {
+        default_data_dir()?
+    };
+
+    // The topology selected on the command line has precedence over the one
+    // selected with `topologies switch`
+    let topology = if let Some(name) = args.topology {
+        name
+    } else {
+        executor::current_topology(&data_dir).context("Getting current topology")?
+    };
+    executor::check_topology_name(&topology)?;
+
     // Options for the executor
     let opts = executor::Options {
         // Terminal to open when starting or attaching to a device. The cli argument
@@ -302,14 +866,25 @@ fn main() -> Result<()> {
             vec![]
         },
 
-        // Working directory for all the imaginet files
-        working_dir: if let Some(dir) = args.base_dir {
+        data_dir,
+
+        // Directory for all the runtime files of the devices. It defaults to
+        // $XDG_RUNTIME_DIR/imaginet, or /tmp/imnet if the variable is not set
+        runtime_dir: if let Some(dir) = args.base_dir {
             dir
-        } else if let Some(base_dir) = &conf.base_directory {
-            base_dir.clone()
+        } else if let Some(runtime_dir) = &conf.runtime_directory {
+            runtime_dir.clone()
         } else {
-            "/tmp/imnet".to_owned()
+            default_runtime_dir()
         },
+
+        topology,
+
+        stop_timeout: std::time::Duration::from_secs(
+            conf.stop_timeout.unwrap_or(executor::DEFAULT_STOP_TIMEOUT),
+        ),
+
+        executables: conf.executables,
     };
 
     match args.command {
@@ -319,6 +894,9 @@ fn main() -> Result<()> {
                 force,
                 summary,
             } => topology_create(opts, config, force, summary).context("Creating topology")?,
+            Commands::Apply { config, dry_run } => {
+                topology_apply(opts, config, dry_run).context("Applying topology")?
+            }
             Commands::Clear { force } => {
                 let res = executor::topology_stop(&opts, None);
                 if !force {
@@ -330,6 +908,7 @@ fn main() -> Result<()> {
                 }
                 executor::clear_topology(&opts)?;
             }
+            Commands::Doctor {} => doctor::run(&opts)?,
             Commands::Dump {} => {
                 let t = executor::get_topology(&opts).context("Getting topology")?;
                 print!(
@@ -339,18 +918,106 @@ fn main() -> Result<()> {
             }
             Commands::Import { config, force } => topology_import(opts, config, force)?,
             Commands::Start { devices, inline } => executor::topology_start(opts, devices, inline)?,
+            Commands::Status { all: true, .. } => executor::topologies_status(&opts)?,
             Commands::Status {
                 devices,
                 verbose,
                 count,
+                ..
             } => executor::topology_status(opts, devices, verbose, count)?,
-            Commands::Stop { devices } => executor::topology_stop(&opts, devices)?,
+            Commands::Stats { cables, json } => stats::run(&opts, cables, json)?,
+            Commands::Capture(c) => match c.command {
+                Some(CaptureSubcommands::Start { cable, output }) => {
+                    capture::start(&opts, cable, output)?
+                }
+                Some(CaptureSubcommands::Stop { cable }) => capture::stop(&opts, cable)?,
+                None => match (c.target, c.output) {
+                    (Some(target), _) if c.live => capture::live(&opts, &target)?,
+                    (Some(target), _) if c.wireshark => capture::wireshark(&opts, &target)?,
+                    (None, Some(output)) if c.all => capture::all(&opts, &output)?,
+                    _ => anyhow::bail!(
+                        "Use a subcommand, a target with --live or --wireshark, or --all. See `imaginet capture --help`"
+                    ),
+                },
+            },
+            Commands::CaptureStage { .. }
+            | Commands::NativeCable { .. }
+            | Commands::NativeSwitch { .. } => unreachable!(),
+            Commands::Chaos(c) => match c {
+                ChaosSubcommands::Run { file } => chaos::run(&opts, &file)?,
+            },
+            Commands::Link(l) => match l {
+                LinkSubcommands::Down { cable } => executor::topology_link(&opts, cable, false)?,
+                LinkSubcommands::Up { cable } => executor::topology_link(&opts, cable, true)?,
+            },
+            Commands::Topologies(t) => match t {
+                TopologiesSubcommands::List {} => executor::topologies_list(&opts)?,
+                TopologiesSubcommands::Switch { name } => {
+                    executor::switch_topology(&opts, &name)?;
+                    println!("Current topology is now {name}");
+                }
+                TopologiesSubcommands::Delete { name, force } => {
+                    executor::check_topology_name(&name)?;
+                    let o = opts.with_topology(&name);
+                    if !executor::topology_exists(&o) {
+                        anyhow::bail!("Topology {name} does not exist");
+                    }
+                    executor::topology_delete(&o, force)?;
+                    println!("Topology {name} deleted");
+                }
+            },
+            Commands::Stop { devices, timeout } => {
+                let mut opts = opts;
+                if let Some(timeout) = timeout {
+                    opts.stop_timeout = std::time::Duration::from_secs(timeout);
+                }
+                executor::topology_stop(&opts, devices)?
+            }
             Commands::Attach { device, inline } => executor::topology_attach(opts, device, inline)?,
             Commands::Exec { device, command } => executor::topology_exec(opts, device, command)?,
+            Commands::Impair {
+                cable,
+                delay,
+                jitter,
+                loss,
+                dup,
+                bandwidth,
+                speed,
+                mtu,
+                noise,
+                chanbufsize,
+                direction,
+                clear,
+            } => {
+                let params = vde::LinkParams {
+                    delay,
+                    jitter,
+                    loss,
+                    dup,
+                    bandwidth,
+                    speed,
+                    mtu,
+                    noise,
+                    chanbufsize,
+                };
+                if !clear && params.is_empty() {
+                    anyhow::bail!("Nothing to change: pass at least one impairment or --clear");
+                }
+                executor::topology_impair(
+                    &opts,
+                    cable,
+                    direction.into(),
+                    (!clear).then_some(params),
+                )?
+            }
             Commands::Add(d) => {
                 let mut t = executor::get_topology(&opts).context("Getting topology")?;
                 match d {
-                    AddSubcommands::Namespace { name, interfaces } => {
+                    AddSubcommands::Namespace {
+                        name,
+                        interfaces,
+                        extra_args,
+                    } => {
                         let real_interfaces =
                             parse_interfaces(interfaces).context("Parsing interfaces")?;
                         let mut ns = vde::Namespace::new(name);
@@ -365,6 +1032,7 @@ fn main() -> Result<()> {
                         for c in ns.config_for_interfaces() {
                             ns.add_config(c);
                         }
+                        ns.set_extra_args(extra_args);
 
                         t.add_namespace(ns)
                             .context("Adding namespace to topology")?;
@@ -374,6 +1042,8 @@ fn main() -> Result<()> {
                         ports,
                         hub,
                         config,
+                        extra_args,
+                        engine,
                     } => {
                         let mut s = vde::Switch::new(name);
 
@@ -390,6 +1060,9 @@ fn main() -> Result<()> {
                             s.set_hub(hub);
                         }
 
+                        s.set_extra_args(extra_args);
+                        s.set_engine(engine.into());
+
                         t.add_switch(s).context("Adding switch to topology")?;
                     }
                     AddSubcommands::Cable {
@@ -397,12 +1070,31 @@ fn main() -> Result<()> {
                         a,
                         port_a,
                         open_a,
+                        plugins_a,
                         b,
                         port_b,
                         open_b,
+                        plugins_b,
                         wirefilter,
                         config,
+                        extra_args,
+                        wirefilter_extra_args,
+                        capture,
+                        engine,
                     } => {
+                        let native = matches!(engine, DeviceEngine::Native);
+                        if native && (vde::is_uri(&a) || vde::is_uri(&b)) {
+                            anyhow::bail!(
+                                "Native cables can only be plugged in devices of the topology"
+                            );
+                        }
+                        if native && !(plugins_a.is_empty() && plugins_b.is_empty()) {
+                            anyhow::bail!("Plugins are not supported by native cables");
+                        }
+                        for p in plugins_a.iter().chain(&plugins_b) {
+                            vde::check_plugin(p).context("Invalid plugin")?;
+                        }
+
                         let endp_a =
                             vde::find_endpoint_path(&t, &a, port_a.as_ref(), open_a).context(
                                 format!("Finding endpoint path for {} on connection {}", &a, &name),
@@ -451,14 +1143,27 @@ fn main() -> Result<()> {
                             conf.lines().for_each(|l| conn.add_config(l.to_owned()));
                         }
 
+                        conn.set_extra_args(extra_args);
+                        conn.set_wirefilter_extra_args(wirefilter_extra_args);
+                        conn.set_capture(capture);
+                        conn.set_engine(engine.into());
+                        conn.set_plugins(plugins_a, plugins_b);
+
                         t.add_cable(conn).context("Adding cable to topology")?;
                     }
-                    AddSubcommands::Slirp { name } => {
-                        let s = vde::Slirp::new(name);
+                    AddSubcommands::Slirp { name, extra_args } => {
+                        let mut s = vde::Slirp::new(name);
+                        s.set_extra_args(extra_args);
                         t.add_slirp(s).context("Adding slirp to topology")?;
                     }
-                    AddSubcommands::VXVDE { name, addr, port } => {
+                    AddSubcommands::VXVDE {
+                        name,
+                        addr,
+                        port,
+                        extra_args,
+                    } => {
                         let mut vx = vde::VXVDE::new(name);
+                        vx.set_extra_args(extra_args);
                         if let Some(addr) = addr {
                             vx.set_addr(addr);
                         }
@@ -468,6 +1173,30 @@ fn main() -> Result<()> {
 
                         t.add_vxvde(vx).context("Adding vxvde to topology")?;
                     }
+                    AddSubcommands::Netnode {
+                        name,
+                        kind,
+                        extra_args,
+                    } => {
+                        let mut nn = vde::Netnode::new(name, kind.into());
+                        nn.set_extra_args(extra_args);
+                        t.add_netnode(nn).context("Adding netnode to topology")?;
+                    }
+                    AddSubcommands::Tap {
+                        name,
+                        interface,
+                        create,
+                        extra_args,
+                    } => {
+                        vde::Tap::check_interface(&interface)?;
+                        if t.get_taps().iter().any(|x| x.get_interface() == &interface) {
+                            anyhow::bail!("Interface {interface} is used by another tap");
+                        }
+                        let mut tap = vde::Tap::new(name, interface);
+                        tap.set_create(create);
+                        tap.set_extra_args(extra_args);
+                        t.add_tap(tap).context("Adding tap to topology")?;
+                    }
                 }
 
                 executor::write_topology(opts.clone(), &t).context("Writing topology")?;
@@ -479,6 +1208,7 @@ fn main() -> Result<()> {
                 t.remove_device(&device)
                     .context("Removing device from topology")?;
                 executor::write_topology(opts.clone(), &t).context("Writing topology")?;
+                executor::remove_device_dir(&opts, &device)?;
             }
         },
         None => {
@@ -506,22 +1236,11 @@ fn topology_create(
         }
     }
 
-    let t;
-    if let Some(config) = config {
-        log::debug!("Reading config at {config}");
-        let file =
-            fs::read_to_string(&config).context(format!("Reading config file at {config}"))?;
-
-        let mut relative_path = PathBuf::from_str(&config)
-            .context("Converting config path provided to a valid path")?;
-        relative_path.pop();
-
-        let c = config::Config::from_string(&file, relative_path).context("Parsing config")?;
-
-        t = config_to_vde_topology(c).context("Converting config to vde topology")?;
+    let t = if let Some(config) = config {
+        topology_from_config_file(&config)?
     } else {
-        t = vde::Topology::new();
-    }
+        vde::Topology::new()
+    };
 
     executor::write_topology(opts.clone(), &t).context("Writing topology")?;
 
@@ -533,11 +1252,32 @@ fn topology_create(
         println!("\tCables:\t\t{}", &t.get_cables().len());
         println!("\tSlirps:\t\t{}", &t.get_slirps().len());
         println!("\tVXVDEs:\t\t{}", &t.get_vxvdes().len());
+        println!("\tNetnodes:\t{}", &t.get_netnodes().len());
+        println!("\tTaps:\t\t{}", &t.get_taps().len());
     }
 
     Ok(())
 }
 
+fn topology_apply(opts: executor::Options, config: String, dry_run: bool) -> Result<()> {
+    let t = topology_from_config_file(&config)?;
+    executor::topology_apply(opts, t, dry_run)
+}
+
+/// Read a yaml configuration file and convert it into a vde topology
+fn topology_from_config_file(config: &str) -> Result<vde::Topology> {
+    log::debug!("Reading config at {config}");
+    let file = fs::read_to_string(config).context(format!("Reading config file at {config}"))?;
+
+    let mut relative_path =
+        PathBuf::from_str(config).context("Converting config path provided to a valid path")?;
+    relative_path.pop();
+
+    let c = config::Config::from_string(&file, relative_path).context("Parsing config")?;
+
+    config_to_vde_topology(c).context("Converting config to vde topology")
+}
+
 fn topology_import(opts: executor::Options, config: String, force: bool) -> Result<()> {
     if executor::topology_exists(&opts) {
         if !force {
@@ -556,12 +1296,16 @@ fn topology_import(opts: executor::Options, config: String, force: bool) -> Resu
 
     executor::write_raw_topology(opts, file).context("Writing topology to file")?;
 
-    return Ok(());
+    Ok(())
 }
 
 fn config_to_vde_topology(c: config::Config) -> Result<vde::Topology> {
     let mut t = vde::Topology::new();
 
+    if let Some(executables) = c.executables {
+        t.set_executables(executables);
+    }
+
     if let Some(sws) = &c.switch {
         for sw in sws {
             let mut s = vde::Switch::new(sw.name.clone());
@@ -579,6 +1323,13 @@ fn config_to_vde_topology(c: config::Config) -> Result<vde::Topology> {
                 s.set_hub(hub);
             }
 
+            s.set_extra_args(sw.extra_args.clone());
+            s.set_engine(sw.engine);
+
+            for v in &sw.vlans {
+                s.add_vlan(vde::Vlan::new(v.id, v.untagged.clone(), v.tagged.clone()));
+            }
+
             t.add_switch(s).context("Adding switch to topology")?;
         }
     }
@@ -606,6 +1357,7 @@ fn config_to_vde_topology(c: config::Config) -> Result<vde::Topology> {
             for c in &ns.commands {
                 n.add_config(c.clone());
             }
+            n.set_extra_args(ns.extra_args.clone());
             t.add_namespace(n).context("Adding namespace to topology")?;
         }
     }
@@ -613,8 +1365,9 @@ fn config_to_vde_topology(c: config::Config) -> Result<vde::Topology> {
     if let Some(sls) = &c.slirp {
         for s in sls {
             log::debug!("Parsing slirp {}", s.name);
-            let s = vde::Slirp::new(s.name.clone());
-            t.add_slirp(s).context("Adding slirp to topology")?;
+            let mut sl = vde::Slirp::new(s.name.clone());
+            sl.set_extra_args(s.extra_args.clone());
+            t.add_slirp(sl).context("Adding slirp to topology")?;
         }
     }
 
@@ -628,11 +1381,31 @@ fn config_to_vde_topology(c: config::Config) -> Result<vde::Topology> {
             if let Some(port) = v.port {
                 vx.set_port(port);
             }
+            vx.set_extra_args(v.extra_args.clone());
 
             t.add_vxvde(vx).context("Adding vxvde to topology")?;
         }
     }
 
+    if let Some(netnodes) = &c.netnode {
+        for n in netnodes {
+            log::debug!("Parsing netnode {}", n.name);
+            let mut nn = vde::Netnode::new(n.name.clone(), n.kind);
+            nn.set_extra_args(n.extra_args.clone());
+            t.add_netnode(nn).context("Adding netnode to topology")?;
+        }
+    }
+
+    if let Some(taps) = &c.tap {
+        for tp in taps {
+            log::debug!("Parsing tap {}", tp.name);
+            let mut tap = vde::Tap::new(tp.name.clone(), tp.interface.clone());
+            tap.set_create(tp.create);
+            tap.set_extra_args(tp.extra_args.clone());
+            t.add_tap(tap).context("Adding tap to topology")?;
+        }
+    }
+
     if let Some(conns) = &c.cable {
         for c in conns {
             let endp_a = vde::find_endpoint_path(
@@ -688,19 +1461,53 @@ fn config_to_vde_topology(c: config::Config) -> Result<vde::Topology> {
                 port_b,
                 endp_b_proto,
                 open_b,
-                c.wirefilter,
+                Some(c.has_wirefilter()),
             );
 
+            if let Some(imp) = &c.impairments {
+                let (a_to_b, b_to_a) = imp.directions();
+                conn.set_impairments(vde::Impairments::new(a_to_b, b_to_a));
+            }
+
             if let Some(config) = &c.config {
                 let conf = fs::read_to_string(config).context("Config file not found")?;
                 conf.lines().for_each(|l| conn.add_config(l.to_owned()));
             }
 
+            conn.set_extra_args(c.extra_args.clone());
+            conn.set_wirefilter_extra_args(c.wirefilter_extra_args.clone());
+            conn.set_capture(c.capture);
+            conn.set_engine(c.engine);
+            conn.set_plugins(c.endpoint_a.plugins.clone(), c.endpoint_b.plugins.clone());
+
             t.add_cable(conn).context("Adding cable to topology")?;
         }
     }
 
-    return Ok(t);
+    Ok(t)
+}
+
+fn default_data_dir() -> Result<String> {
+    let base = match std::env::var("XDG_DATA_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home::home_dir()
+            .context("Getting home directory")?
+            .join(".local")
+            .join("share"),
+    };
+
+    Ok(base
+        .join("imaginet")
+        .to_str()
+        .context("Converting data directory to string")?
+        .to_owned())
+}
+
+fn default_runtime_dir() -> String {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => format!("{dir}/imaginet"),
+        _ => "/tmp/imnet".to_owned(),
+    }
 }
 
 fn parse_config_file(file: &str) -> Result<Config> {
@@ -732,7 +1539,7 @@ fn parse_interfaces(interfaces: Vec<String>) -> Result<Vec<config::NSInterface>>
     }
 
     for (n, i) in tmp.iter().enumerate() {
-        if i.len() < 1 || i.len() > 3 {
+        if i.is_empty() || i.len() > 3 {
             anyhow::bail!("Interface {n} definition must have between 1 and 3 elements");
         }
     }
@@ -758,5 +1565,5 @@ fn parse_interfaces(interfaces: Vec<String>) -> Result<Vec<config::NSInterface>>
         real_interfaces.push(inter);
     }
 
-    return Ok(real_interfaces);
+    Ok(real_interfaces)
 }
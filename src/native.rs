@@ -0,0 +1,57 @@
+//! Devices implemented by imaginet itself, as an alternative to the vde
+//! tools. They speak the vde protocols directly, so they can be plugged in
+//! the same topologies as the switches and namespaces started with vde.
+
+use anyhow::{bail, Context, Result};
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+mod bridge;
+mod cable;
+mod link;
+mod plug;
+mod switch;
+
+pub use cable::run as run_cable;
+pub use switch::run as run_switch;
+
+/// Time given to a native device to answer a command
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(2);
+/// Wait after a receive error, doubled while the errors go on
+const RETRY_MIN: Duration = Duration::from_millis(10);
+const RETRY_MAX: Duration = Duration::from_secs(1);
+
+/// Send a command to the control socket of a native device, returning its
+/// answer. The device closes the connection after answering
+pub fn control(socket: &str, command: &str) -> Result<String> {
+    let mut stream = UnixStream::connect(socket).context(format!("Connecting to {socket}"))?;
+    stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONTROL_TIMEOUT))?;
+    writeln!(&stream, "{command}")?;
+
+    let mut answer = String::new();
+    stream
+        .read_to_string(&mut answer)
+        .context("Reading the answer of the device")?;
+    let answer = answer.trim().to_owned();
+    if let Some(e) = answer.strip_prefix("error ") {
+        bail!("{e}");
+    }
+    Ok(answer)
+}
+
+/// Turn the current process into a daemon: the parent exits as soon as the
+/// child is forked. Must be called before starting any thread
+fn daemonize() -> Result<()> {
+    // SAFETY: the process has a single thread, so the child is a full copy
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()).context("Forking the daemon"),
+        0 => {
+            // SAFETY: setsid has no memory safety requirements
+            unsafe { libc::setsid() };
+            Ok(())
+        }
+        _ => std::process::exit(0),
+    }
+}
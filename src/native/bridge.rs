@@ -0,0 +1,442 @@
+//! Forwarding of the native switch: MAC learning with aging, hub mode and
+//! 802.1Q vlans. Ports belong untagged to vlan 0 until they are moved, like
+//! in vde_switch, and the configuration uses the vde_switch commands.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Time after which a learned address is forgotten, if not seen again
+const DEFAULT_EXPIRE: Duration = Duration::from_secs(120);
+const ETHERTYPE_VLAN: [u8; 2] = [0x81, 0x00];
+const DEFAULT_VLAN: u16 = 0;
+const MAX_VLAN: u16 = 4095;
+
+type Mac = [u8; 6];
+
+/// Errors of the management commands, reported with their errno like in
+/// vde_switch
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    NotFound,
+    Invalid,
+    Busy,
+    Unknown,
+}
+
+impl Error {
+    pub fn errno(&self) -> i32 {
+        match self {
+            Error::NotFound => libc::ENOENT,
+            Error::Invalid => libc::EINVAL,
+            Error::Busy => libc::EADDRINUSE,
+            Error::Unknown => libc::ENOSYS,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            Error::NotFound => "No such file or directory",
+            Error::Invalid => "Invalid argument",
+            Error::Busy => "Address already in use",
+            Error::Unknown => "Function not implemented",
+        };
+        write!(f, "{message}")
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Port {
+    /// Description of the plug connected, if any
+    plug: Option<String>,
+    /// Vlan of the untagged frames, if any
+    untagged: Option<u16>,
+    tagged: BTreeSet<u16>,
+}
+
+impl Port {
+    fn is_member(&self, vlan: u16) -> bool {
+        self.untagged == Some(vlan) || self.tagged.contains(&vlan)
+    }
+}
+
+struct Entry {
+    port: u32,
+    seen: Instant,
+}
+
+pub struct Bridge {
+    hub: bool,
+    ports: Vec<Port>,
+    vlans: BTreeSet<u16>,
+    macs: HashMap<(u16, Mac), Entry>,
+    expire: Duration,
+}
+
+fn mac(bytes: &[u8]) -> Mac {
+    bytes[..6].try_into().unwrap()
+}
+
+fn is_multicast(mac: &Mac) -> bool {
+    mac[0] & 1 == 1
+}
+
+fn format_mac(mac: &Mac) -> String {
+    mac.iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Parse the arguments of a command
+fn numbers<const N: usize>(args: &str) -> Result<[u32; N], Error> {
+    let values = args
+        .split_whitespace()
+        .map(|a| a.parse().map_err(|_| Error::Invalid))
+        .collect::<Result<Vec<u32>, _>>()?;
+    values.try_into().map_err(|_| Error::Invalid)
+}
+
+impl Bridge {
+    pub fn new(numports: u32, hub: bool) -> Bridge {
+        let port = Port {
+            plug: None,
+            untagged: Some(DEFAULT_VLAN),
+            tagged: BTreeSet::new(),
+        };
+        Bridge {
+            hub,
+            ports: vec![port; numports as usize],
+            vlans: BTreeSet::from([DEFAULT_VLAN]),
+            macs: HashMap::new(),
+            expire: DEFAULT_EXPIRE,
+        }
+    }
+
+    /// Connect a plug to a port. Port 0 picks the first free port
+    pub fn connect(&mut self, port: u32, description: &str) -> Result<u32, Error> {
+        let port = if port == 0 {
+            (1..self.ports.len() as u32)
+                .find(|&p| self.ports[p as usize].plug.is_none())
+                .ok_or(Error::Busy)?
+        } else {
+            port
+        };
+        let p = self.ports.get_mut(port as usize).ok_or(Error::Invalid)?;
+        if p.plug.is_some() {
+            return Err(Error::Busy);
+        }
+        p.plug = Some(description.to_owned());
+        Ok(port)
+    }
+
+    pub fn disconnect(&mut self, port: u32) {
+        if let Some(p) = self.ports.get_mut(port as usize) {
+            p.plug = None;
+        }
+        self.macs.retain(|_, e| e.port != port);
+    }
+
+    fn port(&self, port: u32) -> Result<&Port, Error> {
+        self.ports.get(port as usize).ok_or(Error::NotFound)
+    }
+
+    fn port_mut(&mut self, port: u32) -> Result<&mut Port, Error> {
+        self.ports.get_mut(port as usize).ok_or(Error::NotFound)
+    }
+
+    fn vlan(&self, vlan: u32) -> Result<u16, Error> {
+        match u16::try_from(vlan) {
+            Ok(v) if self.vlans.contains(&v) => Ok(v),
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    /// Frames to send for a frame received on a port, with their ports
+    pub fn forward(&mut self, now: Instant, from: u32, frame: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        if frame.len() < 14 {
+            return Vec::new();
+        }
+        let connected = |p: &(usize, &Port)| p.1.plug.is_some() && p.0 as u32 != from;
+
+        if self.hub {
+            return self
+                .ports
+                .iter()
+                .enumerate()
+                .filter(connected)
+                .map(|(p, _)| (p as u32, frame.to_vec()))
+                .collect();
+        }
+
+        let Ok(source) = self.port(from) else {
+            return Vec::new();
+        };
+        let tagged = frame[12..14] == ETHERTYPE_VLAN && frame.len() >= 18;
+        let (vlan, payload) = if tagged {
+            let vlan = u16::from_be_bytes([frame[14], frame[15]]) & MAX_VLAN;
+            if !source.tagged.contains(&vlan) {
+                return Vec::new();
+            }
+            (vlan, [&frame[..12], &frame[16..]].concat())
+        } else {
+            match source.untagged {
+                Some(vlan) => (vlan, frame.to_vec()),
+                None => return Vec::new(),
+            }
+        };
+
+        let (dst, src) = (mac(&payload), mac(&payload[6..]));
+        if !is_multicast(&src) {
+            self.macs.insert(
+                (vlan, src),
+                Entry {
+                    port: from,
+                    seen: now,
+                },
+            );
+        }
+
+        let known = self
+            .macs
+            .get(&(vlan, dst))
+            .filter(|e| !is_multicast(&dst) && now.duration_since(e.seen) < self.expire)
+            .map(|e| e.port);
+        if known == Some(from) {
+            return Vec::new();
+        }
+
+        self.ports
+            .iter()
+            .enumerate()
+            .filter(connected)
+            .filter(|(p, port)| port.is_member(vlan) && known.is_none_or(|k| k == *p as u32))
+            .map(|(p, port)| {
+                let frame = if port.untagged == Some(vlan) {
+                    payload.clone()
+                } else {
+                    let tag = vlan.to_be_bytes();
+                    [&payload[..12], &ETHERTYPE_VLAN, &tag, &payload[12..]].concat()
+                };
+                (p as u32, frame)
+            })
+            .collect()
+    }
+
+    /// Execute a management command, returning its output
+    pub fn command(&mut self, now: Instant, line: &str) -> Result<String, Error> {
+        let (command, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+        match command {
+            "help" => Ok([
+                "showinfo            show the switch configuration",
+                "port/create P       create port P",
+                "port/setvlan P V    move port P untagged to vlan V",
+                "port/print          show the ports",
+                "vlan/create V       create vlan V",
+                "vlan/remove V       remove vlan V",
+                "vlan/addport V P    add port P tagged to vlan V",
+                "vlan/delport V P    remove port P from vlan V",
+                "vlan/print          show the vlans",
+                "hash/print          show the learned addresses",
+                "hash/setexpire S    forget addresses after S seconds",
+                "shutdown            stop the switch",
+            ]
+            .join("\n")),
+            "showinfo" => Ok(format!(
+                "imaginet native switch\nnumports={}\nhub={}\nexpire={}s",
+                self.ports.len(),
+                self.hub,
+                self.expire.as_secs()
+            )),
+            "port/create" => {
+                let [p] = numbers(args)?;
+                self.port(p).map(|_| String::new())
+            }
+            "port/setvlan" => {
+                let [p, v] = numbers(args)?;
+                let vlan = self.vlan(v)?;
+                let port = self.port_mut(p)?;
+                port.tagged.remove(&vlan);
+                port.untagged = Some(vlan);
+                self.macs.retain(|_, e| e.port != p);
+                Ok(String::new())
+            }
+            "port/print" => Ok(self
+                .ports
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| p.plug.is_some())
+                .map(|(i, p)| {
+                    let untagged = p.untagged.map(|v| v.to_string()).unwrap_or_default();
+                    let tagged: Vec<String> = p.tagged.iter().map(|v| v.to_string()).collect();
+                    format!(
+                        "Port {i:04} untagged_vlan={untagged} tagged_vlans={} {}",
+                        tagged.join(","),
+                        p.plug.as_deref().unwrap_or_default()
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")),
+            "vlan/create" => {
+                let [v] = numbers(args)?;
+                match u16::try_from(v) {
+                    Ok(v) if v <= MAX_VLAN && self.vlans.insert(v) => Ok(String::new()),
+                    Ok(v) if v <= MAX_VLAN => Err(Error::Busy),
+                    _ => Err(Error::Invalid),
+                }
+            }
+            "vlan/remove" => {
+                let [v] = numbers(args)?;
+                let vlan = self.vlan(v)?;
+                if vlan == DEFAULT_VLAN || self.ports.iter().any(|p| p.is_member(vlan)) {
+                    return Err(Error::Busy);
+                }
+                self.vlans.remove(&vlan);
+                Ok(String::new())
+            }
+            "vlan/addport" => {
+                let [v, p] = numbers(args)?;
+                let vlan = self.vlan(v)?;
+                let port = self.port_mut(p)?;
+                if port.untagged == Some(vlan) {
+                    return Err(Error::Busy);
+                }
+                port.tagged.insert(vlan);
+                Ok(String::new())
+            }
+            "vlan/delport" => {
+                let [v, p] = numbers(args)?;
+                let vlan = self.vlan(v)?;
+                let port = self.port_mut(p)?;
+                if port.untagged == Some(vlan) {
+                    port.untagged = None;
+                } else if !port.tagged.remove(&vlan) {
+                    return Err(Error::NotFound);
+                }
+                self.macs.retain(|(v, _), e| *v != vlan || e.port != p);
+                Ok(String::new())
+            }
+            "vlan/print" => Ok(self
+                .vlans
+                .iter()
+                .map(|&v| {
+                    let members: Vec<String> = (0..self.ports.len())
+                        .filter(|&p| self.ports[p].is_member(v))
+                        .map(|p| p.to_string())
+                        .collect();
+                    format!("VLAN {v:04} ports: {}", members.join(" "))
+                })
+                .collect::<Vec<_>>()
+                .join("\n")),
+            "hash/print" => Ok(self
+                .macs
+                .iter()
+                .filter(|(_, e)| now.duration_since(e.seen) < self.expire)
+                .map(|((vlan, mac), e)| {
+                    format!(
+                        "Hash: {} VLAN {vlan:04} port {:04} age {}s",
+                        format_mac(mac),
+                        e.port,
+                        now.duration_since(e.seen).as_secs()
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")),
+            "hash/setexpire" => {
+                let [s] = numbers(args)?;
+                self.expire = Duration::from_secs(s.into());
+                Ok(String::new())
+            }
+            _ => Err(Error::Unknown),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(dst: u8, src: u8) -> Vec<u8> {
+        let mut f = vec![0u8; 60];
+        f[5] = dst;
+        f[11] = src;
+        f[12..14].copy_from_slice(&[0x08, 0x00]);
+        f
+    }
+
+    fn ports(out: &[(u32, Vec<u8>)]) -> Vec<u32> {
+        out.iter().map(|(p, _)| *p).collect()
+    }
+
+    #[test]
+    fn learning() {
+        let now = Instant::now();
+        let mut b = Bridge::new(8, false);
+        for p in 1..=3 {
+            assert_eq!(b.connect(0, "plug").unwrap(), p);
+        }
+        assert_eq!(b.connect(2, "plug"), Err(Error::Busy));
+
+        // Unknown destination: flooded
+        assert_eq!(ports(&b.forward(now, 1, &frame(2, 1))), vec![2, 3]);
+        // Learned from the previous frame
+        assert_eq!(ports(&b.forward(now, 2, &frame(1, 2))), vec![1]);
+        assert_eq!(ports(&b.forward(now, 1, &frame(2, 1))), vec![2]);
+
+        // Aging
+        let later = now + DEFAULT_EXPIRE;
+        assert_eq!(ports(&b.forward(later, 1, &frame(2, 1))), vec![2, 3]);
+
+        b.disconnect(2);
+        assert_eq!(ports(&b.forward(later, 3, &frame(2, 3))), vec![1]);
+    }
+
+    #[test]
+    fn hub_floods() {
+        let now = Instant::now();
+        let mut b = Bridge::new(8, true);
+        for _ in 0..3 {
+            b.connect(0, "plug").unwrap();
+        }
+        b.forward(now, 2, &frame(1, 2));
+        assert_eq!(ports(&b.forward(now, 1, &frame(2, 1))), vec![2, 3]);
+    }
+
+    #[test]
+    fn vlans() {
+        let now = Instant::now();
+        let mut b = Bridge::new(8, false);
+        for line in [
+            "vlan/create 10",
+            "port/create 1",
+            "port/setvlan 1 10",
+            "vlan/addport 10 7",
+        ] {
+            b.command(now, line).unwrap();
+        }
+        assert_eq!(b.command(now, "port/setvlan 1 20"), Err(Error::NotFound));
+        assert_eq!(b.command(now, "vlan/addport 10"), Err(Error::Invalid));
+        assert_eq!(b.command(now, "port/reset 1"), Err(Error::Unknown));
+        for p in [1, 2, 7] {
+            b.connect(p, "plug").unwrap();
+        }
+
+        // Untagged on port 1, tagged on the trunk, not sent to vlan 0
+        let out = b.forward(now, 1, &frame(0xff, 1));
+        assert_eq!(ports(&out), vec![7]);
+        assert_eq!(out[0].1.len(), 64);
+        assert_eq!(out[0].1[12..16], [0x81, 0x00, 0x00, 10]);
+
+        // Tagged from the trunk, untagged on port 1
+        let out = b.forward(now, 7, &out[0].1);
+        assert_eq!(ports(&out), vec![1]);
+        assert_eq!(out[0].1, frame(0xff, 1));
+
+        // Vlan 20 is not carried by the trunk
+        let mut tagged = out[0].1.clone();
+        tagged.splice(12..12, [0x81, 0x00, 0x00, 20]);
+        assert!(b.forward(now, 7, &tagged).is_empty());
+    }
+}
@@ -0,0 +1,238 @@
+//! The native cable, a relay between two plugs that applies the impairments
+//! itself, without dpipe and wirefilter. It is controlled through a unix
+//! socket, with one command per connection:
+//! - the commands of wirefilter, i.e. `delay LR 100+10`, answering `ok`
+//! - `showinfo` answers the impairments of both directions
+//! - `stats` answers the traffic counters of both directions, in json
+//! - `shutdown` stops the cable
+
+use anyhow::{Context, Result};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::Instant;
+use std::{fs, process, thread};
+
+use super::link::{self, Link};
+use super::plug::Plug;
+use super::{RETRY_MAX, RETRY_MIN};
+use crate::stats::{Meter, Traffic};
+use crate::vde::Direction;
+
+/// A frame waiting to leave a link. Ordered by time, then by arrival
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Pending {
+    time: Instant,
+    seq: u64,
+    frame: Vec<u8>,
+}
+
+struct Channel {
+    link: Link,
+    meter: Meter,
+    pending: BinaryHeap<Reverse<Pending>>,
+    seq: u64,
+}
+
+/// A direction of the cable, with the frames crossing it
+struct Wire {
+    channel: Mutex<Channel>,
+    ready: Condvar,
+}
+
+impl Wire {
+    fn new() -> Wire {
+        Wire {
+            channel: Mutex::new(Channel {
+                link: Link::new(),
+                meter: Meter::new(true),
+                pending: BinaryHeap::new(),
+                seq: 0,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+}
+
+/// The two directions of the cable: from A to B and from B to A
+type Wires = [Arc<Wire>; 2];
+
+/// Apply a wirefilter command to the directions it refers to
+fn apply(wires: &Wires, line: &str) -> Result<()> {
+    let command = link::parse_command(line)?;
+    let selected = match command.direction {
+        Direction::AToB => &wires[..1],
+        Direction::BToA => &wires[1..],
+        Direction::Both => &wires[..],
+    };
+    for w in selected {
+        let mut channel = w.channel.lock().unwrap();
+        channel
+            .link
+            .get_shape_mut()
+            .set(&command.name, &command.value)?;
+    }
+    Ok(())
+}
+
+/// Receive the frames of a plug and queue them on a direction
+fn receive(from: &Plug, wire: &Wire) -> Result<()> {
+    let mut buf = vec![0u8; u16::MAX as usize];
+    let mut retry = RETRY_MIN;
+    loop {
+        let Some(n) = from
+            .recv(&mut buf)
+            .context(format!("Receiving from {}", from.get_url()))?
+        else {
+            // The peer is away, don't spin until it comes back
+            thread::sleep(retry);
+            retry = (retry * 2).min(RETRY_MAX);
+            continue;
+        };
+        retry = RETRY_MIN;
+
+        let mut frame = buf[..n].to_vec();
+        let now = Instant::now();
+        let mut channel = wire.channel.lock().unwrap();
+        channel.meter.record(now, n);
+        let departures = channel.link.admit(now, &mut frame);
+        if departures.is_empty() {
+            channel.meter.record_drop();
+        }
+        for time in departures {
+            let seq = channel.seq;
+            channel.seq += 1;
+            channel.pending.push(Reverse(Pending {
+                time,
+                seq,
+                frame: frame.clone(),
+            }));
+        }
+        wire.ready.notify_one();
+    }
+}
+
+/// Send the frames of a direction to a plug, when their time comes
+fn deliver(wire: &Wire, to: &Plug) {
+    let mut channel = wire.channel.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        channel = match channel.pending.peek() {
+            None => wire.ready.wait(channel).unwrap(),
+            Some(Reverse(p)) if p.time > now => {
+                let wait = p.time - now;
+                wire.ready.wait_timeout(channel, wait).unwrap().0
+            }
+            Some(_) => {
+                let Reverse(p) = channel.pending.pop().unwrap();
+                drop(channel);
+                to.send(&p.frame);
+                wire.channel.lock().unwrap()
+            }
+        };
+    }
+}
+
+fn handle(stream: UnixStream, wires: &Wires) -> Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let line = line.trim();
+
+    let reply = match line {
+        "showinfo" => {
+            let shape = |w: &Arc<Wire>| w.channel.lock().unwrap().link.get_shape().to_string();
+            format!("LR {}\nRL {}", shape(&wires[0]), shape(&wires[1]))
+        }
+        "stats" => {
+            let now = Instant::now();
+            let counters = |w: &Arc<Wire>| w.channel.lock().unwrap().meter.counters(now);
+            let traffic = Traffic {
+                a_to_b: counters(&wires[0]),
+                b_to_a: counters(&wires[1]),
+            };
+            serde_json::to_string(&traffic)?
+        }
+        "shutdown" => {
+            (&stream).write_all(b"ok\n")?;
+            process::exit(0);
+        }
+        _ => match apply(wires, line) {
+            Ok(()) => "ok".to_owned(),
+            Err(e) => format!("error {e:#}"),
+        },
+    };
+    (&stream).write_all(format!("{reply}\n").as_bytes())?;
+    Ok(())
+}
+
+/// Run a native cable between the plugs `a` and `b`. The process becomes a
+/// daemon once the plugs are connected, and runs until a switch it is
+/// plugged in goes away. `dir` holds the sockets used with the switches
+pub fn run(
+    name: &str,
+    a: &str,
+    b: &str,
+    dir: &str,
+    control: &str,
+    pidfile: &str,
+    rcfile: Option<&str>,
+) -> Result<()> {
+    let wires: Wires = [Arc::new(Wire::new()), Arc::new(Wire::new())];
+    if let Some(rcfile) = rcfile {
+        let content = fs::read_to_string(rcfile).context(format!("Reading {rcfile}"))?;
+        for line in content.lines().map(str::trim) {
+            if !line.is_empty() && !line.starts_with('#') {
+                apply(&wires, line).context(format!("Applying {rcfile}"))?;
+            }
+        }
+    }
+
+    let description = format!("imaginet cable {name}");
+    let local = |side: &str| Path::new(dir).join(format!("{side}.sock"));
+    let plugs = [
+        Arc::new(Plug::open(a, local("a").to_str().unwrap(), &description)?),
+        Arc::new(Plug::open(b, local("b").to_str().unwrap(), &description)?),
+    ];
+
+    if fs::exists(control)? {
+        fs::remove_file(control).context("Removing old control socket")?;
+    }
+    let listener = UnixListener::bind(control).context("Creating control socket")?;
+
+    super::daemonize()?;
+    fs::write(pidfile, process::id().to_string()).context("Writing pid file")?;
+
+    let controlled = wires.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = handle(stream, &controlled) {
+                log::warn!("Control connection: {e:#}");
+            }
+        }
+    });
+
+    let (done, finished) = mpsc::channel();
+    for (from, to, wire) in [(0, 1, &wires[0]), (1, 0, &wires[1])] {
+        let (input, output) = (Arc::clone(&plugs[from]), Arc::clone(&plugs[to]));
+        let (receiving, delivering) = (Arc::clone(wire), Arc::clone(wire));
+        let done = done.clone();
+        thread::spawn(move || {
+            let _ = done.send(receive(&input, &receiving));
+        });
+        thread::spawn(move || deliver(&delivering, &output));
+    }
+    // A cable plugged in a switch goes away with it
+    for plug in &plugs {
+        let (plug, done) = (Arc::clone(plug), done.clone());
+        thread::spawn(move || {
+            plug.wait_closed();
+            log::info!("{} closed", plug.get_url());
+            let _ = done.send(Ok(()));
+        });
+    }
+
+    finished.recv()?
+}
@@ -0,0 +1,300 @@
+//! The impairments of a direction of a native cable. They are set with the
+//! commands of wirefilter, `<command> [LR|RL] <value>`, where LR is the
+//! direction from A to B and RL the opposite one.
+
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::vde::{self, Direction};
+
+/// Frames are considered damaged on this many bytes by noise
+const NOISE_UNIT: f64 = 1024.0 * 1024.0;
+
+/// Impairments of a direction. Values set to 0 are disabled
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Shape {
+    delay: Duration,
+    /// Maximum random variation of the delay, in both directions
+    jitter: Duration,
+    /// Percentage of lost frames
+    loss: f64,
+    /// Percentage of duplicated frames
+    dup: f64,
+    /// Bytes per second of the channel, queueing the frames
+    bandwidth: f64,
+    /// Bytes per second of the interface, delaying each frame by its
+    /// transmission time
+    speed: f64,
+    mtu: usize,
+    /// Damaged bits per megabyte
+    noise: u32,
+    /// Bytes queued on the channel, after which frames are dropped
+    chanbufsize: usize,
+}
+
+/// A command changing the impairments, as written in the rc files
+#[derive(Debug, PartialEq)]
+pub struct Command {
+    pub name: String,
+    pub direction: Direction,
+    pub value: String,
+}
+
+/// Parse a command. Without LR or RL, it applies to both directions
+pub fn parse_command(line: &str) -> Result<Command> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, direction, value) = match words.as_slice() {
+        [name, "LR", value] => (name, Direction::AToB, value),
+        [name, "RL", value] => (name, Direction::BToA, value),
+        [name, value] => (name, Direction::Both, value),
+        _ => bail!("Invalid command \"{line}\": use <command> [LR|RL] <value>"),
+    };
+    Ok(Command {
+        name: name.to_string(),
+        direction,
+        value: value.to_string(),
+    })
+}
+
+/// Parse a rate in bytes per second with an optional K, M or G multiplier
+fn parse_rate(s: &str) -> Result<f64> {
+    let (number, factor) = match s.chars().last() {
+        Some('K' | 'k') => (&s[..s.len() - 1], 1e3),
+        Some('M' | 'm') => (&s[..s.len() - 1], 1e6),
+        Some('G' | 'g') => (&s[..s.len() - 1], 1e9),
+        _ => (s, 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n >= 0.0 && n.is_finite() => Ok(n * factor),
+        _ => bail!("Invalid rate {s}"),
+    }
+}
+
+fn millis(ms: f64) -> Duration {
+    Duration::from_secs_f64(ms / 1000.0)
+}
+
+impl Shape {
+    /// Change an impairment, with its value in the syntax of wirefilter
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let context = || format!("Setting {name} to {value}");
+        match name {
+            "delay" => {
+                let (delay, jitter) = value.split_once('+').unwrap_or((value, "0"));
+                self.delay = millis(vde::parse_delay(delay).with_context(context)?);
+                self.jitter = millis(vde::parse_delay(jitter).with_context(context)?);
+            }
+            "loss" => self.loss = vde::parse_percentage(value).with_context(context)?,
+            "dup" => self.dup = vde::parse_percentage(value).with_context(context)?,
+            "bandwidth" => self.bandwidth = parse_rate(value).with_context(context)?,
+            "speed" => self.speed = parse_rate(value).with_context(context)?,
+            "mtu" => self.mtu = value.parse().with_context(context)?,
+            "noise" => self.noise = value.parse().with_context(context)?,
+            "chanbufsize" => self.chanbufsize = value.parse().with_context(context)?,
+            _ => bail!("Unknown command {name}"),
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |d: Duration| d.as_micros() as f64 / 1000.0;
+        let mut values = Vec::new();
+        if !self.jitter.is_zero() {
+            values.push(format!("delay {}+{}", ms(self.delay), ms(self.jitter)));
+        } else if !self.delay.is_zero() {
+            values.push(format!("delay {}", ms(self.delay)));
+        }
+        for (name, v) in [("loss", self.loss), ("dup", self.dup)] {
+            if v > 0.0 {
+                values.push(format!("{name} {v}"));
+            }
+        }
+        for (name, v) in [("bandwidth", self.bandwidth), ("speed", self.speed)] {
+            if v > 0.0 {
+                values.push(format!("{name} {v}"));
+            }
+        }
+        for (name, v) in [("mtu", self.mtu), ("chanbufsize", self.chanbufsize)] {
+            if v > 0 {
+                values.push(format!("{name} {v}"));
+            }
+        }
+        if self.noise > 0 {
+            values.push(format!("noise {}", self.noise));
+        }
+
+        if values.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", values.join(", "))
+        }
+    }
+}
+
+/// Xorshift generator, good enough to decide the fate of frames
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Rng {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64
+            ^ u64::from(std::process::id());
+        Rng(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// True with the given percentage
+    fn chance(&mut self, percentage: f64) -> bool {
+        percentage > 0.0 && self.unit() * 100.0 < percentage
+    }
+}
+
+/// A direction of a native cable
+pub struct Link {
+    shape: Shape,
+    /// When the channel finishes sending the frames queued so far
+    busy_until: Instant,
+    rng: Rng,
+}
+
+impl Link {
+    pub fn new() -> Link {
+        Link {
+            shape: Shape::default(),
+            busy_until: Instant::now(),
+            rng: Rng::new(),
+        }
+    }
+
+    pub fn get_shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    pub fn get_shape_mut(&mut self) -> &mut Shape {
+        &mut self.shape
+    }
+
+    /// Decide the fate of a frame entering the link at `now`, damaging it
+    /// if there is noise. Returns when each of its copies leaves the link:
+    /// none if the frame is lost
+    pub fn admit(&mut self, now: Instant, frame: &mut [u8]) -> Vec<Instant> {
+        let s = &self.shape;
+        if (s.mtu > 0 && frame.len() > s.mtu) || self.rng.chance(s.loss) {
+            return Vec::new();
+        }
+        let copies = if self.rng.chance(s.dup) { 2 } else { 1 };
+
+        if s.noise > 0 && !frame.is_empty() {
+            let damaged = f64::from(s.noise) * frame.len() as f64 / NOISE_UNIT;
+            let mut bits = damaged as u64 + u64::from(self.rng.unit() < damaged.fract());
+            while bits > 0 {
+                let bit = self.rng.next() as usize % (frame.len() * 8);
+                frame[bit / 8] ^= 1 << (bit % 8);
+                bits -= 1;
+            }
+        }
+
+        let len = frame.len() as f64;
+        let mut departures = Vec::new();
+        for _ in 0..copies {
+            let s = &self.shape;
+            let mut time = now;
+            if s.bandwidth > 0.0 {
+                let start = self.busy_until.max(now);
+                let queued = (start - now).as_secs_f64() * s.bandwidth;
+                if s.chanbufsize > 0 && queued + len > s.chanbufsize as f64 {
+                    continue;
+                }
+                self.busy_until = start + Duration::from_secs_f64(len / s.bandwidth);
+                time = self.busy_until;
+            }
+            if s.speed > 0.0 {
+                time += Duration::from_secs_f64(len / s.speed);
+            }
+
+            let jitter = s.jitter.as_secs_f64() * (2.0 * self.rng.unit() - 1.0);
+            let delay = (s.delay.as_secs_f64() + jitter).max(0.0);
+            departures.push(time + Duration::from_secs_f64(delay));
+        }
+        departures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            parse_command("delay LR 100+10").unwrap(),
+            Command {
+                name: "delay".to_owned(),
+                direction: Direction::AToB,
+                value: "100+10".to_owned()
+            }
+        );
+        assert_eq!(parse_command("loss 5").unwrap().direction, Direction::Both);
+        assert!(parse_command("loss").is_err());
+
+        let mut s = Shape::default();
+        s.set("delay", "100+10").unwrap();
+        s.set("bandwidth", "125K").unwrap();
+        assert_eq!(s.delay, Duration::from_millis(100));
+        assert_eq!(s.jitter, Duration::from_millis(10));
+        assert_eq!(s.bandwidth, 125000.0);
+        assert_eq!(s.to_string(), "delay 100+10, bandwidth 125000");
+        assert!(s.set("loss", "101").is_err());
+        assert!(s.set("markov-numnodes", "2").is_err());
+    }
+
+    #[test]
+    fn loss_dup_and_mtu() {
+        let now = Instant::now();
+        let mut link = Link::new();
+        assert_eq!(link.admit(now, &mut [0; 100]), vec![now]);
+
+        link.get_shape_mut().set("dup", "100").unwrap();
+        assert_eq!(link.admit(now, &mut [0; 100]).len(), 2);
+
+        link.get_shape_mut().set("mtu", "50").unwrap();
+        assert!(link.admit(now, &mut [0; 100]).is_empty());
+
+        link.get_shape_mut().set("mtu", "0").unwrap();
+        link.get_shape_mut().set("loss", "100").unwrap();
+        assert!(link.admit(now, &mut [0; 100]).is_empty());
+    }
+
+    #[test]
+    fn bandwidth_queues_frames() {
+        let now = Instant::now();
+        let mut link = Link::new();
+        link.get_shape_mut().set("bandwidth", "1000").unwrap();
+        link.get_shape_mut().set("delay", "10").unwrap();
+        link.busy_until = now;
+
+        let ms = |d: Vec<Instant>| d.iter().map(|t| (*t - now).as_millis()).collect::<Vec<_>>();
+        assert_eq!(ms(link.admit(now, &mut [0; 500])), vec![510]);
+        assert_eq!(ms(link.admit(now, &mut [0; 500])), vec![1010]);
+
+        // The channel holds 1000 bytes, one more frame does not fit
+        link.get_shape_mut().set("chanbufsize", "1000").unwrap();
+        assert!(link.admit(now, &mut [0; 500]).is_empty());
+    }
+}
@@ -0,0 +1,355 @@
+//! Plugs of the native devices, speaking the vde protocols:
+//! - `vde://<dir>[port]` connects to a vde switch. A request on the `ctl`
+//!   socket of the switch registers our datagram socket on a port, then the
+//!   frames are exchanged as datagrams with the data socket of the switch
+//! - `ptp://<path>` is a point to point datagram socket. The first side
+//!   binds the path and answers the last peer it heard from, the other one
+//!   sends to the path
+//!
+//! The requests of new ports are also decoded here, for the native switch.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::{self, Read, Write};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixStream};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::{fs, process};
+
+/// Magic and version of the requests understood by the vde switches
+const SWITCH_MAGIC: u32 = 0xfeedface;
+const REQUEST_VERSION: u32 = 3;
+/// Request of a new port. The port number is in the upper bits
+const REQ_NEW_CONTROL: u32 = 0;
+/// Size of a struct sockaddr_un: the family and the path
+pub const SOCKADDR_UN_LEN: usize = 110;
+const SUN_PATH_LEN: usize = SOCKADDR_UN_LEN - 2;
+const MAX_DESCRIPTION: usize = 128;
+
+/// Errors of ptp sockets caused by a peer that went away, or that is not
+/// there yet. They last until the peer comes back
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::Interrupted
+    )
+}
+
+/// Used to give a unique name to the sockets of the ptp clients
+static PTP_CLIENTS: AtomicU32 = AtomicU32::new(0);
+
+/// Where a plug is connected
+#[derive(Debug, PartialEq)]
+enum Target {
+    Vde { dir: String, port: u32 },
+    Ptp { path: String },
+}
+
+fn parse_url(url: &str) -> Result<Target> {
+    if let Some(rest) = url.strip_prefix("vde://") {
+        let (dir, port) = match rest.strip_suffix(']').and_then(|r| r.rsplit_once('[')) {
+            Some((dir, port)) => (
+                dir,
+                port.parse()
+                    .map_err(|_| anyhow!("Invalid port {port} in {url}"))?,
+            ),
+            None => (rest, 0),
+        };
+        return Ok(Target::Vde {
+            dir: dir.to_owned(),
+            port,
+        });
+    }
+    if let Some(path) = url.strip_prefix("ptp://") {
+        return Ok(Target::Ptp {
+            path: path.to_owned(),
+        });
+    }
+    bail!("Unsupported plug {url}: native devices support vde:// and ptp://")
+}
+
+/// Encode a struct sockaddr_un for a path
+pub fn sockaddr_un(path: &str) -> Result<[u8; SOCKADDR_UN_LEN]> {
+    if path.len() >= SUN_PATH_LEN {
+        bail!("Socket path {path} is too long");
+    }
+    let mut buf = [0u8; SOCKADDR_UN_LEN];
+    buf[..2].copy_from_slice(&(libc::AF_UNIX as u16).to_ne_bytes());
+    buf[2..2 + path.len()].copy_from_slice(path.as_bytes());
+    Ok(buf)
+}
+
+/// Encode the request of a new port, as a packed struct request_v3
+fn port_request(port: u32, socket: &str, description: &str) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(12 + SOCKADDR_UN_LEN + MAX_DESCRIPTION);
+    buf.extend(SWITCH_MAGIC.to_ne_bytes());
+    buf.extend(REQUEST_VERSION.to_ne_bytes());
+    buf.extend((REQ_NEW_CONTROL | (port << 8)).to_ne_bytes());
+    buf.extend(sockaddr_un(socket)?);
+
+    let description = description.as_bytes();
+    let len = description.len().min(MAX_DESCRIPTION - 1);
+    buf.extend(&description[..len]);
+    buf.resize(buf.capacity(), 0);
+    Ok(buf)
+}
+
+/// Path of a struct sockaddr_un sent by a switch
+fn sockaddr_un_path(buf: &[u8; SOCKADDR_UN_LEN]) -> Result<String> {
+    let path = &buf[2..];
+    let len = path.iter().position(|&c| c == 0).unwrap_or(path.len());
+    String::from_utf8(path[..len].to_vec()).context("Invalid data socket path")
+}
+
+/// A request of a new port, as received by a switch
+#[derive(Debug, PartialEq)]
+pub struct PortRequest {
+    /// Requested port, 0 for any free port
+    pub port: u32,
+    /// Datagram socket of the plug, to which frames are sent
+    pub socket: String,
+    pub description: String,
+}
+
+/// Decode a struct request_v3
+pub fn parse_port_request(buf: &[u8]) -> Result<PortRequest> {
+    let word = |i: usize| u32::from_ne_bytes(buf[i..i + 4].try_into().unwrap());
+    if buf.len() < 12 + SOCKADDR_UN_LEN || word(0) != SWITCH_MAGIC {
+        bail!("Not a port request");
+    }
+    if word(4) != REQUEST_VERSION {
+        bail!("Unsupported request version {}", word(4));
+    }
+    let kind = word(8);
+    if kind & 0xff != REQ_NEW_CONTROL {
+        bail!("Unsupported request type {}", kind & 0xff);
+    }
+
+    let socket = sockaddr_un_path(buf[12..12 + SOCKADDR_UN_LEN].try_into().unwrap())?;
+    let description = &buf[12 + SOCKADDR_UN_LEN..];
+    let len = description
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(description.len());
+    Ok(PortRequest {
+        port: kind >> 8,
+        socket,
+        description: String::from_utf8_lossy(&description[..len]).into_owned(),
+    })
+}
+
+enum Side {
+    /// Registered on a port of a switch, which closes the control
+    /// connection when it goes away
+    Switch { ctl: UnixStream },
+    /// Owner of a ptp path, answering the last peer heard from
+    PtpServer { peer: Mutex<Option<SocketAddr>> },
+    /// Connected to the ptp path owned by the peer
+    PtpClient { path: String },
+}
+
+/// An endpoint of a native device
+pub struct Plug {
+    url: String,
+    socket: UnixDatagram,
+    side: Side,
+}
+
+impl Plug {
+    /// Connect a plug. `local` is the path of the datagram socket used with
+    /// the switches, and `description` is shown by the switch for the port
+    pub fn open(url: &str, local: &str, description: &str) -> Result<Plug> {
+        let (socket, side) = match parse_url(url)? {
+            Target::Vde { dir, port } => {
+                let ctl_path = format!("{dir}/ctl");
+                let mut ctl = UnixStream::connect(&ctl_path)
+                    .context(format!("Connecting to the switch {dir}"))?;
+
+                if fs::exists(local)? {
+                    fs::remove_file(local).context(format!("Removing old socket {local}"))?;
+                }
+                let socket = UnixDatagram::bind(local).context(format!("Binding {local}"))?;
+
+                ctl.write_all(&port_request(port, local, description)?)
+                    .context(format!("Requesting a port to {dir}"))?;
+                let mut reply = [0u8; SOCKADDR_UN_LEN];
+                ctl.read_exact(&mut reply)
+                    .context(format!("The switch {dir} refused the port"))?;
+                let data = sockaddr_un_path(&reply)?;
+                socket
+                    .connect(&data)
+                    .context(format!("Connecting to the data socket {data}"))?;
+                (socket, Side::Switch { ctl })
+            }
+            Target::Ptp { path } => Self::open_ptp(&path)?,
+        };
+
+        Ok(Plug {
+            url: url.to_owned(),
+            socket,
+            side,
+        })
+    }
+
+    fn open_ptp(path: &str) -> Result<(UnixDatagram, Side)> {
+        match UnixDatagram::bind(path) {
+            Ok(socket) => {
+                let peer = Mutex::new(None);
+                return Ok((socket, Side::PtpServer { peer }));
+            }
+            Err(e) if e.kind() != io::ErrorKind::AddrInUse => {
+                return Err(e).context(format!("Binding {path}"));
+            }
+            Err(_) => {}
+        }
+
+        // The peer needs an address to answer to
+        let name = format!(
+            "imaginet-{}-{}",
+            process::id(),
+            PTP_CLIENTS.fetch_add(1, Ordering::Relaxed)
+        );
+        let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+        let socket = UnixDatagram::bind_addr(&addr).context("Binding ptp socket")?;
+        match socket.connect(path) {
+            Ok(()) => Ok((
+                socket,
+                Side::PtpClient {
+                    path: path.to_owned(),
+                },
+            )),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                // Nobody owns the path anymore
+                fs::remove_file(path).context(format!("Removing stale socket {path}"))?;
+                let socket = UnixDatagram::bind(path).context(format!("Binding {path}"))?;
+                Ok((
+                    socket,
+                    Side::PtpServer {
+                        peer: Mutex::new(None),
+                    },
+                ))
+            }
+            Err(e) => Err(e).context(format!("Connecting to {path}")),
+        }
+    }
+
+    pub fn get_url(&self) -> &str {
+        &self.url
+    }
+
+    /// Receive a frame. The transient errors of ptp plugs, caused by a peer
+    /// going away, are not fatal and are reported as None
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match &self.side {
+            Side::Switch { .. } => self.socket.recv(buf).map(Some),
+            Side::PtpServer { peer } => match self.socket.recv_from(buf) {
+                Ok((n, from)) => {
+                    *peer.lock().unwrap() = Some(from);
+                    Ok(Some(n))
+                }
+                Err(e) if is_transient(&e) => {
+                    log::debug!("{}: {e}", self.url);
+                    Ok(None)
+                }
+                Err(e) => Err(e),
+            },
+            Side::PtpClient { .. } => match self.socket.recv(buf) {
+                Ok(n) => Ok(Some(n)),
+                Err(e) if is_transient(&e) => {
+                    log::debug!("{}: {e}", self.url);
+                    Ok(None)
+                }
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    /// Send a frame. Frames that can't be delivered are dropped, as on a
+    /// real cable
+    pub fn send(&self, frame: &[u8]) {
+        let result = match &self.side {
+            Side::Switch { .. } => self.socket.send(frame),
+            Side::PtpServer { peer } => match &*peer.lock().unwrap() {
+                Some(addr) => self.socket.send_to_addr(frame, addr),
+                None => Ok(0),
+            },
+            Side::PtpClient { path } => self.socket.send(frame).inspect_err(|_| {
+                // The owner of the path may have been restarted
+                let _ = self.socket.connect(path);
+            }),
+        };
+        if let Err(e) = result {
+            log::debug!("{}: dropping frame: {e}", self.url);
+        }
+    }
+
+    /// Wait until the switch of the plug goes away. Ptp plugs have no such
+    /// notion, and never return
+    pub fn wait_closed(&self) {
+        match &self.side {
+            Side::Switch { ctl } => {
+                let mut buf = [0u8; 256];
+                while matches!((&*ctl).read(&mut buf), Ok(n) if n > 0) {}
+            }
+            _ => loop {
+                std::thread::park();
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            parse_url("vde:///run/sw1[3]").unwrap(),
+            Target::Vde {
+                dir: "/run/sw1".to_owned(),
+                port: 3
+            }
+        );
+        assert_eq!(
+            parse_url("vde:///run/sw1").unwrap(),
+            Target::Vde {
+                dir: "/run/sw1".to_owned(),
+                port: 0
+            }
+        );
+        assert_eq!(
+            parse_url("ptp:///run/ns1/eth0").unwrap(),
+            Target::Ptp {
+                path: "/run/ns1/eth0".to_owned()
+            }
+        );
+        assert!(parse_url("vde:///run/sw1[x]").is_err());
+        assert!(parse_url("tap://tap0").is_err());
+    }
+
+    #[test]
+    fn port_request_layout() {
+        let req = port_request(5, "/run/conn1/a.sock", "conn1").unwrap();
+        assert_eq!(req.len(), 250);
+        assert_eq!(&req[..4], &SWITCH_MAGIC.to_ne_bytes());
+        assert_eq!(&req[8..12], &(5u32 << 8).to_ne_bytes());
+
+        let addr: [u8; SOCKADDR_UN_LEN] = req[12..12 + SOCKADDR_UN_LEN].try_into().unwrap();
+        assert_eq!(sockaddr_un_path(&addr).unwrap(), "/run/conn1/a.sock");
+        assert_eq!(&req[12 + SOCKADDR_UN_LEN..][..6], b"conn1\0");
+
+        assert_eq!(
+            parse_port_request(&req).unwrap(),
+            PortRequest {
+                port: 5,
+                socket: "/run/conn1/a.sock".to_owned(),
+                description: "conn1".to_owned()
+            }
+        );
+        assert!(parse_port_request(&req[..100]).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+//! The native switch, serving the same directory protocol as vde_switch:
+//! plugs request a port on the `ctl` socket of the directory, and get back
+//! the datagram socket of their port. The management socket speaks the
+//! protocol of vde_switch, so vdecmd and vdeterm work with it.
+
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use std::{fs, process, thread};
+
+use super::bridge::Bridge;
+use super::plug::{self, SOCKADDR_UN_LEN};
+use super::{RETRY_MAX, RETRY_MIN};
+
+const PROMPT: &str = "vde$ ";
+
+/// A port with a plug connected
+#[derive(Clone)]
+struct Port {
+    socket: Arc<UnixDatagram>,
+    /// Address of the socket of the plug, where its frames are sent
+    peer: Arc<[u8; SOCKADDR_UN_LEN]>,
+}
+
+struct State {
+    bridge: Bridge,
+    ports: HashMap<u32, Port>,
+}
+
+type Shared = Arc<Mutex<State>>;
+
+fn remove_stale(path: &str) -> Result<()> {
+    if fs::exists(path)? {
+        fs::remove_file(path).context(format!("Removing old socket {path}"))?;
+    }
+    Ok(())
+}
+
+/// Send a frame to a plug without waiting: like in vde_switch, a plug that
+/// does not keep up loses the frame instead of stopping the switch
+fn send_nowait(
+    socket: &UnixDatagram,
+    frame: &[u8],
+    peer: &[u8; SOCKADDR_UN_LEN],
+) -> io::Result<()> {
+    // SAFETY: the frame and the address are valid for their lengths for the
+    // whole call, and the socket is kept open by the borrow
+    let n = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            frame.as_ptr().cast(),
+            frame.len(),
+            libc::MSG_DONTWAIT,
+            peer.as_ptr().cast(),
+            SOCKADDR_UN_LEN as libc::socklen_t,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Forward the frames received on a port, until the port is disconnected.
+/// The state is locked only to find where the frames go, not to send them
+fn receive(port: u32, socket: Arc<UnixDatagram>, state: &Shared) {
+    let mut buf = vec![0u8; u16::MAX as usize];
+    let mut retry = RETRY_MIN;
+    loop {
+        let n = socket.recv(&mut buf);
+        let mut state = state.lock().unwrap();
+        let State { bridge, ports } = &mut *state;
+        if !ports
+            .get(&port)
+            .is_some_and(|p| Arc::ptr_eq(&p.socket, &socket))
+        {
+            return;
+        }
+        let n = match n {
+            Ok(n) => n,
+            Err(e) => {
+                drop(state);
+                log::debug!("Port {port}: receiving: {e}");
+                thread::sleep(retry);
+                retry = (retry * 2).min(RETRY_MAX);
+                continue;
+            }
+        };
+        retry = RETRY_MIN;
+
+        let targets: Vec<(u32, Port, Vec<u8>)> = bridge
+            .forward(Instant::now(), port, &buf[..n])
+            .into_iter()
+            .filter_map(|(to, frame)| ports.get(&to).map(|p| (to, p.clone(), frame)))
+            .collect();
+        drop(state);
+
+        for (to, p, frame) in targets {
+            if let Err(e) = send_nowait(&p.socket, &frame, &p.peer) {
+                log::debug!("Port {to}: dropping frame: {e}");
+            }
+        }
+    }
+}
+
+/// Connect a plug asking for a port on the control socket. The port lasts
+/// as long as the connection
+fn connect(mut stream: UnixStream, dir: &str, state: &Shared) -> Result<()> {
+    let mut buf = [0u8; 512];
+    let n = stream.read(&mut buf).context("Reading port request")?;
+    let request = plug::parse_port_request(&buf[..n])?;
+
+    let port = state
+        .lock()
+        .unwrap()
+        .bridge
+        .connect(request.port, &request.description)
+        .map_err(|e| anyhow::anyhow!("Port {} for {}: {e}", request.port, request.socket))?;
+
+    let path = format!("{dir}/.port-{port}");
+    let socket = plug::sockaddr_un(&request.socket).and_then(|peer| {
+        remove_stale(&path)?;
+        let s = UnixDatagram::bind(&path).context(format!("Binding {path}"))?;
+        stream.write_all(&plug::sockaddr_un(&path)?)?;
+        Ok((Arc::new(s), peer))
+    });
+    let (socket, peer) = match socket {
+        Ok(s) => s,
+        Err(e) => {
+            state.lock().unwrap().bridge.disconnect(port);
+            return Err(e);
+        }
+    };
+    log::info!("Port {port}: {} connected", request.description);
+
+    state.lock().unwrap().ports.insert(
+        port,
+        Port {
+            socket: Arc::clone(&socket),
+            peer: Arc::new(peer),
+        },
+    );
+    let (receiving, shared) = (Arc::clone(&socket), Arc::clone(state));
+    thread::spawn(move || receive(port, receiving, &shared));
+
+    while matches!(stream.read(&mut buf), Ok(n) if n > 0) {}
+
+    let mut s = state.lock().unwrap();
+    s.ports.remove(&port);
+    s.bridge.disconnect(port);
+    drop(s);
+    let _ = socket.shutdown(Shutdown::Both);
+    let _ = fs::remove_file(&path);
+    log::info!("Port {port}: disconnected");
+    Ok(())
+}
+
+/// Serve a management connection, in the format of vde_switch
+fn manage(stream: UnixStream, name: &str, state: &Shared) -> Result<()> {
+    let mut out = &stream;
+    write!(out, "VDE switch {name} (imaginet native)\n\n{PROMPT}")?;
+    for line in BufReader::new(&stream).lines() {
+        let line = line?;
+        let line = line.trim();
+        match line {
+            "" => {}
+            "logout" => return Ok(()),
+            "shutdown" => {
+                out.write_all(b"1000 Success\n\n")?;
+                process::exit(0);
+            }
+            _ => match state.lock().unwrap().bridge.command(Instant::now(), line) {
+                Ok(data) => {
+                    if !data.is_empty() {
+                        write!(out, "0000 DATA END WITH '.'\n{data}\n.\n")?;
+                    }
+                    out.write_all(b"1000 Success\n\n")?;
+                }
+                Err(e) => write!(out, "{} {e}\n\n", 1000 + e.errno())?,
+            },
+        }
+        out.write_all(PROMPT.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Run a native switch serving the directory `sock`, with the management
+/// socket `mgmt`. The process becomes a daemon once the sockets are ready
+pub fn run(
+    name: &str,
+    sock: &str,
+    mgmt: &str,
+    pidfile: &str,
+    numports: u32,
+    hub: bool,
+    rcfile: Option<&str>,
+) -> Result<()> {
+    let mut bridge = Bridge::new(numports, hub);
+    if let Some(rcfile) = rcfile {
+        let content = fs::read_to_string(rcfile).context(format!("Reading {rcfile}"))?;
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Err(e) = bridge.command(Instant::now(), line) {
+                bail!("Applying {rcfile}: {line}: {e}");
+            }
+        }
+    }
+
+    fs::create_dir_all(sock).context(format!("Creating {sock}"))?;
+    let ctl_path = Path::new(sock).join("ctl");
+    let ctl_path = ctl_path.to_str().unwrap();
+    remove_stale(ctl_path)?;
+    let ctl = UnixListener::bind(ctl_path).context("Creating control socket")?;
+    remove_stale(mgmt)?;
+    let management = UnixListener::bind(mgmt).context("Creating management socket")?;
+
+    super::daemonize()?;
+    fs::write(pidfile, process::id().to_string()).context("Writing pid file")?;
+
+    let state: Shared = Arc::new(Mutex::new(State {
+        bridge,
+        ports: HashMap::new(),
+    }));
+
+    let (managed, name) = (Arc::clone(&state), name.to_owned());
+    thread::spawn(move || {
+        for stream in management.incoming().flatten() {
+            let (state, name) = (Arc::clone(&managed), name.clone());
+            thread::spawn(move || {
+                if let Err(e) = manage(stream, &name, &state) {
+                    log::warn!("Management connection: {e:#}");
+                }
+            });
+        }
+    });
+
+    for stream in ctl.incoming() {
+        let stream = stream.context("Accepting plug")?;
+        let (state, dir) = (Arc::clone(&state), sock.to_owned());
+        thread::spawn(move || {
+            if let Err(e) = connect(stream, &dir, &state) {
+                log::warn!("{e:#}");
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_plug_does_not_block() {
+        let dir = std::env::temp_dir().join(format!("imaginet-test-{}-nowait", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("plug");
+        // The plug never reads, so its queue fills up
+        let _plug = UnixDatagram::bind(&path).unwrap();
+        let peer = plug::sockaddr_un(path.to_str().unwrap()).unwrap();
+
+        let socket = UnixDatagram::unbound().unwrap();
+        let frame = [0u8; 1500];
+        let err = (0..100000)
+            .find_map(|_| send_nowait(&socket, &frame, &peer).err())
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
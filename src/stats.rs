@@ -0,0 +1,246 @@
+//! Traffic counters of the cables. They are kept by the processes that see
+//! the frames crossing a cable: the native relay, which also counts the
+//! frames dropped by the impairments, and the capture stage.
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::executor::{self, Options};
+use crate::vde;
+
+/// Period over which the throughput is measured
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Counters of a direction of a cable
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Counters {
+    /// Frames entering the cable
+    pub frames: u64,
+    pub bytes: u64,
+    /// Frames dropped by the impairments. Unknown for the cables that do
+    /// not apply the impairments themselves
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dropped: Option<u64>,
+    /// Bytes per second entering the cable, over the last second
+    pub throughput: f64,
+}
+
+/// Counters of both the directions of a cable
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Traffic {
+    pub a_to_b: Counters,
+    pub b_to_a: Counters,
+}
+
+/// Keeps the counters of a direction of a cable
+pub struct Meter {
+    counters: Counters,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl Meter {
+    /// A meter counting the dropped frames, if `drops` is set
+    pub fn new(drops: bool) -> Meter {
+        Meter {
+            counters: Counters {
+                dropped: drops.then_some(0),
+                ..Default::default()
+            },
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Update the throughput when its window is over
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= WINDOW {
+            self.counters.throughput = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+    }
+
+    pub fn record(&mut self, now: Instant, len: usize) {
+        self.roll(now);
+        self.counters.frames += 1;
+        self.counters.bytes += len as u64;
+        self.window_bytes += len as u64;
+    }
+
+    pub fn record_drop(&mut self) {
+        if let Some(d) = &mut self.counters.dropped {
+            *d += 1;
+        }
+    }
+
+    pub fn counters(&mut self, now: Instant) -> Counters {
+        self.roll(now);
+        self.counters.clone()
+    }
+}
+
+/// Format a number of bytes, i.e. 1.5 MB
+fn human_bytes(bytes: f64) -> String {
+    let mut value = bytes;
+    for unit in ["B", "KB", "MB", "GB"] {
+        if value < 1000.0 {
+            return format!("{value:.1} {unit}");
+        }
+        value /= 1000.0;
+    }
+    format!("{value:.1} TB")
+}
+
+/// Format a throughput in bytes per second as bits per second
+fn human_rate(bytes: f64) -> String {
+    let mut value = bytes * 8.0;
+    for unit in ["bit/s", "Kbit/s", "Mbit/s"] {
+        if value < 1000.0 {
+            return format!("{value:.1} {unit}");
+        }
+        value /= 1000.0;
+    }
+    format!("{value:.1} Gbit/s")
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} frames, {}",
+            self.frames,
+            human_bytes(self.bytes as f64)
+        )?;
+        if let Some(d) = self.dropped {
+            write!(f, ", {d} dropped")?;
+        }
+        write!(f, ", {}", human_rate(self.throughput))
+    }
+}
+
+/// Where the counters of a cable come from
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Native,
+    Capture,
+}
+
+/// The counters of a cable, as exported by `imaginet stats --json`
+#[derive(Debug, Serialize)]
+pub struct CableStats {
+    pub cable: String,
+    pub source: Source,
+    #[serde(flatten)]
+    pub traffic: Traffic,
+}
+
+/// Counters of a running cable, if it has a process keeping them
+pub fn collect(opts: &Options, conn: &vde::Cable) -> Option<CableStats> {
+    let base = opts.working_dir();
+    let (source, answer) = if conn.get_engine() == vde::Engine::Native {
+        let answer = crate::native::control(&conn.control_path(&base), "stats");
+        (Source::Native, answer)
+    } else {
+        let answer = crate::capture::control(&conn.capture_socket_path(&base), "stats");
+        (Source::Capture, answer)
+    };
+
+    let traffic = serde_json::from_str(&answer.ok()?)
+        .inspect_err(|e| log::warn!("Parsing counters of {}: {e}", conn.get_name()))
+        .ok()?;
+    Some(CableStats {
+        cable: conn.get_name().to_owned(),
+        source,
+        traffic,
+    })
+}
+
+/// Print the counters of the running cables, or of the given ones
+pub fn run(opts: &Options, cables: Vec<String>, json: bool) -> Result<()> {
+    let t = executor::get_topology(opts).context("Getting topology")?;
+    for c in &cables {
+        if !t.get_cables().iter().any(|x| x.get_name() == c) {
+            anyhow::bail!("Cable {c} not found");
+        }
+    }
+
+    let selected = t
+        .get_cables()
+        .iter()
+        .filter(|c| cables.is_empty() || cables.contains(c.get_name()));
+    let mut stats = Vec::new();
+    let mut missing = Vec::new();
+    for conn in selected {
+        if !executor::pid_path_is_alive(&conn.pid_path(&opts.working_dir()))? {
+            continue;
+        }
+        match collect(opts, conn) {
+            Some(s) => stats.push(s),
+            None => missing.push(conn.get_name().as_str()),
+        }
+    }
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&stats).context("Serializing counters")?
+        );
+        return Ok(());
+    }
+
+    for s in &stats {
+        println!("- {} ({:?})", s.cable.bold(), s.source);
+        println!("\ta2b: {}", s.traffic.a_to_b);
+        println!("\tb2a: {}", s.traffic.b_to_a);
+    }
+    if !missing.is_empty() {
+        println!(
+            "No counters for {}: they are kept by native cables and cables with capture",
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meter_throughput() {
+        let start = Instant::now();
+        let mut m = Meter::new(true);
+        m.window_start = start;
+        m.record(start, 500);
+        m.record(start + Duration::from_millis(500), 1500);
+        m.record_drop();
+
+        let c = m.counters(start + Duration::from_secs(2));
+        assert_eq!((c.frames, c.bytes, c.dropped), (2, 2000, Some(1)));
+        assert_eq!(c.throughput, 1000.0);
+
+        // Nothing crossed the cable in the last window
+        let c = m.counters(start + Duration::from_secs(3));
+        assert_eq!(c.throughput, 0.0);
+    }
+
+    #[test]
+    fn counters_format() {
+        let c = Counters {
+            frames: 12,
+            bytes: 1500,
+            dropped: None,
+            throughput: 125000.0,
+        };
+        assert_eq!(c.to_string(), "12 frames, 1.5 KB, 1.0 Mbit/s");
+        let json = serde_json::to_string(&c).unwrap();
+        assert!(!json.contains("dropped"));
+        assert_eq!(serde_json::from_str::<Counters>(&json).unwrap(), c);
+    }
+}
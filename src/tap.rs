@@ -0,0 +1,247 @@
+//! Checks of the host interfaces behind the taps. A tap is plugged without
+//! privileges when its interface is owned by the user: that is the only setup
+//! ImagiNet starts taps with. Creating a missing interface needs root, so it
+//! is done by a privileged helper and only after the user agreed to it.
+
+use anyhow::{bail, Context, Result};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+use std::{fs, process};
+
+use crate::vde::{Executables, Tap};
+
+/// Device used to attach to tun and tap interfaces
+pub const TUN_DEVICE: &str = "/dev/net/tun";
+/// Flag of `tun_flags` marking a tap, see linux/if_tun.h
+const IFF_TAP: u32 = 0x0002;
+
+/// Whether the interface of a tap can be used by the current user
+#[derive(Debug, PartialEq)]
+pub enum Access {
+    Ready,
+    Missing,
+    /// The interface exists but it is not a tap
+    NotTap,
+    /// The user is not allowed to use the interface, with the reason
+    Denied(String),
+}
+
+impl Access {
+    /// What prevents the tap from starting, if anything
+    pub fn problem(&self, interface: &str) -> Option<String> {
+        match self {
+            Access::Ready => None,
+            Access::Missing => Some(format!("{interface} does not exist")),
+            Access::NotTap => Some(format!("{interface} is not a tap interface")),
+            Access::Denied(why) => Some(why.clone()),
+        }
+    }
+}
+
+/// Parse `tun_flags`, written in hex by the kernel
+fn parse_flags(flags: &str) -> Option<u32> {
+    let flags = flags.trim();
+    u32::from_str_radix(flags.strip_prefix("0x").unwrap_or(flags), 16).ok()
+}
+
+/// Parse the owner or group of an interface, -1 when unset
+fn parse_id(id: &str) -> Option<u32> {
+    id.trim().parse().ok()
+}
+
+/// Same rule as the kernel: the owner and the group, when set, must both
+/// match the user
+fn is_allowed(owner: Option<u32>, group: Option<u32>, uid: u32, gids: &[u32]) -> bool {
+    owner.is_none_or(|o| o == uid) && group.is_none_or(|g| gids.contains(&g))
+}
+
+/// Effective user id of the process
+fn euid() -> u32 {
+    // SAFETY: geteuid has no memory safety requirements
+    unsafe { libc::geteuid() }
+}
+
+fn groups() -> Vec<u32> {
+    // SAFETY: getegid has no memory safety requirements
+    let mut gids = vec![unsafe { libc::getegid() }];
+    // SAFETY: with a size of 0 the list is not written, only counted
+    let n = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    if n > 0 {
+        let mut buf = vec![0; n as usize];
+        // SAFETY: buf holds n elements, getgroups writes at most n of them
+        let n = unsafe { libc::getgroups(n, buf.as_mut_ptr()) };
+        buf.truncate(n.max(0) as usize);
+        gids.extend(buf);
+    }
+    gids
+}
+
+/// Whether the tun device can be opened by the current user
+pub fn tun_usable() -> bool {
+    let path = std::ffi::CString::new(TUN_DEVICE).unwrap();
+    // SAFETY: path is a valid nul terminated string that outlives the call
+    unsafe { libc::access(path.as_ptr(), libc::R_OK | libc::W_OK) == 0 }
+}
+
+/// Check whether the current user can plug into an interface
+pub fn check(interface: &str) -> Access {
+    let dir = Path::new("/sys/class/net").join(interface);
+    if !dir.exists() {
+        return Access::Missing;
+    }
+    let read = |file: &str| fs::read_to_string(dir.join(file)).ok();
+
+    let flags = read("tun_flags").as_deref().and_then(parse_flags);
+    if flags.is_none_or(|f| f & IFF_TAP == 0) {
+        return Access::NotTap;
+    }
+
+    let uid = euid();
+    if uid == 0 {
+        return Access::Ready;
+    }
+    let owner = read("owner").as_deref().and_then(parse_id);
+    let group = read("group").as_deref().and_then(parse_id);
+    if !is_allowed(owner, group, uid, &groups()) {
+        return Access::Denied(format!(
+            "{interface} is not usable by user {uid} (owner {}, group {})",
+            owner.map_or("none".to_owned(), |o| o.to_string()),
+            group.map_or("none".to_owned(), |g| g.to_string()),
+        ));
+    }
+    if !tun_usable() {
+        return Access::Denied(format!("{TUN_DEVICE} is not readable and writable"));
+    }
+
+    Access::Ready
+}
+
+/// Commands creating the interface of a tap for the current user. Root
+/// does not need the privileged helper
+fn create_commands(exe: &Executables, tap: &Tap) -> Vec<Vec<String>> {
+    let uid = euid();
+    let mut commands = tap.create_commands(exe, &uid.to_string());
+    if uid == 0 {
+        for c in &mut commands {
+            c.remove(0);
+        }
+    }
+    commands
+}
+
+/// Whether creating interfaces goes through the privileged helper
+pub fn needs_helper() -> bool {
+    euid() != 0
+}
+
+/// How to solve a problem of a tap, to be run by the user
+pub fn fix(exe: &Executables, tap: &Tap, access: &Access) -> String {
+    let interface = tap.get_interface();
+    let create = create_commands(exe, tap)
+        .iter()
+        .map(|c| format!("`{}`", c.join(" ")))
+        .collect::<Vec<_>>()
+        .join(" and ");
+    match access {
+        Access::Ready => String::new(),
+        Access::Missing if tap.get_create() => {
+            format!("create it with {create}, or start the tap from a terminal to be asked")
+        }
+        Access::Missing => {
+            format!("create it with {create}, or set create on the tap to be asked at start")
+        }
+        Access::NotTap => "set the interface of the tap to a tap interface of the host".to_owned(),
+        Access::Denied(_) if !tun_usable() => {
+            format!("allow your user to read and write {TUN_DEVICE}")
+        }
+        Access::Denied(_) => format!(
+            "delete {interface} with `{}{} link delete {interface}` and run {create}",
+            if needs_helper() {
+                format!("{} ", exe.get_sudo())
+            } else {
+                String::new()
+            },
+            exe.get_ip()
+        ),
+    }
+}
+
+/// Make sure the interface of a tap is usable before starting it. A missing
+/// interface is created only if the tap allows it and the user agrees:
+/// privileges are never acquired without asking
+pub fn prepare(exe: &Executables, tap: &Tap) -> Result<()> {
+    let interface = tap.get_interface();
+    let access = check(interface);
+    match &access {
+        Access::Ready => return Ok(()),
+        Access::Missing if tap.get_create() => {}
+        _ => bail!(
+            "Tap {}: {}, fix: {}",
+            tap.get_name(),
+            access.problem(interface).unwrap_or_default(),
+            fix(exe, tap, &access)
+        ),
+    }
+
+    let commands = create_commands(exe, tap);
+    if !io::stdin().is_terminal() {
+        bail!(
+            "Tap {}: creating {interface} needs root and there is no terminal to ask for consent, fix: {}",
+            tap.get_name(),
+            fix(exe, tap, &access)
+        );
+    }
+
+    println!(
+        "Tap {} needs the interface {interface}, which does not exist.",
+        tap.get_name()
+    );
+    println!("These commands will be run with root privileges to create it:");
+    for c in &commands {
+        println!("\t{}", c.join(" "));
+    }
+    print!("Proceed? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .context("Reading the answer")?;
+    if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        bail!("Tap {}: creation of {interface} refused", tap.get_name());
+    }
+
+    for c in &commands {
+        log::debug!("Running {c:?}");
+        let status = process::Command::new(&c[0])
+            .args(&c[1..])
+            .status()
+            .context(format!("Running {}", c[0]))?;
+        if !status.success() {
+            bail!("Tap {}: `{}` failed", tap.get_name(), c.join(" "));
+        }
+    }
+
+    if let Some(problem) = check(interface).problem(interface) {
+        bail!("Tap {}: {problem} after creating it", tap.get_name());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owner_rules() {
+        assert_eq!(parse_flags("0x1002\n"), Some(0x1002));
+        assert_eq!(parse_id("-1\n"), None);
+        assert_eq!(parse_id("1000"), Some(1000));
+
+        assert!(is_allowed(None, None, 1000, &[1000]));
+        assert!(is_allowed(Some(1000), None, 1000, &[1000]));
+        assert!(!is_allowed(Some(0), None, 1000, &[1000]));
+        assert!(is_allowed(None, Some(27), 1000, &[1000, 27]));
+        assert!(!is_allowed(Some(1000), Some(27), 1000, &[1000]));
+    }
+}
@@ -1,28 +1,118 @@
 use anyhow::{Context, Ok, Result};
-pub use cable::Cable;
+pub use cable::{Cable, Endpoint};
 use core::fmt;
+pub use executables::Executables;
+pub use impairments::{
+    parse_delay, parse_percentage, parse_rate, Direction, FlatParams, Impairments, LinkParams,
+};
 pub use namespace::{NSInterface, Namespace};
+pub use netnode::{Netnode, NetnodeKind};
 use serde::{Deserialize, Serialize};
 pub use slirp::Slirp;
-pub use switch::Switch;
+pub use switch::{Switch, Vlan};
+pub use tap::Tap;
 pub use vxvde::VXVDE;
 
 mod cable;
+mod diff;
+mod executables;
+mod impairments;
 mod namespace;
+mod netnode;
 mod slirp;
 mod switch;
+mod tap;
 mod vxvde;
 
 const PID_FILE_NAME: &str = "pid";
 const CONF_FILE_NAME: &str = "config";
 const MGMT_FILE_NAME: &str = "mgmt";
 const SOCK_FILE_NAME: &str = "sock";
+const IMPAIRMENTS_FILE_NAME: &str = "impairments";
+const DOWN_FILE_NAME: &str = "down";
+const CAPTURE_SOCK_FILE_NAME: &str = "capture.sock";
+const CAPTURE_FILE_EXTENSION: &str = "pcap";
+const CONTROL_FILE_NAME: &str = "control";
 pub const OPEN_DIR_NAME: &str = "opn";
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum VdeConnProtocols {
     VDE,
     PTP,
+    /// A vdeplug URI outside of the topology, used as it is
+    URI,
+}
+
+/// Schemes of the vdeplug4 plugins that can be used as cable endpoints
+const URI_SCHEMES: &[&str] = &[
+    "vde",
+    "ptp",
+    "udp",
+    "vxlan",
+    "vxvde",
+    "tap",
+    "macvtap",
+    "cmd",
+    "seqpacket",
+    "null",
+    "hub",
+    "switch",
+    "multi",
+    "bundling",
+    "slirp",
+    "vlan",
+    "agno",
+    "pcap",
+];
+
+/// Schemes that don't need anything after `://`
+const BARE_SCHEMES: &[&str] = &["null", "slirp"];
+
+/// Schemes of the vdeplug4 plugins that can be nested around an endpoint
+const PLUGIN_SCHEMES: &[&str] = &["vlan", "agno"];
+
+/// Check a plugin nested around an endpoint, i.e. `vlan://20`. The nested
+/// URI is added by imaginet
+pub fn check_plugin(plugin: &str) -> Result<()> {
+    let (scheme, rest) = plugin
+        .split_once("://")
+        .ok_or_else(|| anyhow::anyhow!("{plugin} is not a vdeplug URI"))?;
+    if !PLUGIN_SCHEMES.contains(&scheme) {
+        anyhow::bail!(
+            "The vdeplug scheme {scheme}:// can't be nested, use one of: {}",
+            PLUGIN_SCHEMES.join(", ")
+        );
+    }
+    if rest.contains(['{', '}']) {
+        anyhow::bail!("{plugin} must not contain the nested URI");
+    }
+    if scheme == "vlan" && rest.is_empty() {
+        anyhow::bail!("Missing the vlan tag in {plugin}");
+    }
+    Ok(())
+}
+
+/// Tell if an endpoint is a vdeplug URI, i.e. `udp://...`, instead of the
+/// name of a device
+pub fn is_uri(endpoint: &str) -> bool {
+    endpoint.contains("://")
+}
+
+/// Check that a vdeplug URI uses a known plugin
+pub fn check_uri(uri: &str) -> Result<()> {
+    let (scheme, rest) = uri
+        .split_once("://")
+        .ok_or_else(|| anyhow::anyhow!("{uri} is not a vdeplug URI"))?;
+    if !URI_SCHEMES.contains(&scheme) {
+        anyhow::bail!(
+            "Unknown vdeplug scheme {scheme}:// in {uri}, use one of: {}",
+            URI_SCHEMES.join(", ")
+        );
+    }
+    if rest.is_empty() && !BARE_SCHEMES.contains(&scheme) {
+        anyhow::bail!("Missing the address in {uri}");
+    }
+    Ok(())
 }
 
 impl fmt::Display for VdeConnProtocols {
@@ -31,6 +121,30 @@ impl fmt::Display for VdeConnProtocols {
     }
 }
 
+/// Implementation of a device: the vde tools, or imaginet itself
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    #[default]
+    Vde,
+    Native,
+}
+
+impl Engine {
+    pub fn is_default(&self) -> bool {
+        *self == Engine::default()
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Engine::Vde => write!(f, "vde"),
+            Engine::Native => write!(f, "native"),
+        }
+    }
+}
+
 /// A vde topology is a struct that contains all the necessary
 /// information to create a network topology based on VDE
 #[derive(Debug, Serialize, Deserialize)]
@@ -40,6 +154,14 @@ pub struct Topology {
     cables: Vec<Cable>,
     slirps: Vec<Slirp>,
     vxvdes: Vec<VXVDE>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    netnodes: Vec<Netnode>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    taps: Vec<Tap>,
+    /// Executables of this topology, they take precedence over the ones of
+    /// the global configuration
+    #[serde(default, skip_serializing_if = "Executables::is_empty")]
+    executables: Executables,
 }
 
 impl Topology {
@@ -50,6 +172,9 @@ impl Topology {
             cables: Vec::new(),
             slirps: Vec::new(),
             vxvdes: Vec::new(),
+            netnodes: Vec::new(),
+            taps: Vec::new(),
+            executables: Executables::default(),
         }
     }
 
@@ -84,6 +209,18 @@ impl Topology {
             }
         }
 
+        for nn in &self.netnodes {
+            if nn.get_name() == name {
+                return true;
+            }
+        }
+
+        for tap in &self.taps {
+            if tap.get_name() == name {
+                return true;
+            }
+        }
+
         false
     }
 
@@ -132,6 +269,24 @@ impl Topology {
         Ok(())
     }
 
+    pub fn add_netnode(&mut self, nn: Netnode) -> Result<()> {
+        if self.is_name_used(nn.get_name()) {
+            anyhow::bail!("Name already used");
+        }
+        self.netnodes.push(nn);
+
+        Ok(())
+    }
+
+    pub fn add_tap(&mut self, tap: Tap) -> Result<()> {
+        if self.is_name_used(tap.get_name()) {
+            anyhow::bail!("Name already used");
+        }
+        self.taps.push(tap);
+
+        Ok(())
+    }
+
     pub fn get_switches(&self) -> &Vec<Switch> {
         &self.switches
     }
@@ -144,6 +299,10 @@ impl Topology {
         &self.cables
     }
 
+    pub fn get_cable_mut(&mut self, name: &str) -> Option<&mut Cable> {
+        self.cables.iter_mut().find(|c| c.get_name() == name)
+    }
+
     pub fn get_slirps(&self) -> &Vec<Slirp> {
         &self.slirps
     }
@@ -152,6 +311,35 @@ impl Topology {
         &self.vxvdes
     }
 
+    pub fn get_netnodes(&self) -> &Vec<Netnode> {
+        &self.netnodes
+    }
+
+    pub fn get_taps(&self) -> &Vec<Tap> {
+        &self.taps
+    }
+
+    pub fn get_executables(&self) -> &Executables {
+        &self.executables
+    }
+
+    pub fn set_executables(&mut self, executables: Executables) {
+        self.executables = executables;
+    }
+
+    /// Names of all the devices in the topology
+    pub fn get_device_names(&self) -> Vec<String> {
+        let mut v = Vec::new();
+        v.extend(self.switches.iter().map(|x| x.get_name().to_owned()));
+        v.extend(self.namespaces.iter().map(|x| x.get_name().to_owned()));
+        v.extend(self.cables.iter().map(|x| x.get_name().to_owned()));
+        v.extend(self.slirps.iter().map(|x| x.get_name().to_owned()));
+        v.extend(self.vxvdes.iter().map(|x| x.get_name().to_owned()));
+        v.extend(self.netnodes.iter().map(|x| x.get_name().to_owned()));
+        v.extend(self.taps.iter().map(|x| x.get_name().to_owned()));
+        v
+    }
+
     pub fn remove_device(&mut self, name: &String) -> Result<()> {
         self.check_dependecy(name)?;
 
@@ -170,6 +358,26 @@ impl Topology {
             return Ok(());
         };
 
+        if let Some(pos) = self.slirps.iter().position(|x| x.get_name() == name) {
+            self.slirps.remove(pos);
+            return Ok(());
+        };
+
+        if let Some(pos) = self.vxvdes.iter().position(|x| x.get_name() == name) {
+            self.vxvdes.remove(pos);
+            return Ok(());
+        };
+
+        if let Some(pos) = self.netnodes.iter().position(|x| x.get_name() == name) {
+            self.netnodes.remove(pos);
+            return Ok(());
+        };
+
+        if let Some(pos) = self.taps.iter().position(|x| x.get_name() == name) {
+            self.taps.remove(pos);
+            return Ok(());
+        };
+
         Ok(())
     }
 
@@ -190,7 +398,7 @@ impl Topology {
     }
 
     pub fn from_string(file: &str) -> Result<Topology> {
-        serde_yaml::from_str(&file)
+        serde_yaml::from_str(file)
             .map_err(anyhow::Error::new)
             .context("Parsing topology file")
     }
@@ -202,6 +410,17 @@ pub fn find_endpoint_path(
     port: Option<&String>,
     open: Option<bool>,
 ) -> Result<String> {
+    if is_uri(name) {
+        if open == Some(true) {
+            anyhow::bail!("The vdeplug URI {name} can't be an open endpoint");
+        }
+        if port.is_some() {
+            anyhow::bail!("The vdeplug URI {name} has no ports");
+        }
+        check_uri(name)?;
+        return Ok(name.to_owned());
+    }
+
     if open == Some(true) {
         return Ok(format!("{OPEN_DIR_NAME}/{name}"));
     }
@@ -224,6 +443,18 @@ pub fn find_endpoint_path(
         }
     }
 
+    for nn in t.get_netnodes() {
+        if nn.get_name() == name {
+            return Ok(nn.sock_path("."));
+        }
+    }
+
+    for tap in t.get_taps() {
+        if tap.get_name() == name {
+            return Ok(tap.conn_path("."));
+        }
+    }
+
     // For the namespaces the port must be defined
     let port =
         port.ok_or_else(|| anyhow::anyhow!("Port is not defined and namespaces requires it"))?;
@@ -238,6 +469,10 @@ pub fn find_endpoint_path(
 }
 
 pub fn find_endpoint_protocol(t: &Topology, name: &str) -> Result<VdeConnProtocols> {
+    if is_uri(name) {
+        return Ok(VdeConnProtocols::URI);
+    }
+
     for sw in t.get_switches() {
         if sw.get_name() == name {
             return Ok(VdeConnProtocols::VDE);
@@ -262,5 +497,45 @@ pub fn find_endpoint_protocol(t: &Topology, name: &str) -> Result<VdeConnProtoco
         }
     }
 
+    for nn in t.get_netnodes() {
+        if nn.get_name() == name {
+            return Ok(VdeConnProtocols::VDE);
+        }
+    }
+
+    for tap in t.get_taps() {
+        if tap.get_name() == name {
+            return Ok(VdeConnProtocols::PTP);
+        }
+    }
+
     panic!("Endpoint not found");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uri_endpoints() {
+        assert!(is_uri("udp://10.0.0.1:5000"));
+        assert!(!is_uri("sw1"));
+        check_uri("udp://10.0.0.1:5000").unwrap();
+        check_uri("slirp://").unwrap();
+        assert!(check_uri("tap://").is_err());
+        assert!(check_uri("http://example.org").is_err());
+        check_plugin("vlan://20").unwrap();
+        check_plugin("agno://").unwrap();
+        assert!(check_plugin("vlan://").is_err());
+        assert!(check_plugin("udp://10.0.0.1:5000").is_err());
+        assert!(check_plugin("agno://{vde:///tmp/sw}").is_err());
+
+        let t = Topology::new();
+        let uri = "cmd://ssh host vde_plug".to_owned();
+        assert_eq!(find_endpoint_path(&t, &uri, None, None).unwrap(), uri);
+        assert_eq!(
+            find_endpoint_protocol(&t, &uri).unwrap(),
+            VdeConnProtocols::URI
+        );
+    }
+}
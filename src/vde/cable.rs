@@ -1,18 +1,38 @@
-use super::{VdeConnProtocols, CONF_FILE_NAME, MGMT_FILE_NAME, PID_FILE_NAME};
+use super::{
+    Engine, Executables, Impairments, VdeConnProtocols, CAPTURE_FILE_EXTENSION,
+    CAPTURE_SOCK_FILE_NAME, CONF_FILE_NAME, CONTROL_FILE_NAME, DOWN_FILE_NAME,
+    IMPAIRMENTS_FILE_NAME, MGMT_FILE_NAME, PID_FILE_NAME,
+};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Cable {
     pub name: String,
     pub a: Endpoint,
     pub b: Endpoint,
     pub wirefilter: bool,
     pub config: Vec<String>,
+    /// Arguments appended to each vde_plug of the cable
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra_args: Vec<String>,
+    /// Arguments appended to wirefilter
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    wirefilter_extra_args: Vec<String>,
+    /// Impairments applied by wirefilter when it starts
+    #[serde(default, skip_serializing_if = "Impairments::is_empty")]
+    impairments: Impairments,
+    /// Record the frames crossing the cable from when it starts
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    capture: bool,
+    /// A native cable is a relay run by imaginet, which applies the
+    /// impairments itself in place of wirefilter
+    #[serde(default, skip_serializing_if = "Engine::is_default")]
+    engine: Engine,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Endpoint {
     name: String,
     port: Option<String>,
@@ -22,6 +42,10 @@ pub struct Endpoint {
     /// in the VDE topology file, but it creates a PTP connection that can
     /// be manually used to connect external devices.
     open: bool,
+    /// vdeplug plugins wrapped around the endpoint, i.e. `vlan://20`. The
+    /// first one is the outermost, the first to see the frames sent
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    plugins: Vec<String>,
 }
 
 impl Endpoint {
@@ -31,6 +55,7 @@ impl Endpoint {
             port,
             protocol,
             open,
+            plugins: Vec::new(),
         }
     }
 
@@ -49,9 +74,40 @@ impl Endpoint {
     pub fn get_open(&self) -> bool {
         self.open
     }
+
+    pub fn get_plugins(&self) -> &Vec<String> {
+        &self.plugins
+    }
+
+    /// Get the vdeplug URI of the endpoint given the runtime directory,
+    /// nested in its plugins
+    pub fn uri(&self, base: &str) -> String {
+        let path = PathBuf::from(base)
+            .join(&self.name)
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let mut uri = match self.protocol {
+            VdeConnProtocols::VDE => format!("vde://{path}"),
+            VdeConnProtocols::PTP => format!("ptp://{path}"),
+            VdeConnProtocols::URI => self.name.clone(),
+        };
+
+        if let Some(port) = &self.port {
+            if self.protocol == VdeConnProtocols::VDE {
+                uri.push_str(&format!("[{port}]"));
+            }
+        }
+
+        for plugin in self.plugins.iter().rev() {
+            uri = format!("{plugin}{{{uri}}}");
+        }
+        uri
+    }
 }
 
 impl Cable {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         name: String,
         a: String,
@@ -70,6 +126,11 @@ impl Cable {
             b: Endpoint::new(b, port_b, protocol_b, open_b),
             wirefilter: wirefilter.unwrap_or(false),
             config: Vec::new(),
+            extra_args: Vec::new(),
+            wirefilter_extra_args: Vec::new(),
+            impairments: Impairments::default(),
+            capture: false,
+            engine: Engine::default(),
         }
     }
 
@@ -77,8 +138,37 @@ impl Cable {
         &self.name
     }
 
+    /// Set the plugins wrapped around the endpoints A and B
+    pub fn set_plugins(&mut self, a: Vec<String>, b: Vec<String>) {
+        self.a.plugins = a;
+        self.b.plugins = b;
+    }
+
+    pub fn get_extra_args(&self) -> &Vec<String> {
+        &self.extra_args
+    }
+
+    pub fn set_extra_args(&mut self, args: Vec<String>) {
+        self.extra_args = args;
+    }
+
+    pub fn get_wirefilter_extra_args(&self) -> &Vec<String> {
+        &self.wirefilter_extra_args
+    }
+
+    pub fn set_wirefilter_extra_args(&mut self, args: Vec<String>) {
+        self.wirefilter_extra_args = args;
+    }
+
+    /// Only cables started with vde use wirefilter, native cables apply
+    /// the impairments themselves
     pub fn has_wirefilter(&self) -> bool {
-        self.wirefilter
+        self.wirefilter && self.engine == Engine::Vde
+    }
+
+    /// Whether the impairments of the cable can be changed while it runs
+    pub fn can_impair(&self) -> bool {
+        self.has_wirefilter() || self.engine == Engine::Native
     }
 
     pub fn add_config(&mut self, config: String) {
@@ -97,8 +187,49 @@ impl Cable {
         &self.b
     }
 
+    pub fn get_impairments(&self) -> &Impairments {
+        &self.impairments
+    }
+
+    pub fn set_impairments(&mut self, impairments: Impairments) {
+        self.impairments = impairments;
+    }
+
+    pub fn get_capture(&self) -> bool {
+        self.capture
+    }
+
+    pub fn set_capture(&mut self, capture: bool) {
+        self.capture = capture;
+    }
+
+    pub fn get_engine(&self) -> Engine {
+        self.engine
+    }
+
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    /// Both the endpoints, as shown in captures
+    pub fn description(&self) -> String {
+        let endpoint = |e: &Endpoint| match e.get_port() {
+            Some(port) => format!("{}[{port}]", e.get_name()),
+            None => e.get_name().to_owned(),
+        };
+        format!("{} <-> {}", endpoint(&self.a), endpoint(&self.b))
+    }
+
     pub fn needs_config(&self) -> bool {
-        !self.config.is_empty()
+        !self.config.is_empty() || !self.impairments.is_empty()
+    }
+
+    /// Commands executed by wirefilter when it starts: the ones that apply
+    /// the impairments, followed by the configuration
+    pub fn rc_lines(&self) -> Vec<String> {
+        let mut lines = self.impairments.rc_lines();
+        lines.extend(self.config.iter().cloned());
+        lines
     }
 
     pub fn base_path(&self, base: &str) -> String {
@@ -118,7 +249,7 @@ impl Cable {
     }
 
     pub fn mgmt_path(&self, base: &str) -> Result<String> {
-        if !self.wirefilter {
+        if !self.has_wirefilter() {
             return Err(anyhow::anyhow!(
                 "No wirefilter cable. Can't have a management file"
             ));
@@ -138,65 +269,132 @@ impl Cable {
             .to_owned()
     }
 
-    pub fn exec_command(&self) -> String {
-        if self.wirefilter {
-            String::from("dpipe")
-        } else {
-            String::from("vde_plug")
-        }
+    /// Path of the control socket of a native cable
+    pub fn control_path(&self, base: &str) -> String {
+        PathBuf::from(self.base_path(base))
+            .join(CONTROL_FILE_NAME)
+            .to_str()
+            .unwrap()
+            .to_owned()
     }
 
-    pub fn exec_args(&self, base: &str) -> Vec<String> {
-        let b = PathBuf::from(base);
-        let mut pa = b.join(&self.a.get_name()).to_str().unwrap().to_owned();
-        let mut pb = b.join(&self.b.get_name()).to_str().unwrap().to_owned();
+    /// Path of the file holding the impairments changed while the cable
+    /// is running
+    pub fn impairments_path(&self, base: &str) -> String {
+        PathBuf::from(self.base_path(base))
+            .join(IMPAIRMENTS_FILE_NAME)
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
 
-        pa = match *self.a.get_protocol() {
-            VdeConnProtocols::VDE => format!("vde://{pa}"),
-            VdeConnProtocols::PTP => format!("ptp://{pa}"),
-        };
+    /// Path of the control socket of the capture stage
+    pub fn capture_socket_path(&self, base: &str) -> String {
+        PathBuf::from(self.base_path(base))
+            .join(CAPTURE_SOCK_FILE_NAME)
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
 
-        pb = match *self.b.get_protocol() {
-            VdeConnProtocols::VDE => format!("vde://{pb}"),
-            VdeConnProtocols::PTP => format!("ptp://{pb}"),
-        };
+    /// Path of the file in which frames are recorded by default, named after
+    /// the cable in the directory of the recordings
+    pub fn capture_path(&self, captures: &str) -> String {
+        PathBuf::from(captures)
+            .join(format!("{}.{CAPTURE_FILE_EXTENSION}", self.name))
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
 
-        if let Some(port) = &self.a.get_port() {
-            if *self.a.get_protocol() == VdeConnProtocols::VDE {
-                pa.push_str(&format!("[{port}]"));
-            }
-        }
+    /// Path of the file marking the cable as administratively down
+    pub fn down_path(&self, base: &str) -> String {
+        PathBuf::from(self.base_path(base))
+            .join(DOWN_FILE_NAME)
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
 
-        if let Some(port) = &self.b.get_port() {
-            if *self.b.get_protocol() == VdeConnProtocols::VDE {
-                pb.push_str(&format!("[{port}]"));
-            }
+    pub fn exec_command(&self, exe: &Executables) -> String {
+        if self.engine == Engine::Native {
+            exe.get_imaginet()
+        } else if self.wirefilter || self.capture {
+            exe.get_dpipe()
+        } else {
+            exe.get_vde_plug()
         }
+    }
 
-        let pid_p = self.pid_path(base);
+    /// Arguments of the command starting the cable. A capture stage records
+    /// into `output`, if given, or it only forwards the frames
+    pub fn exec_args(&self, base: &str, exe: &Executables, output: Option<&str>) -> Vec<String> {
+        let pa = self.a.uri(base);
+        let pb = self.b.uri(base);
 
-        if self.wirefilter {
-            let mgmt_p = self.mgmt_path(base).unwrap();
-            let conf_p = self.config_path(base);
+        let pid_p = self.pid_path(base);
 
-            vec![
+        if self.engine == Engine::Native {
+            let mut args = vec![
+                "__cable".to_owned(),
+                "--name".to_owned(),
+                self.name.clone(),
+                "--a".to_owned(),
+                pa,
+                "--b".to_owned(),
+                pb,
+                "--dir".to_owned(),
+                self.base_path(base),
+                "--control".to_owned(),
+                self.control_path(base),
+                "--pidfile".to_owned(),
+                pid_p,
+            ];
+            if self.needs_config() {
+                args.extend(["--rcfile".to_owned(), self.config_path(base)]);
+            }
+            args
+        } else if self.wirefilter || self.capture {
+            let mut args = vec![
                 "--daemon".to_owned(),
                 "--pidfile".to_owned(),
                 pid_p,
-                "vde_plug".to_owned(),
+                exe.get_vde_plug(),
                 pa,
-                "=".to_owned(),
-                "wirefilter".to_owned(),
-                "--mgmt".to_owned(),
-                mgmt_p,
-                "--rcfile".to_owned(),
-                conf_p,
-                "=".to_owned(),
-                "vde_plug".to_owned(),
-                pb,
-            ]
+            ];
+            args.extend(self.extra_args.iter().cloned());
+            if self.capture {
+                args.extend([
+                    "=".to_owned(),
+                    exe.get_imaginet(),
+                    "__capture".to_owned(),
+                    "--name".to_owned(),
+                    self.name.clone(),
+                    "--description".to_owned(),
+                    self.description(),
+                    "--socket".to_owned(),
+                    self.capture_socket_path(base),
+                ]);
+                if let Some(output) = output {
+                    args.extend(["--output".to_owned(), output.to_owned()]);
+                }
+            }
+            if self.wirefilter {
+                args.extend([
+                    "=".to_owned(),
+                    exe.get_wirefilter(),
+                    "--mgmt".to_owned(),
+                    self.mgmt_path(base).unwrap(),
+                    "--rcfile".to_owned(),
+                    self.config_path(base),
+                ]);
+                args.extend(self.wirefilter_extra_args.iter().cloned());
+            }
+            args.extend(["=".to_owned(), exe.get_vde_plug(), pb]);
+            args.extend(self.extra_args.iter().cloned());
+            args
         } else {
-            vec![
+            let mut args = vec![
                 pa,
                 pb,
                 "--pidfile".to_owned(),
@@ -204,13 +402,15 @@ impl Cable {
                 "--descr".to_owned(),
                 self.name.to_owned(),
                 "--daemon".to_owned(),
-            ]
+            ];
+            args.extend(self.extra_args.iter().cloned());
+            args
         }
     }
 
-    pub fn attach_command(&self) -> Result<String> {
-        if self.wirefilter {
-            Ok(String::from("vdeterm"))
+    pub fn attach_command(&self, exe: &Executables) -> Result<String> {
+        if self.has_wirefilter() {
+            Ok(exe.get_vdeterm())
         } else {
             Err(anyhow::anyhow!(
                 "Simple cable (no wirefilter) can't be attached"
@@ -219,7 +419,7 @@ impl Cable {
     }
 
     pub fn attach_args(&self, base: &str) -> Result<Vec<String>> {
-        if self.wirefilter {
+        if self.has_wirefilter() {
             let socke_p = self.mgmt_path(base)?;
             Ok(vec![socke_p])
         } else {
@@ -232,9 +432,9 @@ impl Cable {
     /// Returns the command to execute in order to execute a command
     /// inside the switch. This is different from exec_command in which the
     /// command returned is used to start the switch
-    pub fn exec_command_command(&self) -> Result<String> {
-        if self.wirefilter {
-            Ok(String::from("vdecmd"))
+    pub fn exec_command_command(&self, exe: &Executables) -> Result<String> {
+        if self.has_wirefilter() {
+            Ok(exe.get_vdecmd())
         } else {
             Err(anyhow::anyhow!(
                 "Simple cable (no wirefilter) can't be attached"
@@ -247,7 +447,7 @@ impl Cable {
     /// returned are used to start the switch. This function is used with
     /// the exec_command_command function
     pub fn exec_command_args(&self, base: &str, command: &mut Vec<String>) -> Result<Vec<String>> {
-        if !self.wirefilter {
+        if !self.has_wirefilter() {
             return Err(anyhow::anyhow!(
                 "Simple cable (no wirefilter) can't be attached"
             ));
@@ -256,6 +456,6 @@ impl Cable {
         let mut args = vec!["-s".to_owned(), self.mgmt_path(base)?];
         args.append(command);
 
-        return Ok(args);
+        Ok(args)
     }
 }
@@ -0,0 +1,227 @@
+use super::{Cable, Namespace, Netnode, Slirp, Switch, Tap, Topology, VXVDE};
+
+/// Borrowed view over any device of a topology. It is only used to compare
+/// devices with the same name across two topologies
+#[derive(Debug, PartialEq)]
+enum Device<'a> {
+    Switch(&'a Switch),
+    Namespace(&'a Namespace),
+    Cable(&'a Cable),
+    Slirp(&'a Slirp),
+    VXVDE(&'a VXVDE),
+    Netnode(&'a Netnode),
+    Tap(&'a Tap),
+}
+
+impl Device<'_> {
+    fn kind(&self) -> &'static str {
+        match self {
+            Device::Switch(_) => "switch",
+            Device::Namespace(_) => "namespace",
+            Device::Cable(_) => "cable",
+            Device::Slirp(_) => "slirp",
+            Device::VXVDE(_) => "vxvde",
+            Device::Netnode(_) => "netnode",
+            Device::Tap(_) => "tap",
+        }
+    }
+}
+
+/// A single entry of a topology diff: the kind of the device and its name
+#[derive(Debug, PartialEq)]
+pub struct DiffEntry {
+    pub kind: &'static str,
+    pub name: String,
+}
+
+/// The set of changes needed to go from one topology to another.
+#[derive(Debug, Default, PartialEq)]
+pub struct TopologyDiff {
+    /// Devices only present in the new topology
+    pub added: Vec<DiffEntry>,
+    /// Devices only present in the old topology
+    pub removed: Vec<DiffEntry>,
+    /// Devices present in both topologies but with a different definition
+    pub changed: Vec<DiffEntry>,
+    /// Cables that did not change, but are connected to a device that
+    /// changed. They must be restarted in order to be reconnected
+    pub rewired: Vec<DiffEntry>,
+}
+
+impl TopologyDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.rewired.is_empty()
+    }
+}
+
+impl Topology {
+    fn devices(&self) -> Vec<(&String, Device<'_>)> {
+        let mut v = Vec::new();
+        v.extend(
+            self.switches
+                .iter()
+                .map(|d| (d.get_name(), Device::Switch(d))),
+        );
+        v.extend(
+            self.namespaces
+                .iter()
+                .map(|d| (d.get_name(), Device::Namespace(d))),
+        );
+        v.extend(self.cables.iter().map(|d| (d.get_name(), Device::Cable(d))));
+        v.extend(self.slirps.iter().map(|d| (d.get_name(), Device::Slirp(d))));
+        v.extend(self.vxvdes.iter().map(|d| (d.get_name(), Device::VXVDE(d))));
+        v.extend(
+            self.netnodes
+                .iter()
+                .map(|d| (d.get_name(), Device::Netnode(d))),
+        );
+        v.extend(self.taps.iter().map(|d| (d.get_name(), Device::Tap(d))));
+        v
+    }
+
+    /// Compute the changes needed to transform this topology into `new`
+    pub fn diff(&self, new: &Topology) -> TopologyDiff {
+        let mut diff = TopologyDiff::default();
+
+        let old_devices = self.devices();
+        let new_devices = new.devices();
+
+        for (name, dev) in &old_devices {
+            match new_devices.iter().find(|(n, _)| n == name) {
+                None => diff.removed.push(DiffEntry {
+                    kind: dev.kind(),
+                    name: name.to_string(),
+                }),
+                Some((_, new_dev)) => {
+                    if dev != new_dev {
+                        diff.changed.push(DiffEntry {
+                            kind: new_dev.kind(),
+                            name: name.to_string(),
+                        })
+                    }
+                }
+            }
+        }
+
+        for (name, dev) in &new_devices {
+            if !old_devices.iter().any(|(n, _)| n == name) {
+                diff.added.push(DiffEntry {
+                    kind: dev.kind(),
+                    name: name.to_string(),
+                });
+            }
+        }
+
+        // An unchanged cable must be reconnected if one of its endpoints is
+        // restarted. Endpoints names are paths relative to the working
+        // directory, so the first component is the device name
+        for con in &new.cables {
+            if diff.changed.iter().any(|e| &e.name == con.get_name()) {
+                continue;
+            }
+            if !self.cables.iter().any(|c| c.get_name() == con.get_name()) {
+                continue;
+            }
+
+            let touches = |endp: &String| {
+                endp.split('/')
+                    .any(|x| diff.changed.iter().any(|e| e.name == x))
+            };
+            if touches(con.get_a().get_name()) || touches(con.get_b().get_name()) {
+                diff.rewired.push(DiffEntry {
+                    kind: "cable",
+                    name: con.get_name().to_owned(),
+                });
+            }
+        }
+
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vde::{NSInterface, VdeConnProtocols};
+
+    fn base_topology() -> Topology {
+        let mut t = Topology::new();
+        t.add_switch(Switch::new("sw1".to_owned())).unwrap();
+
+        let mut ns = Namespace::new("ns1".to_owned());
+        ns.add_interface(NSInterface::new("eth0".to_owned(), None, None));
+        t.add_namespace(ns).unwrap();
+
+        t.add_cable(Cable::new(
+            "conn1".to_owned(),
+            "ns1/eth0".to_owned(),
+            Some("eth0".to_owned()),
+            VdeConnProtocols::PTP,
+            false,
+            "sw1/sock".to_owned(),
+            None,
+            VdeConnProtocols::VDE,
+            false,
+            None,
+        ))
+        .unwrap();
+        t
+    }
+
+    #[test]
+    fn diff_same_topology_is_empty() {
+        let t = base_topology();
+        assert!(t.diff(&base_topology()).is_empty());
+    }
+
+    #[test]
+    fn diff_added_and_removed() {
+        let old = base_topology();
+        let mut new = base_topology();
+        new.add_switch(Switch::new("sw2".to_owned())).unwrap();
+        new.remove_device(&"conn1".to_owned()).unwrap();
+
+        let d = old.diff(&new);
+        assert_eq!(
+            d.added,
+            vec![DiffEntry {
+                kind: "switch",
+                name: "sw2".to_owned()
+            }]
+        );
+        assert_eq!(
+            d.removed,
+            vec![DiffEntry {
+                kind: "cable",
+                name: "conn1".to_owned()
+            }]
+        );
+        assert!(d.changed.is_empty());
+    }
+
+    #[test]
+    fn diff_changed_device_rewires_cables() {
+        let old = base_topology();
+        let mut new = base_topology();
+        new.switches[0].set_ports(8);
+
+        let d = old.diff(&new);
+        assert_eq!(
+            d.changed,
+            vec![DiffEntry {
+                kind: "switch",
+                name: "sw1".to_owned()
+            }]
+        );
+        assert_eq!(
+            d.rewired,
+            vec![DiffEntry {
+                kind: "cable",
+                name: "conn1".to_owned()
+            }]
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Paths of the executables used to run the devices. A missing path means
+/// that the executable is searched in the PATH with its default name
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Executables {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vde_switch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vde_plug: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vdens: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nsenter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vdeterm: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vdecmd: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dpipe: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wirefilter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wireshark: Option<String>,
+    /// ImagiNet itself, used by the capture stage of the cables
+    #[serde(skip_serializing_if = "Option::is_none")]
+    imaginet: Option<String>,
+    /// Helper that runs a command as root, used to create the taps
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sudo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+}
+
+fn or_default(path: &Option<String>, default: &str) -> String {
+    path.clone().unwrap_or_else(|| default.to_owned())
+}
+
+impl Executables {
+    pub fn get_vde_switch(&self) -> String {
+        or_default(&self.vde_switch, "vde_switch")
+    }
+
+    pub fn get_vde_plug(&self) -> String {
+        or_default(&self.vde_plug, "vde_plug")
+    }
+
+    pub fn get_vdens(&self) -> String {
+        or_default(&self.vdens, "vdens")
+    }
+
+    pub fn get_nsenter(&self) -> String {
+        or_default(&self.nsenter, "nsenter")
+    }
+
+    pub fn get_vdeterm(&self) -> String {
+        or_default(&self.vdeterm, "vdeterm")
+    }
+
+    pub fn get_vdecmd(&self) -> String {
+        or_default(&self.vdecmd, "vdecmd")
+    }
+
+    pub fn get_dpipe(&self) -> String {
+        or_default(&self.dpipe, "dpipe")
+    }
+
+    pub fn get_wirefilter(&self) -> String {
+        or_default(&self.wirefilter, "wirefilter")
+    }
+
+    pub fn get_wireshark(&self) -> String {
+        or_default(&self.wireshark, "wireshark")
+    }
+
+    pub fn get_sudo(&self) -> String {
+        or_default(&self.sudo, "sudo")
+    }
+
+    pub fn get_ip(&self) -> String {
+        or_default(&self.ip, "ip")
+    }
+
+    /// Defaults to the running executable, so that the capture stage
+    /// matches the version that starts it
+    pub fn get_imaginet(&self) -> String {
+        self.imaginet.clone().unwrap_or_else(|| {
+            std::env::current_exe()
+                .ok()
+                .and_then(|p| p.to_str().map(|s| s.to_owned()))
+                .unwrap_or_else(|| "imaginet".to_owned())
+        })
+    }
+
+    /// Make the relative paths (the ones with a `/`) absolute, relative to
+    /// `dir`. Bare names are still searched in the PATH
+    pub fn resolve_paths(&mut self, dir: &Path) -> Result<()> {
+        for path in [
+            &mut self.vde_switch,
+            &mut self.vde_plug,
+            &mut self.vdens,
+            &mut self.nsenter,
+            &mut self.vdeterm,
+            &mut self.vdecmd,
+            &mut self.dpipe,
+            &mut self.wirefilter,
+            &mut self.wireshark,
+            &mut self.imaginet,
+            &mut self.sudo,
+            &mut self.ip,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if path.contains('/') && Path::new(path).is_relative() {
+                let abs = std::path::absolute(dir.join(&path))
+                    .context(format!("Resolving executable path {path}"))?;
+                *path = abs
+                    .to_str()
+                    .context(format!("Can't convert path to string: {abs:?}"))?
+                    .to_owned();
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Executables::default()
+    }
+
+    /// Returns a copy of these executables, where the paths set in `over`
+    /// take precedence
+    pub fn merge(&self, over: &Executables) -> Executables {
+        let pick = |a: &Option<String>, b: &Option<String>| b.clone().or_else(|| a.clone());
+        Executables {
+            vde_switch: pick(&self.vde_switch, &over.vde_switch),
+            vde_plug: pick(&self.vde_plug, &over.vde_plug),
+            vdens: pick(&self.vdens, &over.vdens),
+            nsenter: pick(&self.nsenter, &over.nsenter),
+            vdeterm: pick(&self.vdeterm, &over.vdeterm),
+            vdecmd: pick(&self.vdecmd, &over.vdecmd),
+            dpipe: pick(&self.dpipe, &over.dpipe),
+            wirefilter: pick(&self.wirefilter, &over.wirefilter),
+            wireshark: pick(&self.wireshark, &over.wireshark),
+            imaginet: pick(&self.imaginet, &over.imaginet),
+            sudo: pick(&self.sudo, &over.sudo),
+            ip: pick(&self.ip, &over.ip),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn executables_defaults() {
+        let e = Executables::default();
+        assert_eq!(e.get_vde_switch(), "vde_switch");
+        assert_eq!(e.get_wirefilter(), "wirefilter");
+        assert!(e.is_empty());
+    }
+
+    #[test]
+    fn executables_merge() {
+        let global: Executables =
+            serde_yaml::from_str("vde_switch: /opt/vde/bin/vde_switch\nvdens: /opt/vdens").unwrap();
+        let topology: Executables = serde_yaml::from_str("vdens: ./vdens").unwrap();
+
+        let e = global.merge(&topology);
+        assert_eq!(e.get_vde_switch(), "/opt/vde/bin/vde_switch");
+        assert_eq!(e.get_vdens(), "./vdens");
+        assert_eq!(e.get_vde_plug(), "vde_plug");
+    }
+
+    #[test]
+    fn executables_relative_paths() {
+        let mut e: Executables = serde_yaml::from_str(
+            "vdens: ./bin/vdens
+vde_plug: /opt/vde_plug
+wirefilter: wirefilter",
+        )
+        .unwrap();
+        e.resolve_paths(Path::new("/labs/lab1")).unwrap();
+        assert_eq!(e.get_vdens(), "/labs/lab1/bin/vdens");
+        assert_eq!(e.get_vde_plug(), "/opt/vde_plug");
+        assert_eq!(e.get_wirefilter(), "wirefilter");
+    }
+}
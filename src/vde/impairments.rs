@@ -0,0 +1,477 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+
+/// Parameters of wirefilter for a single direction of a cable. A parameter
+/// that is not set keeps the wirefilter default
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LinkParams {
+    /// Delay in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay: Option<f64>,
+    /// Random variation of the delay in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jitter: Option<f64>,
+    /// Percentage of lost packets
+    #[serde(
+        default,
+        deserialize_with = "deserialize_percentage",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub loss: Option<f64>,
+    /// Percentage of duplicated packets
+    #[serde(
+        default,
+        deserialize_with = "deserialize_percentage",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub dup: Option<f64>,
+    /// Bandwidth of the channel in bytes per second, with an optional K, M
+    /// or G multiplier. Packets are queued
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<String>,
+    /// Speed of the interface in bytes per second, with an optional K, M
+    /// or G multiplier. Packets are delayed by their transmission time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<String>,
+    /// Packets larger than the mtu are dropped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+    /// Damaged bits per megabyte
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub noise: Option<u32>,
+    /// Size of the channel buffer in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chanbufsize: Option<u32>,
+}
+
+/// Link parameters flattened among other keys, i.e. at the top level of
+/// the impairments of a cable. Serde can't deny unknown keys next to a
+/// flattened struct, so they are kept to be rejected by `check_unknown`
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FlatParams {
+    #[serde(flatten)]
+    pub params: LinkParams,
+    #[serde(flatten, skip_serializing)]
+    unknown: BTreeMap<String, serde_yaml::Value>,
+}
+
+impl FlatParams {
+    /// Fail on the first key that is not a link parameter, i.e. a typo
+    pub fn check_unknown(&self) -> Result<()> {
+        if let Some(key) = self.unknown.keys().next() {
+            bail!("Unknown parameter {key}");
+        }
+        Ok(())
+    }
+}
+
+/// Direction of the traffic on a cable
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Direction {
+    #[serde(rename = "a2b")]
+    AToB,
+    #[serde(rename = "b2a")]
+    BToA,
+    #[serde(rename = "both")]
+    Both,
+}
+
+impl Direction {
+    fn a_to_b(&self) -> bool {
+        *self != Direction::BToA
+    }
+
+    fn b_to_a(&self) -> bool {
+        *self != Direction::AToB
+    }
+}
+
+/// Parse a delay in milliseconds, i.e. 100, 100ms, 1.5s
+pub fn parse_delay(s: &str) -> Result<f64> {
+    let (number, factor) = if let Some(n) = s.strip_suffix("ms") {
+        (n, 1.0)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1000.0)
+    } else {
+        (s, 1.0)
+    };
+    match number.trim().parse::<f64>() {
+        Ok(n) if n >= 0.0 => Ok(n * factor),
+        _ => bail!("Invalid delay {s}: use milliseconds (100, 100ms) or seconds (1s)"),
+    }
+}
+
+/// Parse a percentage, i.e. 5 or 5%
+pub fn parse_percentage(s: &str) -> Result<f64> {
+    let number = s.strip_suffix('%').unwrap_or(s);
+    match number.trim().parse::<f64>() {
+        Ok(n) if (0.0..=100.0).contains(&n) => Ok(n),
+        _ => bail!("Invalid percentage {s}: it must be between 0 and 100"),
+    }
+}
+
+/// Parse a rate. Rates in bits per second (i.e. 1Mbit, 512Kbit) are
+/// converted to bytes per second, the unit used by wirefilter. Rates in
+/// bytes per second (i.e. 100K, 1M) are kept as they are
+pub fn parse_rate(s: &str) -> Result<String> {
+    let lower = s.to_lowercase();
+    let Some(bits) = lower.strip_suffix("bit") else {
+        check_rate("rate", s)?;
+        return Ok(s.to_owned());
+    };
+
+    let (number, factor) = match bits.chars().last() {
+        Some('k') => (&bits[..bits.len() - 1], 1e3),
+        Some('m') => (&bits[..bits.len() - 1], 1e6),
+        Some('g') => (&bits[..bits.len() - 1], 1e9),
+        _ => (bits, 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n > 0.0 => Ok(format!("{}", (n * factor / 8.0).round() as u64)),
+        _ => bail!("Invalid rate {s}: use bits (1Mbit) or bytes with an optional K, M or G multiplier (125K)"),
+    }
+}
+
+/// Check a rate with an optional multiplier, i.e. 100, 10K, 1.5M
+fn check_rate(name: &str, rate: &str) -> Result<()> {
+    let number = rate.trim_end_matches(['K', 'k', 'M', 'm', 'G', 'g']);
+    match number.parse::<f64>() {
+        Ok(n) if n > 0.0 && rate.len() - number.len() <= 1 => Ok(()),
+        _ => bail!("Invalid {name} {rate}: it must be a positive number with an optional K, M or G multiplier"),
+    }
+}
+
+/// Read a percentage written as a number or with the syntax of
+/// `parse_percentage`, i.e. 5 or "5%"
+fn deserialize_percentage<'de, D: Deserializer<'de>>(d: D) -> Result<Option<f64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Percentage {
+        Number(f64),
+        Text(String),
+    }
+
+    match Option::<Percentage>::deserialize(d)? {
+        None => Ok(None),
+        Some(Percentage::Number(n)) => Ok(Some(n)),
+        Some(Percentage::Text(s)) => parse_percentage(&s)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+fn check_percentage(name: &str, value: Option<f64>) -> Result<()> {
+    if let Some(v) = value {
+        if !(0.0..=100.0).contains(&v) {
+            bail!("Invalid {name} {v}: it must be a percentage between 0 and 100");
+        }
+    }
+    Ok(())
+}
+
+/// Format a number without a useless fractional part
+fn number(n: f64) -> String {
+    if n.fract() == 0.0 {
+        format!("{}", n as i64)
+    } else {
+        format!("{n}")
+    }
+}
+
+impl LinkParams {
+    pub fn is_empty(&self) -> bool {
+        *self == LinkParams::default()
+    }
+
+    pub fn checks(&self) -> Result<()> {
+        for (name, v) in [("delay", self.delay), ("jitter", self.jitter)] {
+            if let Some(v) = v {
+                if v < 0.0 {
+                    bail!("Invalid {name} {v}: it can't be negative");
+                }
+            }
+        }
+        if self.jitter.is_some() && self.delay.is_none() {
+            bail!("Jitter requires a delay");
+        }
+
+        check_percentage("loss", self.loss)?;
+        check_percentage("dup", self.dup)?;
+
+        if let Some(b) = &self.bandwidth {
+            check_rate("bandwidth", b)?;
+        }
+        if let Some(s) = &self.speed {
+            check_rate("speed", s)?;
+        }
+
+        for (name, v) in [("mtu", self.mtu), ("chanbufsize", self.chanbufsize)] {
+            if v == Some(0) {
+                bail!("Invalid {name}: it can't be 0");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns a copy of these parameters, where the ones set in `over`
+    /// take precedence
+    pub fn merge(&self, over: &LinkParams) -> LinkParams {
+        LinkParams {
+            delay: over.delay.or(self.delay),
+            jitter: over.jitter.or(self.jitter),
+            loss: over.loss.or(self.loss),
+            dup: over.dup.or(self.dup),
+            bandwidth: over.bandwidth.clone().or_else(|| self.bandwidth.clone()),
+            speed: over.speed.clone().or_else(|| self.speed.clone()),
+            mtu: over.mtu.or(self.mtu),
+            noise: over.noise.or(self.noise),
+            chanbufsize: over.chanbufsize.or(self.chanbufsize),
+        }
+    }
+
+    /// Parameters that disable the impairments set in these ones
+    fn reset(&self) -> LinkParams {
+        LinkParams {
+            delay: self.delay.map(|_| 0.0),
+            jitter: None,
+            loss: self.loss.map(|_| 0.0),
+            dup: self.dup.map(|_| 0.0),
+            bandwidth: self.bandwidth.as_ref().map(|_| "0".to_owned()),
+            speed: self.speed.as_ref().map(|_| "0".to_owned()),
+            mtu: self.mtu.map(|_| 0),
+            noise: self.noise.map(|_| 0),
+            chanbufsize: self.chanbufsize.map(|_| 0),
+        }
+    }
+
+    /// Wirefilter commands and their values, in the wirefilter syntax
+    fn values(&self) -> Vec<(&'static str, Option<String>)> {
+        vec![
+            (
+                "delay",
+                self.delay.map(|d| match self.jitter {
+                    Some(j) => format!("{}+{}", number(d), number(j)),
+                    None => number(d),
+                }),
+            ),
+            ("loss", self.loss.map(number)),
+            ("dup", self.dup.map(number)),
+            ("bandwidth", self.bandwidth.clone()),
+            ("speed", self.speed.clone()),
+            ("mtu", self.mtu.map(|x| x.to_string())),
+            ("noise", self.noise.map(|x| x.to_string())),
+            ("chanbufsize", self.chanbufsize.map(|x| x.to_string())),
+        ]
+    }
+}
+
+/// Impairments of a wirefilter cable, for each direction. A is the left
+/// side of wirefilter and B the right one
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Impairments {
+    #[serde(default, skip_serializing_if = "LinkParams::is_empty")]
+    a_to_b: LinkParams,
+    #[serde(default, skip_serializing_if = "LinkParams::is_empty")]
+    b_to_a: LinkParams,
+}
+
+impl Impairments {
+    pub fn new(a_to_b: LinkParams, b_to_a: LinkParams) -> Impairments {
+        Impairments { a_to_b, b_to_a }
+    }
+
+    pub fn get_a_to_b(&self) -> &LinkParams {
+        &self.a_to_b
+    }
+
+    pub fn get_b_to_a(&self) -> &LinkParams {
+        &self.b_to_a
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.a_to_b.is_empty() && self.b_to_a.is_empty()
+    }
+
+    /// Apply new parameters to a direction of the cable. Returns the new
+    /// impairments and the wirefilter commands that apply them
+    pub fn apply(&self, direction: Direction, params: &LinkParams) -> (Impairments, Vec<String>) {
+        let pick = |enabled: bool| {
+            if enabled {
+                params.clone()
+            } else {
+                LinkParams::default()
+            }
+        };
+        let changes = Impairments::new(pick(direction.a_to_b()), pick(direction.b_to_a()));
+
+        // A new delay replaces the jitter too, as they are set together
+        let update = |current: &LinkParams, change: &LinkParams| {
+            let mut p = current.merge(change);
+            if change.delay.is_some() {
+                p.jitter = change.jitter;
+            }
+            p
+        };
+        let new = Impairments::new(
+            update(&self.a_to_b, &changes.a_to_b),
+            update(&self.b_to_a, &changes.b_to_a),
+        );
+        (new, changes.rc_lines())
+    }
+
+    /// Remove the impairments from a direction of the cable. Returns the new
+    /// impairments and the wirefilter commands that remove them
+    pub fn clear(&self, direction: Direction) -> (Impairments, Vec<String>) {
+        let reset = |enabled: bool, p: &LinkParams| {
+            if enabled {
+                p.reset()
+            } else {
+                LinkParams::default()
+            }
+        };
+        let changes = Impairments::new(
+            reset(direction.a_to_b(), &self.a_to_b),
+            reset(direction.b_to_a(), &self.b_to_a),
+        );
+
+        let keep = |enabled: bool, p: &LinkParams| {
+            if enabled {
+                LinkParams::default()
+            } else {
+                p.clone()
+            }
+        };
+        let new = Impairments::new(
+            keep(direction.a_to_b(), &self.a_to_b),
+            keep(direction.b_to_a(), &self.b_to_a),
+        );
+        (new, changes.rc_lines())
+    }
+
+    /// Wirefilter commands that apply the impairments. A parameter with the
+    /// same value in both directions is set once for both of them
+    pub fn rc_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let ab = self.a_to_b.values();
+        let ba = self.b_to_a.values();
+
+        for ((cmd, ab), (_, ba)) in ab.into_iter().zip(ba) {
+            match (ab, ba) {
+                (Some(ab), Some(ba)) if ab == ba => lines.push(format!("{cmd} {ab}")),
+                (ab, ba) => {
+                    if let Some(v) = ab {
+                        lines.push(format!("{cmd} LR {v}"));
+                    }
+                    if let Some(v) = ba {
+                        lines.push(format!("{cmd} RL {v}"));
+                    }
+                }
+            }
+        }
+
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symmetric_impairments() {
+        let p = LinkParams {
+            delay: Some(10.0),
+            jitter: Some(2.5),
+            loss: Some(1.0),
+            ..Default::default()
+        };
+        let i = Impairments::new(p.clone(), p);
+        assert_eq!(i.rc_lines(), vec!["delay 10+2.5", "loss 1"]);
+    }
+
+    #[test]
+    fn asymmetric_impairments() {
+        let both = LinkParams {
+            bandwidth: Some("10M".to_owned()),
+            ..Default::default()
+        };
+        let a_to_b = both.merge(&LinkParams {
+            delay: Some(50.0),
+            ..Default::default()
+        });
+        let i = Impairments::new(a_to_b, both);
+        assert_eq!(i.rc_lines(), vec!["delay LR 50", "bandwidth 10M"]);
+    }
+
+    #[test]
+    fn link_params_checks() {
+        let ok = LinkParams {
+            delay: Some(1.0),
+            jitter: Some(1.0),
+            speed: Some("1.5G".to_owned()),
+            ..Default::default()
+        };
+        assert!(ok.checks().is_ok());
+
+        for bad in [
+            LinkParams {
+                jitter: Some(1.0),
+                ..Default::default()
+            },
+            LinkParams {
+                loss: Some(101.0),
+                ..Default::default()
+            },
+            LinkParams {
+                bandwidth: Some("10MB".to_owned()),
+                ..Default::default()
+            },
+            LinkParams {
+                mtu: Some(0),
+                ..Default::default()
+            },
+        ] {
+            assert!(bad.checks().is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn parse_units() {
+        assert_eq!(parse_delay("100ms").unwrap(), 100.0);
+        assert_eq!(parse_delay("1.5s").unwrap(), 1500.0);
+        assert_eq!(parse_percentage("5%").unwrap(), 5.0);
+        assert_eq!(parse_rate("1Mbit").unwrap(), "125000");
+        assert_eq!(parse_rate("10K").unwrap(), "10K");
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[test]
+    fn apply_and_clear() {
+        let i = Impairments::default();
+        let (i, cmds) = i.apply(
+            Direction::AToB,
+            &LinkParams {
+                delay: Some(100.0),
+                ..Default::default()
+            },
+        );
+        assert_eq!(cmds, vec!["delay LR 100"]);
+
+        let (i, cmds) = i.apply(
+            Direction::Both,
+            &LinkParams {
+                loss: Some(5.0),
+                ..Default::default()
+            },
+        );
+        assert_eq!(cmds, vec!["loss 5"]);
+        assert_eq!(i.rc_lines(), vec!["delay LR 100", "loss 5"]);
+
+        let (i, cmds) = i.clear(Direction::Both);
+        assert_eq!(cmds, vec!["delay LR 0", "loss 0"]);
+        assert!(i.is_empty());
+    }
+}
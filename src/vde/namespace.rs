@@ -2,18 +2,21 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
-use super::{CONF_FILE_NAME, PID_FILE_NAME};
+use super::{Executables, CONF_FILE_NAME, PID_FILE_NAME};
 
 const STARTER_SCRIPT: &[u8] = include_bytes!("ns_starter.sh");
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Namespace {
     name: String,
     interfaces: Vec<NSInterface>,
     config: Vec<String>,
+    /// Arguments passed to vdens, before the interfaces
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra_args: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct NSInterface {
     name: String,
     ip: Option<String>,
@@ -26,6 +29,7 @@ impl Namespace {
             name,
             interfaces: Vec::new(),
             config: Vec::new(),
+            extra_args: Vec::new(),
         }
     }
 
@@ -33,6 +37,14 @@ impl Namespace {
         &self.name
     }
 
+    pub fn get_extra_args(&self) -> &Vec<String> {
+        &self.extra_args
+    }
+
+    pub fn set_extra_args(&mut self, args: Vec<String>) {
+        self.extra_args = args;
+    }
+
     pub fn get_interfaces(&self) -> &Vec<NSInterface> {
         &self.interfaces
     }
@@ -77,11 +89,11 @@ impl Namespace {
                 ));
             }
         }
-        return v;
+        v
     }
 
     /// Get base path of all the files related to the switch given
-    /// the global base path
+    /// the runtime directory
     pub fn base_path(&self, base: &str) -> String {
         PathBuf::from(base)
             .join(&self.name)
@@ -107,11 +119,11 @@ impl Namespace {
             .to_owned()
     }
 
-    pub fn exec_command(&self) -> String {
-        "vdens".to_owned()
+    pub fn exec_command(&self, exe: &Executables) -> String {
+        exe.get_vdens()
     }
 
-    /// Get the path of the interface connection given the global base path and
+    /// Get the path of the interface connection given the runtime directory and
     /// the interface name
     pub fn conn_path(&self, base: &str, interface: &str) -> Result<String> {
         for i in &self.interfaces {
@@ -133,11 +145,12 @@ impl Namespace {
         );
     }
 
-    /// base: base path for the working directory.
+    /// base: runtime directory, where sockets and pid files are kept.
     /// starter: the name of the starter script that will perform pid writing
     pub fn exec_args(&self, base: &str, starter: &str) -> Vec<String> {
         let name = self.get_name().to_owned();
         let mut args = vec!["--hostname".to_owned(), name.clone()];
+        args.extend(self.extra_args.iter().cloned());
 
         if !self.interfaces.is_empty() {
             args.push("--multi".to_owned());
@@ -160,11 +173,11 @@ impl Namespace {
             self.config_path(base),
         ];
         args.append(&mut args2);
-        return args;
+        args
     }
 
-    pub fn attach_command(&self) -> String {
-        "nsenter".to_owned()
+    pub fn attach_command(&self, exe: &Executables) -> String {
+        exe.get_nsenter()
     }
 
     pub fn attach_args(&self, _base: &str, pid: u32) -> Vec<String> {
@@ -182,8 +195,8 @@ impl Namespace {
     /// Returns the command to execute in order to execute a command
     /// inside the namespace. This is different from exec_command in which the
     /// command returned is used to start the namespace
-    pub fn exec_command_command(&self) -> String {
-        self.attach_command()
+    pub fn exec_command_command(&self, exe: &Executables) -> String {
+        self.attach_command(exe)
     }
 
     /// Returns the arguments to execute in order to execute a command inside
@@ -199,7 +212,7 @@ impl Namespace {
         let mut args = self.attach_args(_base, pid);
         args.append(command);
 
-        return args;
+        args
     }
 
     pub fn get_starter_script() -> &'static [u8] {
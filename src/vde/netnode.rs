@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+
+use super::{Executables, PID_FILE_NAME, SOCK_FILE_NAME};
+
+/// The unmanaged nodes of libvdeplug_netnode
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NetnodeKind {
+    Hub,
+    Switch,
+    Multi,
+    Bundling,
+}
+
+impl fmt::Display for NetnodeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetnodeKind::Hub => write!(f, "hub"),
+            NetnodeKind::Switch => write!(f, "switch"),
+            NetnodeKind::Multi => write!(f, "multi"),
+            NetnodeKind::Bundling => write!(f, "bundling"),
+        }
+    }
+}
+
+/// A netnode is hosted by a vde_plug and serves a directory like the one of
+/// vde_switch, so it is plugged with `vde://`. It has no management socket
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Netnode {
+    name: String,
+    kind: NetnodeKind,
+    /// Arguments appended to the command that starts the netnode
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra_args: Vec<String>,
+}
+
+impl Netnode {
+    pub fn new(name: String, kind: NetnodeKind) -> Self {
+        Self {
+            name,
+            kind,
+            extra_args: Vec::new(),
+        }
+    }
+
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn get_kind(&self) -> NetnodeKind {
+        self.kind
+    }
+
+    pub fn get_extra_args(&self) -> &Vec<String> {
+        &self.extra_args
+    }
+
+    pub fn set_extra_args(&mut self, args: Vec<String>) {
+        self.extra_args = args;
+    }
+
+    /// Get base path of all the files related to the netnode given
+    /// the runtime directory
+    pub fn base_path(&self, base: &str) -> String {
+        PathBuf::from(base)
+            .join(&self.name)
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    pub fn pid_path(&self, base: &str) -> String {
+        PathBuf::from(self.base_path(base))
+            .join(PID_FILE_NAME)
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    /// Get the path of the directory served by the netnode given the
+    /// runtime directory
+    pub fn sock_path(&self, base: &str) -> String {
+        PathBuf::from(self.base_path(base))
+            .join(SOCK_FILE_NAME)
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    pub fn exec_command(&self, exe: &Executables) -> String {
+        exe.get_vde_plug()
+    }
+
+    /// base: runtime directory, where sockets and pid files are kept.
+    /// The other side of the vde_plug hosting the node discards everything
+    pub fn exec_args(&self, base: &str) -> Vec<String> {
+        let mut args = vec![
+            "--pidfile".to_owned(),
+            self.pid_path(base),
+            "null://".to_owned(),
+            format!("{}://{}", self.kind, self.sock_path(base)),
+            "--daemon".to_owned(),
+        ];
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn netnode_args() {
+        let mut n = Netnode::new("hub1".to_owned(), NetnodeKind::Hub);
+        n.set_extra_args(vec!["-v".to_owned()]);
+
+        assert_eq!(
+            n.exec_args("/tmp"),
+            vec![
+                "--pidfile",
+                "/tmp/hub1/pid",
+                "null://",
+                "hub:///tmp/hub1/sock",
+                "--daemon",
+                "-v"
+            ]
+        );
+    }
+}
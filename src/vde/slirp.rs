@@ -2,24 +2,38 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
-use super::PID_FILE_NAME;
+use super::{Executables, PID_FILE_NAME};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Slirp {
     name: String,
+    /// Arguments appended to the command that starts the slirp
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra_args: Vec<String>,
 }
 
 impl Slirp {
     pub fn new(name: String) -> Self {
-        Self { name }
+        Self {
+            name,
+            extra_args: Vec::new(),
+        }
     }
 
     pub fn get_name(&self) -> &String {
         &self.name
     }
 
+    pub fn get_extra_args(&self) -> &Vec<String> {
+        &self.extra_args
+    }
+
+    pub fn set_extra_args(&mut self, args: Vec<String>) {
+        self.extra_args = args;
+    }
+
     /// Get base path of all the files related to the switch given
-    /// the global base path
+    /// the runtime directory
     pub fn base_path(&self, base: &str) -> String {
         PathBuf::from(base)
             .join(&self.name)
@@ -37,21 +51,24 @@ impl Slirp {
             .to_owned()
     }
 
-    pub fn exec_command(&self) -> String {
-        "vde_plug".to_owned()
+    pub fn exec_command(&self, exe: &Executables) -> String {
+        exe.get_vde_plug()
     }
 
-    /// base: base path for the working directory.
+    /// base: runtime directory, where sockets and pid files are kept.
     pub fn exec_args(&self, base: &str) -> Vec<String> {
-        vec![
+        let mut args = vec![
             "--pidfile".to_owned(),
             self.pid_path(base),
             format!("ptp:///{}/{}", self.base_path(base), self.get_name()),
             "slirp://".to_owned(),
-        ]
+            "--daemon".to_owned(),
+        ];
+        args.extend(self.extra_args.iter().cloned());
+        args
     }
 
-    /// Get the path of the interface connection given the global base path
+    /// Get the path of the interface connection given the runtime directory
     pub fn conn_path(&self, base: &str) -> Result<String> {
         Ok(PathBuf::from(self.base_path(base))
             .join(self.get_name())
@@ -1,4 +1,4 @@
-use super::{CONF_FILE_NAME, MGMT_FILE_NAME, PID_FILE_NAME, SOCK_FILE_NAME};
+use super::{Engine, Executables, CONF_FILE_NAME, MGMT_FILE_NAME, PID_FILE_NAME, SOCK_FILE_NAME};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -6,13 +6,53 @@ const DEFAULT_PORTS: u32 = 32;
 
 /// This is the internal rappresentation of a switch
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Switch {
     /// The name should be unique
     name: String,
     config: Vec<String>,
     ports: u32,
     hub: bool,
+    /// Arguments appended to the command that starts the switch
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra_args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    vlans: Vec<Vlan>,
+    /// A native switch is run by imaginet in place of vde_switch
+    #[serde(default, skip_serializing_if = "Engine::is_default")]
+    engine: Engine,
+}
+
+/// A vlan of the switch, with the ports that belong to it
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Vlan {
+    id: u16,
+    /// Ports without a tag, they belong only to this vlan
+    untagged: Vec<u32>,
+    /// Ports that carry the traffic of this vlan tagged
+    tagged: Vec<u32>,
+}
+
+impl Vlan {
+    pub fn new(id: u16, untagged: Vec<u32>, tagged: Vec<u32>) -> Vlan {
+        Vlan {
+            id,
+            untagged,
+            tagged,
+        }
+    }
+
+    pub fn get_id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn get_untagged(&self) -> &Vec<u32> {
+        &self.untagged
+    }
+
+    pub fn get_tagged(&self) -> &Vec<u32> {
+        &self.tagged
+    }
 }
 
 impl Switch {
@@ -22,6 +62,9 @@ impl Switch {
             config: Vec::new(),
             ports: DEFAULT_PORTS,
             hub: false,
+            extra_args: Vec::new(),
+            vlans: Vec::new(),
+            engine: Engine::default(),
         }
     }
 
@@ -29,6 +72,22 @@ impl Switch {
         &self.name
     }
 
+    pub fn get_extra_args(&self) -> &Vec<String> {
+        &self.extra_args
+    }
+
+    pub fn set_extra_args(&mut self, args: Vec<String>) {
+        self.extra_args = args;
+    }
+
+    pub fn get_engine(&self) -> Engine {
+        self.engine
+    }
+
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
     pub fn get_ports(&self) -> u32 {
         self.ports
     }
@@ -59,12 +118,56 @@ impl Switch {
         &self.config
     }
 
+    pub fn add_vlan(&mut self, vlan: Vlan) {
+        self.vlans.push(vlan);
+    }
+
+    pub fn get_vlans(&self) -> &Vec<Vlan> {
+        &self.vlans
+    }
+
     pub fn needs_config(&self) -> bool {
-        !self.config.is_empty()
+        !self.config.is_empty() || !self.vlans.is_empty()
+    }
+
+    /// Commands executed by the switch when it starts: the ones that
+    /// create the vlans, followed by the configuration
+    pub fn rc_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        for v in &self.vlans {
+            lines.push(format!("vlan/create {}", v.id));
+        }
+
+        // The cables are connected after the switch started, so the
+        // ports must be created before being added to a vlan
+        let mut ports: Vec<u32> = self
+            .vlans
+            .iter()
+            .flat_map(|v| v.untagged.iter().chain(&v.tagged))
+            .copied()
+            .collect();
+        ports.sort();
+        ports.dedup();
+        for p in ports {
+            lines.push(format!("port/create {p}"));
+        }
+
+        for v in &self.vlans {
+            for p in &v.untagged {
+                lines.push(format!("port/setvlan {p} {}", v.id));
+            }
+            for p in &v.tagged {
+                lines.push(format!("vlan/addport {} {p}", v.id));
+            }
+        }
+
+        lines.extend(self.config.iter().cloned());
+        lines
     }
 
     /// Get base path of all the files related to the switch given
-    /// the global base path
+    /// the runtime directory
     pub fn base_path(&self, base: &str) -> String {
         PathBuf::from(base)
             .join(&self.name)
@@ -73,7 +176,7 @@ impl Switch {
             .to_owned()
     }
 
-    /// Get the path of the pid file of the switch given the global base path
+    /// Get the path of the pid file of the switch given the runtime directory
     pub fn pid_path(&self, base: &str) -> String {
         PathBuf::from(self.base_path(base))
             .join(PID_FILE_NAME)
@@ -82,7 +185,7 @@ impl Switch {
             .to_owned()
     }
 
-    /// Get the path of the management file of the switch given the global base path
+    /// Get the path of the management file of the switch given the runtime directory
     pub fn mgmt_path(&self, base: &str) -> String {
         PathBuf::from(self.base_path(base))
             .join(MGMT_FILE_NAME)
@@ -91,7 +194,7 @@ impl Switch {
             .to_owned()
     }
 
-    /// Get the path of the socket file of the switch given the global base path
+    /// Get the path of the socket file of the switch given the runtime directory
     pub fn sock_path(&self, base: &str) -> String {
         PathBuf::from(self.base_path(base))
             .join(SOCK_FILE_NAME)
@@ -108,8 +211,11 @@ impl Switch {
             .to_owned()
     }
 
-    pub fn exec_command(&self) -> String {
-        String::from("vde_switch")
+    pub fn exec_command(&self, exe: &Executables) -> String {
+        match self.engine {
+            Engine::Vde => exe.get_vde_switch(),
+            Engine::Native => exe.get_imaginet(),
+        }
     }
 
     pub fn exec_args(&self, base: &str) -> Vec<String> {
@@ -118,6 +224,29 @@ impl Switch {
         let sock_p = self.sock_path(base);
         let conf_p = self.config_path(base);
 
+        if self.engine == Engine::Native {
+            let mut v = vec![
+                "__switch".to_owned(),
+                "--name".to_owned(),
+                self.name.clone(),
+                "--pidfile".to_owned(),
+                pid_p,
+                "--mgmt".to_owned(),
+                mgmt_p,
+                "--sock".to_owned(),
+                sock_p,
+                "--numports".to_owned(),
+                self.ports.to_string(),
+            ];
+            if self.needs_config() {
+                v.extend(["--rcfile".to_owned(), conf_p]);
+            }
+            if self.hub {
+                v.push("--hub".to_owned());
+            }
+            return v;
+        }
+
         let mut v = vec![
             "--pidfile".to_owned(),
             pid_p,
@@ -136,11 +265,13 @@ impl Switch {
             v.push("--hub".to_owned());
         }
 
-        return v;
+        v.extend(self.extra_args.iter().cloned());
+
+        v
     }
 
-    pub fn attach_command(&self) -> String {
-        String::from("vdeterm")
+    pub fn attach_command(&self, exe: &Executables) -> String {
+        exe.get_vdeterm()
     }
 
     pub fn attach_args(&self, base: &str, _pid: u32) -> Vec<String> {
@@ -151,8 +282,8 @@ impl Switch {
     /// Returns the command to execute in order to execute a command
     /// inside the switch. This is different from exec_command in which the
     /// command returned is used to start the switch
-    pub fn exec_command_command(&self) -> String {
-        String::from("vdecmd")
+    pub fn exec_command_command(&self, exe: &Executables) -> String {
+        exe.get_vdecmd()
     }
 
     /// Returns the arguments to execute in order to execute a command inside
@@ -163,7 +294,7 @@ impl Switch {
         let mut args = vec!["-s".to_owned(), self.mgmt_path(base)];
         args.append(command);
 
-        return args;
+        args
     }
 }
 
@@ -214,4 +345,52 @@ mod tests {
 
         assert_eq!(sw.sock_path(base), String::from("/tmp/sw-13ndo28/sock"));
     }
+
+    #[test]
+    fn switch_extra_args() {
+        let mut sw = Switch::new("sw".to_owned());
+        sw.set_extra_args(vec!["--macaddr".to_owned(), "02:00:00:00:00:01".to_owned()]);
+        let args = sw.exec_args("/tmp");
+
+        assert_eq!(args[args.len() - 2..], ["--macaddr", "02:00:00:00:00:01"]);
+    }
+
+    #[test]
+    fn native_switch_args() {
+        let mut sw = Switch::new("sw".to_owned());
+        sw.set_engine(Engine::Native);
+        sw.set_hub(true);
+        let args = sw.exec_args("/tmp");
+
+        assert_eq!(args[0], "__switch");
+        assert!(!args.contains(&"--rcfile".to_owned()));
+        assert_eq!(args.last().unwrap(), "--hub");
+
+        sw.add_config("hash/setexpire 60".to_owned());
+        assert!(sw.exec_args("/tmp").contains(&"/tmp/sw/config".to_owned()));
+    }
+
+    #[test]
+    fn switch_vlan_rc_lines() {
+        let mut sw = Switch::new("sw".to_owned());
+        sw.add_vlan(Vlan::new(10, vec![1], vec![7]));
+        sw.add_vlan(Vlan::new(20, vec![2], vec![7]));
+        sw.add_config("port/print".to_owned());
+
+        assert_eq!(
+            sw.rc_lines(),
+            vec![
+                "vlan/create 10",
+                "vlan/create 20",
+                "port/create 1",
+                "port/create 2",
+                "port/create 7",
+                "port/setvlan 1 10",
+                "vlan/addport 10 7",
+                "port/setvlan 2 20",
+                "vlan/addport 20 7",
+                "port/print",
+            ]
+        );
+    }
 }
@@ -0,0 +1,172 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use super::{Executables, PID_FILE_NAME};
+
+/// Size of the interface names in the kernel, with the terminator
+const IFNAMSIZ: usize = 16;
+
+/// A tap interface of the host, bridged to the topology by a vde_plug. The
+/// vde_plug runs without privileges, so the tap must already exist and be
+/// owned by the user. Its other side is a ptp endpoint, like the slirps
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Tap {
+    name: String,
+    /// Name of the tap interface on the host
+    interface: String,
+    /// Create the interface when it is missing, asking for consent before
+    /// running the privileged helper
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    create: bool,
+    /// Arguments appended to the command that starts the tap
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra_args: Vec<String>,
+}
+
+impl Tap {
+    pub fn new(name: String, interface: String) -> Self {
+        Self {
+            name,
+            interface,
+            create: false,
+            extra_args: Vec::new(),
+        }
+    }
+
+    /// Check that an interface name is accepted by the kernel
+    pub fn check_interface(interface: &str) -> Result<()> {
+        if interface.is_empty() || interface.len() > IFNAMSIZ - 1 {
+            bail!(
+                "Interface name {interface} must be 1 to {} characters long",
+                IFNAMSIZ - 1
+            );
+        }
+        if interface.contains(|c: char| c == '/' || c == ':' || c.is_whitespace()) {
+            bail!("Interface name {interface} can't contain '/', ':' or spaces");
+        }
+        Ok(())
+    }
+
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn get_interface(&self) -> &String {
+        &self.interface
+    }
+
+    pub fn get_create(&self) -> bool {
+        self.create
+    }
+
+    pub fn set_create(&mut self, create: bool) {
+        self.create = create;
+    }
+
+    pub fn get_extra_args(&self) -> &Vec<String> {
+        &self.extra_args
+    }
+
+    pub fn set_extra_args(&mut self, args: Vec<String>) {
+        self.extra_args = args;
+    }
+
+    /// Get base path of all the files related to the tap given
+    /// the runtime directory
+    pub fn base_path(&self, base: &str) -> String {
+        PathBuf::from(base)
+            .join(&self.name)
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    pub fn pid_path(&self, base: &str) -> String {
+        PathBuf::from(self.base_path(base))
+            .join(PID_FILE_NAME)
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    /// Get the path of the interface connection given the runtime directory
+    pub fn conn_path(&self, base: &str) -> String {
+        PathBuf::from(self.base_path(base))
+            .join(self.get_name())
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    pub fn exec_command(&self, exe: &Executables) -> String {
+        exe.get_vde_plug()
+    }
+
+    /// base: runtime directory, where sockets and pid files are kept.
+    pub fn exec_args(&self, base: &str) -> Vec<String> {
+        let mut args = vec![
+            "--pidfile".to_owned(),
+            self.pid_path(base),
+            format!("ptp://{}", self.conn_path(base)),
+            format!("tap://{}", self.interface),
+            "--daemon".to_owned(),
+        ];
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+
+    /// Commands that create the interface for `user` and bring it up. They
+    /// need root, so they are run through the privileged helper
+    pub fn create_commands(&self, exe: &Executables, user: &str) -> Vec<Vec<String>> {
+        let (sudo, ip) = (exe.get_sudo(), exe.get_ip());
+        let ifname = self.interface.clone();
+        vec![
+            vec![
+                sudo.clone(),
+                ip.clone(),
+                "tuntap".to_owned(),
+                "add".to_owned(),
+                "dev".to_owned(),
+                ifname.clone(),
+                "mode".to_owned(),
+                "tap".to_owned(),
+                "user".to_owned(),
+                user.to_owned(),
+            ],
+            vec![
+                sudo,
+                ip,
+                "link".to_owned(),
+                "set".to_owned(),
+                ifname,
+                "up".to_owned(),
+            ],
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tap_args() {
+        let tap = Tap::new("uplink".to_owned(), "tap0".to_owned());
+        let args = tap.exec_args("/tmp");
+
+        assert_eq!(args[2], "ptp:///tmp/uplink/uplink");
+        assert_eq!(args[3], "tap://tap0");
+
+        let create = tap.create_commands(&Executables::default(), "lab");
+        assert_eq!(
+            create[0].join(" "),
+            "sudo ip tuntap add dev tap0 mode tap user lab"
+        );
+
+        assert!(Tap::check_interface("tap0").is_ok());
+        assert!(Tap::check_interface("").is_err());
+        assert!(Tap::check_interface("a-very-long-name").is_err());
+        assert!(Tap::check_interface("tap/0").is_err());
+    }
+}
@@ -2,13 +2,16 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
-use super::PID_FILE_NAME;
+use super::{Executables, PID_FILE_NAME};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct VXVDE {
     name: String,
     addr: Option<String>,
     port: Option<u16>,
+    /// Arguments appended to the command that starts the vxvde
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra_args: Vec<String>,
 }
 
 impl VXVDE {
@@ -17,6 +20,7 @@ impl VXVDE {
             name,
             addr: None,
             port: None,
+            extra_args: Vec::new(),
         }
     }
 
@@ -24,6 +28,14 @@ impl VXVDE {
         &self.name
     }
 
+    pub fn get_extra_args(&self) -> &Vec<String> {
+        &self.extra_args
+    }
+
+    pub fn set_extra_args(&mut self, args: Vec<String>) {
+        self.extra_args = args;
+    }
+
     pub fn set_addr(&mut self, addr: String) {
         self.addr = Some(addr);
     }
@@ -41,7 +53,7 @@ impl VXVDE {
     }
 
     /// Get base path of all the files related to the switch given
-    /// the global base path
+    /// the runtime directory
     pub fn base_path(&self, base: &str) -> String {
         PathBuf::from(base)
             .join(&self.name)
@@ -59,31 +71,34 @@ impl VXVDE {
             .to_owned()
     }
 
-    pub fn exec_command(&self) -> String {
-        "vde_plug".to_owned()
+    pub fn exec_command(&self, exe: &Executables) -> String {
+        exe.get_vde_plug()
     }
 
-    /// base: base path for the working directory.
+    /// base: runtime directory, where sockets and pid files are kept.
     pub fn exec_args(&self, base: &str) -> Vec<String> {
         let mut vxconn = "vxvde://".to_owned();
 
-        if self.addr.is_some() {
-            vxconn = vxconn + self.addr.as_ref().unwrap();
+        if let Some(addr) = &self.addr {
+            vxconn = vxconn + addr;
         }
 
-        if self.port.is_some() {
-            vxconn = vxconn + "/port=" + &self.port.unwrap().to_string();
+        if let Some(port) = self.port {
+            vxconn = vxconn + "/port=" + &port.to_string();
         }
 
-        vec![
+        let mut args = vec![
             "--pidfile".to_owned(),
             self.pid_path(base),
             format!("ptp:///{}/{}", self.base_path(base), self.get_name()),
             vxconn,
-        ]
+            "--daemon".to_owned(),
+        ];
+        args.extend(self.extra_args.iter().cloned());
+        args
     }
 
-    /// Get the path of the interface connection given the global base path
+    /// Get the path of the interface connection given the runtime directory
     pub fn conn_path(&self, base: &str) -> Result<String> {
         Ok(PathBuf::from(self.base_path(base))
             .join(self.get_name())